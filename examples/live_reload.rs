@@ -0,0 +1,21 @@
+//! Rebuild a wasm package with wasm-pack and reload the pages including the
+//! live reload script:
+//!
+//! ```html
+//! <script src="http://localhost:35729/live-reload.js"></script>
+//! ```
+//!
+//! Run with `cargo run --example live_reload --features live-reload`.
+
+use xtask_watch::{anyhow::Result, CommandList, Watch};
+
+fn main() -> Result<()> {
+    Watch::default()
+        .with_live_reload(35729)
+        .run(CommandList::from_argv([
+            "wasm-pack",
+            "build",
+            "--target",
+            "web",
+        ]))
+}
@@ -0,0 +1,33 @@
+//! A minimal terminal interface controlling a watch running in the background.
+//!
+//! Type a command and press enter:
+//!
+//! * `r` to relaunch the command
+//! * `p` to pause the watch
+//! * `c` to resume the watch
+//! * `s` to show the status of the watch
+//! * `q` to quit
+
+use std::{io, process::Command};
+use xtask_watch::{anyhow::Result, Watch};
+
+fn main() -> Result<()> {
+    let mut check = Command::new("cargo");
+    check.arg("check");
+
+    let session = Watch::default().spawn_session(check);
+
+    for line in io::stdin().lines() {
+        match line?.trim() {
+            "r" => session.trigger(),
+            "p" => session.pause(),
+            "c" => session.resume(),
+            "s" => println!("{:?}", session.status()),
+            "q" => break,
+            "" => {}
+            other => println!("unknown command {other:?}, use r, p, c, s or q"),
+        }
+    }
+
+    session.stop()
+}
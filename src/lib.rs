@@ -115,6 +115,28 @@
 //! }
 //! ```
 //!
+//! ## A minimal implementation
+//!
+//! [`run_main`] parses `cargo xtask watch [OPTIONS] [COMMAND]...`, sets up a
+//! logger and runs the watch, leaving only the commands to choose:
+//!
+//! ```rust,no_run
+//! use xtask_watch::CommandList;
+//!
+//! fn main() -> xtask_watch::anyhow::Result<()> {
+//!     xtask_watch::run_main(|command| {
+//!         if command.is_empty() {
+//!             CommandList::from_argv(["cargo", "check"])
+//!         } else {
+//!             CommandList::from_argv(command)
+//!         }
+//!     })
+//! }
+//! ```
+//!
+//! Use [`Watch`] directly like above to add other subcommands or to configure
+//! the watch further.
+//!
 //! ## A more complex demonstration
 //!
 //! [`examples/demo`](https://github.com/rustminded/xtask-watch/tree/main/examples/demo)
@@ -163,14 +185,31 @@ use clap::Parser;
 use lazy_static::lazy_static;
 use notify::{Event, EventHandler, RecursiveMode, Watcher};
 use std::{
-    env, io,
+    collections::{HashMap, VecDeque},
+    env,
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    io::{self, BufRead, IsTerminal, Write},
     path::{Path, PathBuf},
-    process::{Child, Command, ExitStatus},
-    sync::{mpsc, Arc, Mutex},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, RwLock, Weak,
+    },
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// Log a message about a run, prefixed with the number of the run.
+macro_rules! run_log {
+    ($level:ident, $run:expr, $($arg:tt)+) => {
+        log::$level!("[run #{}] {}", $run, format_args!($($arg)+))
+    };
+}
+
+#[cfg(feature = "live-reload")]
+mod live_reload;
+
 pub use anyhow;
 pub use cargo_metadata;
 pub use cargo_metadata::camino;
@@ -187,6 +226,73 @@ pub fn metadata() -> &'static cargo_metadata::Metadata {
     &METADATA
 }
 
+/// Parse the arguments of `xtask watch [OPTIONS] [COMMAND]...`, set up a logger
+/// and run the watch with the commands returned by `commands`.
+///
+/// `commands` gets the command given on the command line, if any. The logger
+/// prints the messages of the watch to the standard error, from the `info`
+/// level or the level set in `RUST_LOG`, unless another logger was set up
+/// before.
+///
+/// ```rust,no_run
+/// use xtask_watch::CommandList;
+///
+/// fn main() -> xtask_watch::anyhow::Result<()> {
+///     xtask_watch::run_main(|command| {
+///         if command.is_empty() {
+///             CommandList::from_argv(["cargo", "check"])
+///         } else {
+///             CommandList::from_argv(command)
+///         }
+///     })
+/// }
+/// ```
+pub fn run_main<C: Into<CommandList>>(commands: impl FnOnce(&[String]) -> C) -> Result<()> {
+    let MainOpt::Watch { command, watch } = MainOpt::parse();
+
+    if log::set_logger(&StderrLogger).is_ok() {
+        let level = env::var("RUST_LOG")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(log::LevelFilter::Info);
+        log::set_max_level(level);
+    }
+
+    watch.run(commands(&command))
+}
+
+/// Command line of [`run_main`].
+#[derive(Parser)]
+enum MainOpt {
+    /// Watch over your project's source code.
+    #[group(skip)]
+    Watch {
+        /// Command executed when changes are detected.
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+
+        #[clap(flatten)]
+        watch: Watch,
+    },
+}
+
+/// Logger printing the messages to the standard error, set up by [`run_main`].
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
 /// Fetch information of a package in the current crate.
 pub fn package(name: &str) -> Option<&cargo_metadata::Package> {
     metadata().packages.iter().find(|x| x.name == name)
@@ -205,21 +311,574 @@ pub fn xtask_command() -> Command {
 pub struct Watch {
     /// Watch specific file(s) or folder(s).
     ///
-    /// The default is the workspace root.
+    /// The default is the workspace root. A path can contain `*` and `?`
+    /// wildcards, like `crates/*/src`, to watch every matching directory. The
+    /// directories created later are watched when they match.
     #[clap(long = "watch", short = 'w')]
     pub watch_paths: Vec<PathBuf>,
+    /// Read more paths to watch from a file, or from the standard input with
+    /// `-`.
+    ///
+    /// The file contains one path per line. Blank lines and lines starting
+    /// with `#` are ignored.
+    #[clap(long)]
+    pub watch_from: Option<PathBuf>,
+    /// Watch the source files listed in the dep-info files (`.d`) of the
+    /// workspace's `target` directory instead of the whole workspace.
+    ///
+    /// The dep-info files are written by cargo when building, so the list is
+    /// the one of the last build: a build is needed before starting the watch
+    /// and source files added afterwards are not watched until the watch is
+    /// restarted.
+    #[clap(long)]
+    pub dep_info: bool,
+    /// Also watch the files outside the workspace listed in the dep-info
+    /// files, like the files included with `include_str!`.
+    ///
+    /// The other watched paths are unchanged. Like with `--dep-info`, a build
+    /// is needed before starting the watch.
+    #[clap(long)]
+    pub dep_info_external: bool,
     /// Paths that will be excluded.
+    ///
+    /// A bare name, without any path separator (like `Cargo.lock`), excludes
+    /// every file or directory with that name anywhere in the watched paths.
     #[clap(long = "ignore", short = 'i')]
     pub exclude_paths: Vec<PathBuf>,
     /// Paths, relative to the workspace root, that will be excluded.
     #[clap(skip)]
     pub workspace_exclude_paths: Vec<PathBuf>,
+    /// Paths excluded only within a root, like a watched path, grouped by
+    /// root.
+    ///
+    /// The paths are relative to their root, a bare name excludes every file
+    /// or directory with that name anywhere in the root.
+    #[clap(skip)]
+    pub root_exclude_paths: Vec<(PathBuf, Vec<PathBuf>)>,
+    /// Watch the target directory instead of excluding it.
+    #[clap(long)]
+    pub include_target: bool,
+    /// Target directories to exclude instead of the workspace's one.
+    ///
+    /// This is useful when a workspace member or a vendored crate builds in
+    /// its own target directory, or when a sub-build writes in a target
+    /// directory the watch should react to. The directories don't need to
+    /// exist yet, but their parent does. Ignored with `--include-target`.
+    #[clap(long = "exclude-target", value_name = "DIR")]
+    pub exclude_targets: Vec<PathBuf>,
+    /// Target directories to exclude in addition to the workspace's one.
+    ///
+    /// This is useful when building for several targets or profiles in
+    /// separate target directories. Like with `--exclude-target`, only their
+    /// parent needs to exist and `--include-target` ignores them.
+    #[clap(long = "extra-target-dir", value_name = "DIR")]
+    pub extra_target_dirs: Vec<PathBuf>,
+    /// Skip the watched and excluded paths that don't exist, with a warning,
+    /// instead of failing.
+    ///
+    /// This is useful for optional paths, present in some checkouts only. The
+    /// watch still fails when none of the watched paths exist.
+    #[clap(long)]
+    pub lenient_paths: bool,
+    /// Watch the hidden files and directories, whose name starts with a `.`,
+    /// instead of ignoring them.
+    ///
+    /// Excluding `.git` is recommended along with this option.
+    #[clap(long)]
+    pub include_hidden: bool,
+    /// Watch the hidden files and directories with these names, like `.env`
+    /// or `.sqlx`, while still ignoring the other hidden paths.
+    ///
+    /// The name is matched against the hidden component directly below a
+    /// watched path.
+    #[clap(long, value_name = "NAME")]
+    pub watch_hidden: Vec<String>,
     /// Throttle events to prevent the command to be re-executed too early
     /// right after an execution already occurred.
     ///
     /// The default is 2 seconds.
     #[clap(skip = Duration::from_secs(2))]
     pub debounce: Duration,
+    /// Relaunch the command when files are deleted, with this debounce
+    /// instead of [`debounce`](Self::debounce).
+    ///
+    /// A longer debounce collapses the delete and the create of an atomic save
+    /// or a bulk deletion into a single run. Deletions are ignored when unset.
+    #[clap(skip)]
+    pub delete_debounce: Option<Duration>,
+    /// Collect the changes in a directory until none occurred in it for this
+    /// duration, then relaunch the command once with all of them.
+    ///
+    /// A burst of saves in a single directory, like a formatter rewriting a
+    /// module, relaunches the command once at the end of the burst even when
+    /// [`debounce`](Self::debounce) is shorter than the burst.
+    #[clap(skip)]
+    pub directory_debounce: Option<Duration>,
+    /// Ignore the changes of a file for this many milliseconds after a change
+    /// of the same file relaunched the command.
+    ///
+    /// Editors writing a file twice per save relaunch the command once even
+    /// when the writes straddle the debounce. The changes of other files are
+    /// not affected.
+    #[clap(long, value_name = "MS", value_parser = parse_millis)]
+    pub file_debounce: Option<Duration>,
+    /// Ignore the changes for this many seconds once the paths are watched.
+    ///
+    /// Editors and tools touching files on startup, like when reindexing or
+    /// formatting the files they open, don't relaunch the command while the
+    /// environment settles. Unlike the debounce, it only applies once, right
+    /// after starting to watch.
+    #[clap(long, value_name = "SECS", value_parser = parse_secs)]
+    pub startup_quiet: Option<Duration>,
+    /// Wait for the changes to stop for this many milliseconds before
+    /// relaunching the command.
+    ///
+    /// While changes keep arriving faster than that, like when saving
+    /// repeatedly while typing, the running command is left alone. Changes
+    /// dropped by the debounce don't delay the rerun, so this works best with
+    /// a short debounce.
+    #[clap(long, value_name = "MS", value_parser = parse_millis)]
+    pub settle: Option<Duration>,
+    /// Relaunch the command when the permissions or other metadata of a file
+    /// change, like with `chmod`.
+    ///
+    /// Metadata changes other than the modification time are ignored by
+    /// default as they are noisy.
+    #[clap(long)]
+    pub on_chmod: bool,
+    /// Kinds of events relaunching the command, as a comma-separated list
+    /// like `create,modify,remove`.
+    ///
+    /// Every kind but `chmod` triggers by default, deletions only when a
+    /// deletion debounce is set.
+    #[clap(long, value_enum, value_delimiter = ',')]
+    pub events: Vec<TriggerEvent>,
+    /// Only relaunch the command when files appear in this directory,
+    /// ignoring their modifications and deletions.
+    ///
+    /// A file is new when it's created or moved into the directory. The
+    /// directory is watched along with the other watched paths.
+    #[clap(long, value_name = "DIR")]
+    pub inbox: Vec<PathBuf>,
+    /// Ring the terminal bell when the command fails.
+    ///
+    /// Nothing is written if stderr is not a terminal.
+    #[clap(long)]
+    pub bell: bool,
+    /// Run cargo commands with `--message-format=json` and print a summary
+    /// of the errors and warnings after each run.
+    ///
+    /// Commands that are not cargo commands are unaffected, and so are the
+    /// cargo commands passing arguments after `--` unless they are templated:
+    /// the flag can only be added at the end of the arguments of a command.
+    #[clap(long)]
+    pub cargo_json: bool,
+    /// Only relaunch the command when this file is created or updated.
+    ///
+    /// This is useful when another tool writes many files and touches a
+    /// marker file once it's done. Every other change is ignored.
+    #[clap(long)]
+    pub ready_marker: Option<PathBuf>,
+    /// Read environment variables from this file and pass them to the
+    /// command.
+    ///
+    /// The file contains `KEY=VALUE` lines. It is read again before every run
+    /// and a change to it relaunches the command with the new values. When the
+    /// file is malformed, the error is logged and the last valid values are
+    /// used. Variables set explicitly on the command take precedence.
+    #[clap(long)]
+    pub env_file: Option<PathBuf>,
+    /// Read more settings from this file.
+    ///
+    /// The file contains `KEY = VALUE` lines: `watch = PATH` and
+    /// `exclude = PATH` add a watched or an excluded path, relative to the
+    /// directory of the file, and `debounce = MS` sets the debounce in
+    /// milliseconds. Blank lines and lines starting with `#` are ignored.
+    #[clap(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Apply the changes of the `--config` file while watching, without
+    /// relaunching the command.
+    ///
+    /// The excluded paths and the debounce are replaced. The paths added to
+    /// the watched paths are watched, the removed ones are ignored.
+    #[clap(long)]
+    pub reload_on_config_change: bool,
+    /// Apply the changes of the `--config` file when receiving `SIGUSR2`
+    /// (Unix only), without relaunching the command.
+    ///
+    /// Like with `--reload-on-config-change`, the excluded paths and the
+    /// debounce are replaced, the paths added to the watched paths are
+    /// watched and the removed ones are ignored. The other settings are not
+    /// reloaded.
+    #[clap(long)]
+    pub reload_on_signal: bool,
+    /// Maximum number of directories to watch.
+    ///
+    /// Every directory is watched individually, skipping the excluded and
+    /// hidden ones. The directories are registered breadth-first, the deepest
+    /// ones are left out when the limit is reached.
+    ///
+    /// On macOS and Windows, where a single watch can cover a whole tree, this
+    /// still means a watch per directory: an FSEvents stream or a directory
+    /// handle each, which makes the start slower on large trees.
+    ///
+    /// The default is no limit.
+    #[clap(long)]
+    pub max_watches: Option<usize>,
+    /// Only watch the directories up to this depth below the watched paths.
+    ///
+    /// With a depth of 0 only the files directly in the watched directories
+    /// are watched, with a depth of 1 the files of their sub-directories too,
+    /// and so on. Changes in deeper directories don't relaunch the command.
+    ///
+    /// The default is no limit.
+    #[clap(long)]
+    pub max_depth: Option<usize>,
+    /// Run the command from the directory of the package where the changes
+    /// occurred.
+    ///
+    /// When the changes span multiple packages, or on the first run, the
+    /// command runs from the current directory. Commands that have their own
+    /// working directory (set with `Command::current_dir`) keep it.
+    #[clap(long)]
+    pub package_dir: bool,
+    /// Relaunch the command every time a line is written to this named pipe
+    /// (Unix only).
+    ///
+    /// The named pipe is created if it doesn't exist.
+    #[clap(long)]
+    pub trigger_fifo: Option<PathBuf>,
+    /// Relaunch the command every day at these times, in local time, given as
+    /// `HH:MM`.
+    ///
+    /// This is useful for generators embedding the date. The times are the
+    /// durations since midnight. Multiple times can be given, separated by
+    /// commas.
+    #[clap(long, value_name = "HH:MM", value_delimiter = ',', value_parser = parse_time_of_day)]
+    pub rerun_at: Vec<Duration>,
+    /// Listen for commands on this Unix domain socket (Unix only).
+    ///
+    /// Every line sent is a command answered with a line: `ping` (answers
+    /// `pong`), `rerun` to relaunch the command, `status` (answers `running`
+    /// or `idle`) and `stop` to stop the watch.
+    ///
+    /// A build tool can also name the files that changed with a JSON line
+    /// like `{"changed": ["src/lib.rs"]}`, relaunching the command with
+    /// exactly those paths as changes. Relative paths are relative to the
+    /// workspace root.
+    #[clap(long)]
+    pub control_socket: Option<PathBuf>,
+    /// Relaunch the command when the git `HEAD` or a branch changes.
+    ///
+    /// The other changes are ignored for a short moment after `HEAD` changes
+    /// so a checkout only relaunches the command once.
+    #[clap(long)]
+    pub watch_git_head: bool,
+    /// Watch the git submodules of the workspace, as listed in its
+    /// `.gitmodules`.
+    ///
+    /// The submodules are added to the watched paths, even when they are
+    /// outside of the other watched paths, while their `.git` is excluded.
+    /// The submodules are found using the `git` command line.
+    #[clap(long)]
+    pub include_submodules: bool,
+    /// Lower the scheduling priority of the command.
+    ///
+    /// On Unix this is the niceness of the command, between -20 and 19 (only a
+    /// privileged user can use a negative value). On Windows, a value of 10 or
+    /// more uses the idle priority class, a value between 1 and 9 the below
+    /// normal priority class and a negative value the above normal priority
+    /// class. Values out of range are clamped.
+    #[clap(long, allow_hyphen_values = true)]
+    pub nice: Option<i32>,
+    /// Set the file mode creation mask of the command, in octal (Unix only).
+    ///
+    /// The files created by the command don't depend on the umask of the shell
+    /// that started the watch. This is ignored on other platforms.
+    #[clap(long, value_name = "OCTAL", value_parser = parse_umask)]
+    pub umask: Option<u32>,
+    /// Run the command in a clean environment, with only the variables set
+    /// explicitly on the command.
+    ///
+    /// The `PATH` is not inherited either: it needs to be set explicitly for
+    /// the command to start other programs.
+    #[clap(long)]
+    pub clean_env: bool,
+    /// Target triple to build for, like `wasm32-unknown-unknown`, given to
+    /// the command as `CARGO_BUILD_TARGET`.
+    ///
+    /// A command setting `CARGO_BUILD_TARGET` explicitly keeps its own value.
+    #[clap(long)]
+    pub cargo_target: Option<String>,
+    /// Run the command in a pseudo-terminal (Unix only).
+    ///
+    /// The output of the command goes through a pseudo-terminal so the command
+    /// keeps its terminal behavior (like colors) even when the output of the
+    /// watch is redirected. The output redirected on the command itself is
+    /// kept.
+    ///
+    /// The pseudo-terminal has the size of the terminal of the watch and
+    /// follows its resizes (`SIGWINCH`), so progress bars and other terminal
+    /// interfaces render correctly, like in a tmux pane.
+    #[clap(long)]
+    pub pty: bool,
+    /// Log every command before running it.
+    #[clap(long)]
+    pub show_command: bool,
+    /// Log the changed paths grouped by kind (created, modified or removed)
+    /// before every rerun.
+    #[clap(long)]
+    pub show_changes: bool,
+    /// Tag every rerun with what triggered it: `[initial]`, `[file: <path>]`,
+    /// `[manual]` (named pipe, control socket or session), `[git]`,
+    /// `[scheduled]`, `[rescan]` (missed events) or `[crash]`.
+    #[clap(long)]
+    pub show_reason: bool,
+    /// Log how long each stage of a rerun takes.
+    ///
+    /// This covers the handling of the file event, the termination of the
+    /// previous run, the spawn of the commands and their execution.
+    #[clap(long)]
+    pub profile: bool,
+    /// Append a line to this file after every run.
+    ///
+    /// The line contains the time of the end of the run (seconds since the
+    /// Unix epoch), the exit status of the command and the changed paths that
+    /// triggered the run, independently of the console logging.
+    #[clap(long)]
+    pub log_file: Option<PathBuf>,
+    /// Record the start of the last successful run in this file.
+    ///
+    /// On startup, the watched files modified since the recorded run are
+    /// given to the first run as if they changed while watching.
+    #[clap(long)]
+    pub since_file: Option<PathBuf>,
+    /// Copy the output of the command to a new file in this directory for
+    /// every run, while still showing it.
+    ///
+    /// The files are named after the start time of the run, like
+    /// `run-1700000000.042.log`.
+    ///
+    /// The command writes to a pipe instead of the terminal, so most programs
+    /// stop coloring their output, unless it runs in a pseudo-terminal with
+    /// `--pty`. The output redirected on the command itself is kept, except
+    /// outside of Unix where it's replaced.
+    #[clap(long, value_name = "DIR")]
+    pub tee: Option<PathBuf>,
+    /// Start every line of output with the name of the command that printed
+    /// it, like `[cargo#1]`.
+    ///
+    /// Commands are named after their program, followed by their position in
+    /// the list when several commands share a program. Like with `--tee`, the
+    /// command doesn't write to the terminal anymore, unless it runs in a
+    /// pseudo-terminal with `--pty`.
+    #[clap(long)]
+    pub prefix_output: bool,
+    /// Pause and resume the watch when receiving `SIGUSR1` (Unix only).
+    ///
+    /// Changes are still detected while paused but the command is not
+    /// relaunched.
+    #[clap(long)]
+    pub pause_on_signal: bool,
+    /// Relaunch the command when resuming the watch if changes occurred while
+    /// it was paused.
+    #[clap(long)]
+    pub run_on_resume: bool,
+    /// Pause the watch while the standard input or output is no longer a
+    /// terminal, like when the terminal is closed.
+    ///
+    /// Only the streams that were terminals when the watch started are
+    /// checked.
+    #[clap(long)]
+    pub pause_when_detached: bool,
+    /// Pause the watch while the machine runs on battery (Linux and macOS
+    /// only).
+    ///
+    /// With `--run-on-resume`, the changes detected on battery are covered by
+    /// a single run when the power comes back.
+    #[clap(long)]
+    pub skip_on_battery: bool,
+    /// Leave the command running when the watch stops instead of terminating
+    /// it.
+    ///
+    /// The command is not waited for anymore: it is orphaned once the process
+    /// running the watch exits, and needs to be stopped by other means.
+    #[clap(long)]
+    pub detach_on_exit: bool,
+    /// Don't run the command when the watch starts, wait for the first change
+    /// instead.
+    ///
+    /// The command still runs at start when files changed since the last run
+    /// recorded by `--since-file`, or with `--run-now`.
+    #[clap(long)]
+    pub skip_initial: bool,
+    /// Run the command when the watch starts, even with `--skip-initial`.
+    ///
+    /// Meant to be given for a single invocation while `--skip-initial` is
+    /// set in an alias or a script: the initial run happens exactly once, then
+    /// the watch waits for changes as usual.
+    #[clap(long)]
+    pub run_now: bool,
+    /// Signals sent to terminate the command, with their delay in seconds
+    /// since the start of the termination (Unix only).
+    ///
+    /// For example `INT:0,TERM:2,KILL:5` sends `SIGINT`, then `SIGTERM` after
+    /// 2 seconds and `SIGKILL` after 5 seconds if the command is still
+    /// running. The command is killed 2 seconds after the last signal if it
+    /// still runs. The default is `TERM:0`.
+    #[clap(long, value_name = "SIGNAL:DELAY", value_delimiter = ',', value_parser = parse_kill_step)]
+    pub kill_escalation: Vec<KillStep>,
+    /// Log how long the command takes to exit once asked to terminate, and
+    /// whether it had to be killed.
+    ///
+    /// Useful to pick the delays of `--kill-escalation`.
+    #[clap(long)]
+    pub measure_shutdown: bool,
+    /// Wait before re-running a command that keeps failing.
+    ///
+    /// The delay starts at 1 second and doubles after each consecutive
+    /// failure, up to 30 seconds. Changes detected in the meantime are
+    /// covered by the delayed run. A successful run resets the delay.
+    #[clap(long)]
+    pub backoff: bool,
+    /// Relaunch the command when it fails on its own, without waiting for a
+    /// change.
+    ///
+    /// A command exiting successfully is not relaunched. Use it along with
+    /// `--backoff` to avoid relaunching a command that keeps failing
+    /// immediately in a tight loop.
+    #[clap(long)]
+    pub restart_on_crash: bool,
+    /// Maximum delay when [`backoff`](Self::backoff) is enabled.
+    #[clap(skip = Duration::from_secs(30))]
+    pub backoff_max: Duration,
+    /// What to do when changes are detected while the command is still
+    /// running.
+    #[clap(long, value_enum, default_value_t)]
+    pub on_change_while_running: ChangeWhileRunning,
+    /// What to do when the program of a command doesn't exist.
+    #[clap(long, value_enum, default_value_t)]
+    pub on_missing_program: MissingProgram,
+    /// Standard input of the command.
+    ///
+    /// The command inherits the standard input of the watch by default: the
+    /// watch itself never reads it while watching. Use `null` when the
+    /// standard input is used by something else, like the application
+    /// embedding the watch, otherwise the command and the other reader
+    /// compete for the input.
+    #[clap(long, value_enum, default_value_t)]
+    pub child_stdin: ChildStdin,
+    /// What to do with a command containing a token without value.
+    ///
+    /// The tokens `{file}`, `{files}`, `{dir}` and `{package}` in the
+    /// arguments of the templated commands are replaced by the first changed
+    /// path, all the changed paths, the directory of the first changed path
+    /// and the name of the package containing the changed paths. They have no
+    /// value on the first run, or for `{package}` when the changed paths are
+    /// not all in the same package.
+    ///
+    /// The token `{packages}` is replaced by a `-p <name>` flag for each
+    /// package containing changed paths, to build them all in a single cargo
+    /// invocation. It always has a value, see `--no-changed-package`. It must
+    /// be a whole argument: a command using it in a larger argument is
+    /// skipped.
+    #[clap(long, value_enum, default_value_t)]
+    pub on_unresolved_token: UnresolvedToken,
+    /// What the token `{packages}` is replaced by when no package contains
+    /// changed paths, like on the first run.
+    #[clap(long, value_enum, default_value_t)]
+    pub no_changed_package: NoChangedPackage,
+    /// Watched paths containing wildcards, with their parent without
+    /// wildcards canonicalized.
+    #[clap(skip)]
+    watch_globs: Vec<PathBuf>,
+    /// Watched paths as given, before following the symlinks, with their
+    /// canonical form.
+    #[clap(skip)]
+    watch_aliases: Vec<(PathBuf, PathBuf)>,
+    /// Excluded paths indexed for the lookups, once resolved.
+    #[clap(skip)]
+    exclude_index: Option<Arc<ExcludeIndex>>,
+    /// Settings added by the config file, replaced when it's reloaded.
+    #[clap(skip)]
+    applied_config: AppliedConfig,
+    /// Command that needs to succeed before starting to watch, with the
+    /// number of retries allowed.
+    #[clap(skip)]
+    wait_for: Option<(CommandList, u32)>,
+    /// Command run once when the watch stops.
+    #[clap(skip)]
+    teardown: Option<CommandList>,
+    /// Command run after a period without changes, with the duration of the
+    /// period.
+    #[clap(skip)]
+    quiet_command: Option<(CommandList, Duration)>,
+    /// Commands run instead of the main command when files with a given
+    /// extension or name change.
+    #[clap(skip)]
+    bound_commands: Vec<(Binding, CommandList)>,
+    /// Port of the live reload server.
+    #[cfg(feature = "live-reload")]
+    #[clap(skip)]
+    live_reload_port: Option<u16>,
+    /// Live reload server notified after every successful run.
+    #[cfg(feature = "live-reload")]
+    #[clap(skip)]
+    live_reload: Option<live_reload::LiveReloadServer>,
+    /// Callback deciding if the command is relaunched for a batch of changed
+    /// paths.
+    #[clap(skip)]
+    should_rerun: Option<Hook<ShouldRerun>>,
+    /// Callback delaying the rerun until it returns `true`.
+    #[clap(skip)]
+    ready_when: Option<Hook<ReadyWhen>>,
+    /// Callback invoked right before terminating the command for a rerun.
+    #[clap(skip)]
+    before_terminate: Option<Hook<BeforeTerminate>>,
+    /// Callback rendering the line logged at the end of every run.
+    #[clap(skip)]
+    summary_formatter: Option<Hook<SummaryFormatter>>,
+    /// Number of runs in progress, shared between the clones of the watch.
+    #[clap(skip)]
+    running: Arc<AtomicUsize>,
+    /// The watch has been asked to stop, shared between the clones of the
+    /// watch.
+    #[clap(skip)]
+    stopped: Arc<AtomicBool>,
+    /// Error stopping the watch, shared between the clones of the watch.
+    #[clap(skip)]
+    fatal_error: Arc<Mutex<Option<String>>>,
+    /// The watch has been paused by a [`WatchSession`], shared between the
+    /// clones of the watch.
+    #[clap(skip)]
+    paused: Arc<AtomicBool>,
+    /// End of the last successful run, shared between the clones of the watch.
+    #[clap(skip)]
+    last_success: Arc<Mutex<Option<Instant>>>,
+    /// Watched paths of the running watch, changed by a [`WatchSession`],
+    /// shared between the clones of the watch.
+    #[clap(skip)]
+    live: Arc<RwLock<Option<LiveWatch>>>,
+    /// Paths added by a [`WatchSession`] before the watch started.
+    #[clap(skip)]
+    queued_watches: Arc<Mutex<Vec<PathBuf>>>,
+    /// Number of `SIGINT` received by the process when the watch loop started.
+    #[clap(skip)]
+    interrupts: Arc<AtomicUsize>,
+    /// Reloads the config file of the running watch, set once the watch
+    /// starts with [`reload_on_signal`](Self::reload_on_signal).
+    #[clap(skip)]
+    config_reloader: Option<Hook<ReloadConfig>>,
+    /// Number of `SIGUSR2` received by the process when the config file was
+    /// last reloaded.
+    #[clap(skip)]
+    reloads: Arc<AtomicUsize>,
+    /// Wakes up the watch loop, set once the watch starts.
+    #[clap(skip)]
+    wake: Option<mpsc::Sender<Trigger>>,
+    /// Last valid variables of the environment file.
+    #[clap(skip)]
+    env_file_vars: Arc<Mutex<Vec<(String, String)>>>,
 }
 
 impl Watch {
@@ -237,6 +896,40 @@ impl Watch {
         self
     }
 
+    /// Add a path outside of the workspace to watch for changes, like a
+    /// sibling directory.
+    ///
+    /// A relative `path` is relative to the workspace root rather than the
+    /// current directory. The hidden and backup files are detected relative
+    /// to the closest watched path, so the path can be watched along with the
+    /// workspace.
+    pub fn watch_external(mut self, path: impl AsRef<Path>) -> Self {
+        self.watch_paths
+            .push(metadata().workspace_root.as_std_path().join(path));
+        self
+    }
+
+    /// Read more paths to watch from a file, or from the standard input with
+    /// `-`, one path per line.
+    pub fn watch_from(mut self, path: impl Into<PathBuf>) -> Self {
+        self.watch_from = Some(path.into());
+        self
+    }
+
+    /// Watch the source files listed in the dep-info files of the last build
+    /// instead of the whole workspace.
+    pub fn dep_info(mut self) -> Self {
+        self.dep_info = true;
+        self
+    }
+
+    /// Also watch the files outside the workspace listed in the dep-info files
+    /// of the last build.
+    pub fn dep_info_external(mut self) -> Self {
+        self.dep_info_external = true;
+        self
+    }
+
     /// Add a path that will be ignored if changes are detected.
     pub fn exclude_path(mut self, path: impl AsRef<Path>) -> Self {
         self.exclude_paths.push(path.as_ref().to_path_buf());
@@ -272,329 +965,9055 @@ impl Watch {
         self
     }
 
-    /// Set the debounce duration after relaunching the command.
-    pub fn debounce(mut self, duration: Duration) -> Self {
-        self.debounce = duration;
+    /// Add a path, relative to `root`, that will be ignored if changes are
+    /// detected within `root` only.
+    ///
+    /// ```rust,no_run
+    /// use xtask_watch::{CommandList, Watch};
+    ///
+    /// Watch::default()
+    ///     .watch_paths(["frontend", "backend"])
+    ///     .exclude_path_in("frontend", "node_modules")
+    ///     .exclude_path_in("backend", "target")
+    ///     .run(CommandList::from_argv(["make"]))
+    ///     .expect("cannot run the watch");
+    /// ```
+    pub fn exclude_path_in(mut self, root: impl AsRef<Path>, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        match self
+            .root_exclude_paths
+            .iter_mut()
+            .find(|(x, _)| x == root.as_ref())
+        {
+            Some((_, paths)) => paths.push(path),
+            None => self
+                .root_exclude_paths
+                .push((root.as_ref().to_path_buf(), vec![path])),
+        }
         self
     }
 
-    /// Run the given `command`, monitor the watched paths and relaunch the
-    /// command when changes are detected.
+    /// Watch the target directory instead of excluding it.
+    pub fn include_target(mut self) -> Self {
+        self.include_target = true;
+        self
+    }
+
+    /// Exclude this target directory instead of the workspace's one.
     ///
-    /// Workspace's `target` directory and hidden paths are excluded by default.
-    pub fn run(mut self, commands: impl Into<CommandList>) -> Result<()> {
-        let commands = commands.into();
-        let metadata = metadata();
+    /// Can be called multiple times to exclude several target directories.
+    pub fn exclude_target(mut self, path: impl AsRef<Path>) -> Self {
+        self.exclude_targets.push(path.as_ref().to_path_buf());
+        self
+    }
 
-        self.exclude_paths
-            .push(metadata.target_directory.clone().into_std_path_buf());
+    /// Exclude this target directory in addition to the workspace's one.
+    ///
+    /// Can be called multiple times to exclude several target directories.
+    pub fn extra_target_dir(mut self, path: impl AsRef<Path>) -> Self {
+        self.extra_target_dirs.push(path.as_ref().to_path_buf());
+        self
+    }
 
-        self.exclude_paths = self
-            .exclude_paths
-            .into_iter()
-            .map(|x| {
-                x.canonicalize()
-                    .with_context(|| format!("can't find {}", x.display()))
-            })
-            .collect::<Result<Vec<_>, _>>()?;
+    /// Skip the watched and excluded paths that don't exist instead of
+    /// failing.
+    pub fn lenient_paths(mut self) -> Self {
+        self.lenient_paths = true;
+        self
+    }
 
-        if self.watch_paths.is_empty() {
-            self.watch_paths
-                .push(metadata.workspace_root.clone().into_std_path_buf());
-        }
+    /// Watch the hidden files and directories instead of ignoring them.
+    pub fn include_hidden(mut self) -> Self {
+        self.include_hidden = true;
+        self
+    }
 
-        self.watch_paths = self
-            .watch_paths
-            .into_iter()
-            .map(|x| {
-                x.canonicalize()
-                    .with_context(|| format!("can't find {}", x.display()))
-            })
-            .collect::<Result<Vec<_>, _>>()?;
+    /// Watch the hidden files or directories named `name`, the other hidden
+    /// paths being still ignored.
+    pub fn watch_hidden(mut self, name: impl Into<String>) -> Self {
+        self.watch_hidden.push(name.into());
+        self
+    }
 
-        let (tx, rx) = mpsc::channel();
+    /// Add the `src` directory of every member of the workspace to the watched
+    /// paths.
+    ///
+    /// Since this adds watch paths, the workspace root will not be watched by
+    /// default anymore: only the `src` directories (and any other path added
+    /// with [`watch_path`](Self::watch_path)) will be.
+    pub fn watch_workspace_sources(mut self) -> Self {
+        self.watch_paths.extend(workspace_source_dirs(metadata()));
+        self
+    }
 
-        let handler = WatchEventHandler {
-            watch: self.clone(),
-            tx,
-            command_start: Instant::now(),
-        };
+    /// Set the debounce duration after relaunching the command.
+    pub fn debounce(mut self, duration: Duration) -> Self {
+        self.debounce = duration;
+        self
+    }
 
-        let mut watcher =
-            notify::recommended_watcher(handler).context("could not initialize watcher")?;
+    /// Relaunch the command when files are deleted, with a specific debounce
+    /// duration after a deletion.
+    pub fn delete_debounce(mut self, duration: Duration) -> Self {
+        self.delete_debounce = Some(duration);
+        self
+    }
 
-        for path in &self.watch_paths {
-            match watcher.watch(path, RecursiveMode::Recursive) {
-                Ok(()) => log::trace!("Watching {}", path.display()),
-                Err(err) => log::error!("cannot watch {}: {err}", path.display()),
-            }
-        }
+    /// Relaunch the command for the changes in a directory once none occurred
+    /// in it for `duration`.
+    pub fn directory_debounce(mut self, duration: Duration) -> Self {
+        self.directory_debounce = Some(duration);
+        self
+    }
 
-        let mut current_child = SharedChild::new();
-        loop {
-            {
-                log::info!("Re-running command");
-                let mut current_child = current_child.clone();
-                let mut commands = commands.clone();
-                thread::spawn(move || {
-                    let mut status = ExitStatus::default();
-                    commands.spawn(|res| match res {
-                        Err(err) => {
-                            log::error!("Could not execute command: {err}");
-                            false
-                        }
-                        Ok(child) => {
-                            log::trace!("new child: {}", child.id());
-                            current_child.replace(child);
-                            status = current_child.wait();
-                            status.success()
-                        }
-                    });
-                    if status.success() {
-                        log::info!("Command succeeded.");
-                    } else if let Some(code) = status.code() {
-                        log::error!("Command failed (exit code: {code})");
-                    } else {
-                        log::error!("Command failed.");
-                    }
-                });
-            }
+    /// Ignore the changes of a file for `duration` after a change of the same
+    /// file relaunched the command.
+    pub fn file_debounce(mut self, duration: Duration) -> Self {
+        self.file_debounce = Some(duration);
+        self
+    }
 
-            let res = rx.recv();
-            if res.is_ok() {
-                log::trace!("changes detected");
-            }
-            current_child.terminate();
-            if res.is_err() {
-                break;
-            }
-        }
+    /// Ignore the changes for `duration` once the paths are watched.
+    pub fn startup_quiet(mut self, duration: Duration) -> Self {
+        self.startup_quiet = Some(duration);
+        self
+    }
 
-        Ok(())
+    /// Wait for the changes to stop for `duration` before relaunching the
+    /// command.
+    pub fn settle(mut self, duration: Duration) -> Self {
+        self.settle = Some(duration);
+        self
     }
 
-    fn is_excluded_path(&self, path: &Path) -> bool {
-        if self.exclude_paths.iter().any(|x| path.starts_with(x)) {
-            return true;
-        }
+    /// Relaunch the command when the permissions or other metadata of a file
+    /// change.
+    pub fn on_chmod(mut self) -> Self {
+        self.on_chmod = true;
+        self
+    }
 
-        if let Ok(stripped_path) = path.strip_prefix(metadata().workspace_root.as_std_path()) {
-            if self
-                .workspace_exclude_paths
-                .iter()
-                .any(|x| stripped_path.starts_with(x))
-            {
-                return true;
-            }
-        }
+    /// Only relaunch the command for these kinds of events.
+    pub fn events(mut self, events: impl IntoIterator<Item = TriggerEvent>) -> Self {
+        self.events.extend(events);
+        self
+    }
 
-        false
+    /// Only relaunch the command when files appear in `dir`, which is watched
+    /// too.
+    pub fn inbox(mut self, dir: impl AsRef<Path>) -> Self {
+        self.inbox.push(dir.as_ref().to_path_buf());
+        self
     }
 
-    fn is_hidden_path(&self, path: &Path) -> bool {
-        self.watch_paths.iter().any(|x| {
-            path.strip_prefix(x)
-                .iter()
-                .any(|x| x.to_string_lossy().starts_with('.'))
-        })
+    /// Ring the terminal bell when the command fails.
+    pub fn bell(mut self) -> Self {
+        self.bell = true;
+        self
     }
 
-    fn is_backup_file(&self, path: &Path) -> bool {
-        self.watch_paths.iter().any(|x| {
-            path.strip_prefix(x)
-                .iter()
-                .any(|x| x.to_string_lossy().ends_with('~'))
-        })
+    /// Run cargo commands with `--message-format=json` and print a summary of
+    /// the errors and warnings after each run.
+    pub fn cargo_json(mut self) -> Self {
+        self.cargo_json = true;
+        self
     }
-}
 
-struct WatchEventHandler {
-    watch: Watch,
-    tx: mpsc::Sender<()>,
-    command_start: Instant,
-}
+    /// Only relaunch the command when the given marker file is created or
+    /// updated.
+    pub fn ready_marker(mut self, path: impl AsRef<Path>) -> Self {
+        self.ready_marker = Some(path.as_ref().to_path_buf());
+        self
+    }
 
-impl EventHandler for WatchEventHandler {
-    fn handle_event(&mut self, event: Result<Event, notify::Error>) {
-        match event {
-            Ok(event) => {
-                if event.paths.iter().any(|x| {
-                    !self.watch.is_excluded_path(x)
-                        && x.exists()
-                        && !self.watch.is_hidden_path(x)
-                        && !self.watch.is_backup_file(x)
-                        && event.kind != notify::EventKind::Create(notify::event::CreateKind::Any)
-                        && event.kind
-                            != notify::EventKind::Modify(notify::event::ModifyKind::Name(
-                                notify::event::RenameMode::Any,
-                            ))
-                        && self.command_start.elapsed() >= self.watch.debounce
-                }) {
-                    log::trace!("Changes detected in {event:?}");
-                    self.command_start = Instant::now();
+    /// Read environment variables for the command from the given file.
+    pub fn env_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.env_file = Some(path.into());
+        self
+    }
 
-                    self.tx.send(()).expect("can send");
-                } else {
-                    log::trace!("Ignoring changes in {event:?}");
-                }
-            }
-            Err(err) => log::error!("watch error: {err}"),
-        }
+    /// Read more settings from the given file.
+    ///
+    /// See [`Watch::config`] for the format of the file.
+    pub fn config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config = Some(path.into());
+        self
     }
-}
 
+    /// Apply the changes of the config file while watching.
+    pub fn reload_on_config_change(mut self) -> Self {
+        self.reload_on_config_change = true;
+        self
+    }
+
+    /// Apply the changes of the config file when receiving `SIGUSR2` (Unix
+    /// only).
+    ///
+    /// See [`Watch::reload_on_signal`] for the settings reloaded.
+    pub fn reload_on_signal(mut self) -> Self {
+        self.reload_on_signal = true;
+        self
+    }
+
+    /// Set the maximum number of directories to watch.
+    pub fn max_watches(mut self, max: usize) -> Self {
+        self.max_watches = Some(max);
+        self
+    }
+
+    /// Only watch the directories up to `depth` levels below the watched
+    /// paths.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Run the command from the directory of the package where the changes
+    /// occurred.
+    pub fn package_dir(mut self) -> Self {
+        self.package_dir = true;
+        self
+    }
+
+    /// Relaunch the command every time a line is written to the given named
+    /// pipe (Unix only).
+    pub fn trigger_fifo(mut self, path: impl AsRef<Path>) -> Self {
+        self.trigger_fifo = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Relaunch the command every day at `hour:minute`, in local time.
+    ///
+    /// Can be called multiple times to relaunch the command at several times.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hour` is not below 24 or `minute` is not below 60.
+    pub fn rerun_at(mut self, hour: u8, minute: u8) -> Self {
+        assert!(hour < 24 && minute < 60, "invalid time {hour}:{minute}");
+        self.rerun_at.push(Duration::from_secs(
+            u64::from(hour) * 3600 + u64::from(minute) * 60,
+        ));
+        self
+    }
+
+    /// Listen for commands on the given Unix domain socket (Unix only).
+    ///
+    /// See [`Watch::control_socket`] for the commands available.
+    pub fn control_socket(mut self, path: impl AsRef<Path>) -> Self {
+        self.control_socket = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Relaunch the command when the git `HEAD` or a branch changes.
+    pub fn watch_git_head(mut self) -> Self {
+        self.watch_git_head = true;
+        self
+    }
+
+    /// Watch the git submodules of the workspace, excluding their `.git`.
+    pub fn include_submodules(mut self) -> Self {
+        self.include_submodules = true;
+        self
+    }
+
+    /// Lower the scheduling priority of the command.
+    ///
+    /// See [`Watch::nice`] for the valid values on each platform.
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Set the file mode creation mask of the command (Unix only).
+    ///
+    /// Only the permission bits (`0o777`) are used.
+    pub fn umask(mut self, umask: u32) -> Self {
+        self.umask = Some(umask);
+        self
+    }
+
+    /// Run the command in a clean environment, with only the variables set
+    /// explicitly on the command.
+    ///
+    /// The `PATH` is not inherited either: it needs to be set explicitly for
+    /// the command to start other programs.
+    pub fn clean_env(mut self) -> Self {
+        self.clean_env = true;
+        self
+    }
+
+    /// Set the target triple to build for, given to the command as
+    /// `CARGO_BUILD_TARGET`.
+    ///
+    /// ```rust,no_run
+    /// use std::process::Command;
+    /// use xtask_watch::Watch;
+    ///
+    /// let mut build = Command::new("cargo");
+    /// build.arg("build");
+    ///
+    /// Watch::default()
+    ///     .cargo_target("wasm32-unknown-unknown")
+    ///     .run(build)
+    ///     .expect("cannot run the watch");
+    /// ```
+    pub fn cargo_target(mut self, triple: impl Into<String>) -> Self {
+        self.cargo_target = Some(triple.into());
+        self
+    }
+
+    /// Run the command in a pseudo-terminal (Unix only).
+    pub fn pty(mut self) -> Self {
+        self.pty = true;
+        self
+    }
+
+    /// Log every command before running it.
+    pub fn show_command(mut self) -> Self {
+        self.show_command = true;
+        self
+    }
+
+    /// Log the changed paths grouped by kind before every rerun.
+    pub fn show_changes(mut self) -> Self {
+        self.show_changes = true;
+        self
+    }
+
+    /// Tag every rerun with what triggered it.
+    pub fn show_reason(mut self) -> Self {
+        self.show_reason = true;
+        self
+    }
+
+    /// Log how long each stage of a rerun takes.
+    pub fn profile(mut self) -> Self {
+        self.profile = true;
+        self
+    }
+
+    /// Append a line to a file after every run.
+    pub fn log_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.log_file = Some(path.into());
+        self
+    }
+
+    /// Record the start of the last successful run in a file, giving the files
+    /// modified since to the first run.
+    pub fn since_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.since_file = Some(path.into());
+        self
+    }
+
+    /// Copy the output of the command to a new file in `dir` for every run.
+    pub fn tee(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.tee = Some(dir.into());
+        self
+    }
+
+    /// Start every line of output with the name of the command that printed
+    /// it.
+    pub fn prefix_output(mut self) -> Self {
+        self.prefix_output = true;
+        self
+    }
+
+    /// Pause and resume the watch when receiving `SIGUSR1` (Unix only).
+    ///
+    /// When `run_on_resume` is `true`, the command is relaunched when resuming
+    /// if changes occurred while the watch was paused.
+    pub fn pause_on_signal(mut self, run_on_resume: bool) -> Self {
+        self.pause_on_signal = true;
+        self.run_on_resume = run_on_resume;
+        self
+    }
+
+    /// Pause the watch while the standard input or output is no longer a
+    /// terminal.
+    pub fn pause_when_detached(mut self) -> Self {
+        self.pause_when_detached = true;
+        self
+    }
+
+    /// Pause the watch while the machine runs on battery (Linux and macOS
+    /// only).
+    pub fn skip_on_battery(mut self) -> Self {
+        self.skip_on_battery = true;
+        self
+    }
+
+    /// Leave the command running when the watch stops instead of terminating
+    /// it.
+    pub fn detach_on_exit(mut self) -> Self {
+        self.detach_on_exit = true;
+        self
+    }
+
+    /// Send `signal` to terminate the command, `delay` after the start of the
+    /// termination (Unix only).
+    ///
+    /// Can be called multiple times to escalate the signals, see
+    /// [`Watch::kill_escalation`].
+    pub fn kill_step(mut self, signal: i32, delay: Duration) -> Self {
+        self.kill_escalation.push(KillStep { signal, delay });
+        self
+    }
+
+    /// Log how long the command takes to exit once asked to terminate.
+    pub fn measure_shutdown(mut self) -> Self {
+        self.measure_shutdown = true;
+        self
+    }
+
+    /// Relaunch the command when it fails on its own, without waiting for a
+    /// change.
+    pub fn restart_on_crash(mut self) -> Self {
+        self.restart_on_crash = true;
+        self
+    }
+
+    /// Wait for the first change instead of running the command when the watch
+    /// starts.
+    pub fn skip_initial(mut self) -> Self {
+        self.skip_initial = true;
+        self
+    }
+
+    /// Run the command when the watch starts, overriding
+    /// [`skip_initial`](Self::skip_initial).
+    pub fn run_now(mut self) -> Self {
+        self.run_now = true;
+        self
+    }
+
+    /// Wait before re-running a command that keeps failing, doubling the delay
+    /// after each consecutive failure, up to `max`.
+    pub fn backoff(mut self, max: Duration) -> Self {
+        self.backoff = true;
+        self.backoff_max = max;
+        self
+    }
+
+    /// Set the standard input of the command.
+    pub fn child_stdin(mut self, stdin: ChildStdin) -> Self {
+        self.child_stdin = stdin;
+        self
+    }
+
+    /// Set what to do when changes are detected while the command is still
+    /// running.
+    pub fn on_change_while_running(mut self, policy: ChangeWhileRunning) -> Self {
+        self.on_change_while_running = policy;
+        self
+    }
+
+    /// Set what to do when the program of a command doesn't exist.
+    pub fn on_missing_program(mut self, policy: MissingProgram) -> Self {
+        self.on_missing_program = policy;
+        self
+    }
+
+    /// Set what to do with a command containing a token without value.
+    ///
+    /// See [`Watch::on_unresolved_token`] for the tokens available.
+    pub fn on_unresolved_token(mut self, policy: UnresolvedToken) -> Self {
+        self.on_unresolved_token = policy;
+        self
+    }
+
+    /// Set what the token `{packages}` is replaced by when no package contains
+    /// changed paths, like on the first run.
+    pub fn no_changed_package(mut self, policy: NoChangedPackage) -> Self {
+        self.no_changed_package = policy;
+        self
+    }
+
+    /// Run a command before starting to watch, retrying it up to `retries`
+    /// times until it succeeds.
+    ///
+    /// [`run`](Self::run) returns an error if the command still fails after
+    /// the last retry.
+    pub fn wait_for(mut self, commands: impl Into<CommandList>, retries: u32) -> Self {
+        self.wait_for = Some((commands.into(), retries));
+        self
+    }
+
+    /// Run a command once when the watch stops, like to stop a container.
+    ///
+    /// The command runs when [`run`](Self::run) returns, including when it
+    /// fails. On Unix, `Ctrl-C` (`SIGINT`) stops the watch instead of exiting
+    /// immediately so the command can run. The previous handler of the signal
+    /// is still called, and restored when the watch stops.
+    pub fn teardown_command(mut self, commands: impl Into<CommandList>) -> Self {
+        self.teardown = Some(commands.into());
+        self
+    }
+
+    /// Run another command once no change has been detected for `quiet`, like
+    /// a slow test suite next to a quick `cargo check`.
+    ///
+    /// The command runs alongside the main command, at most once per quiet
+    /// period: it is terminated when changes are detected while it runs, and
+    /// runs again after the next quiet period. The start of the watch counts
+    /// as a change.
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use xtask_watch::{CommandList, Watch};
+    ///
+    /// Watch::default()
+    ///     .quiet_command(
+    ///         CommandList::from_argv(["cargo", "test"]),
+    ///         Duration::from_secs(30),
+    ///     )
+    ///     .run(CommandList::from_argv(["cargo", "check"]))
+    ///     .expect("cannot run the watch");
+    /// ```
+    pub fn quiet_command(mut self, commands: impl Into<CommandList>, quiet: Duration) -> Self {
+        self.quiet_command = Some((commands.into(), quiet));
+        self
+    }
+
+    /// Run `commands` instead of the main command when files with the
+    /// extension `ext` change.
+    ///
+    /// When a batch of changes contains several extensions, the commands bound
+    /// to each of them run one after the other. The main command runs on the
+    /// first run, on manual reruns and for the changed files whose extension
+    /// isn't bound.
+    ///
+    /// ```rust,no_run
+    /// use xtask_watch::{CommandList, Watch};
+    ///
+    /// Watch::default()
+    ///     .bind_extension("rs", CommandList::from_argv(["cargo", "check"]))
+    ///     .bind_extension("toml", CommandList::from_argv(["cargo", "build"]))
+    ///     .run(CommandList::from_argv(["cargo", "build"]))
+    ///     .expect("cannot run the watch");
+    /// ```
+    pub fn bind_extension(mut self, ext: &str, commands: impl Into<CommandList>) -> Self {
+        self.bound_commands.push((
+            Binding::Extension(ext.trim_start_matches('.').into()),
+            commands.into(),
+        ));
+        self
+    }
+
+    /// Run `commands` instead of the main command when a `Cargo.toml` of the
+    /// workspace changes, like when adding a dependency or changing features.
+    ///
+    /// This works like [`bind_extension`](Self::bind_extension), keyed on the
+    /// `Cargo.toml` file name, and takes precedence over the commands bound to
+    /// the `toml` extension.
+    ///
+    /// ```rust,no_run
+    /// use xtask_watch::{CommandList, Watch};
+    ///
+    /// Watch::default()
+    ///     .on_manifest_change(CommandList::from_argv(["cargo", "build", "--workspace"]))
+    ///     .run(CommandList::from_argv(["cargo", "check"]))
+    ///     .expect("cannot run the watch");
+    /// ```
+    pub fn on_manifest_change(mut self, commands: impl Into<CommandList>) -> Self {
+        self.bound_commands
+            .push((Binding::FileName("Cargo.toml".into()), commands.into()));
+        self
+    }
+
+    /// Serve a live reload endpoint on `port` and tell the connected pages to
+    /// reload after every successful run.
+    ///
+    /// The pages connect to the server by including its script:
+    ///
+    /// ```html
+    /// <script src="http://localhost:35729/live-reload.js"></script>
+    /// ```
+    ///
+    /// This is meant for wasm-bindgen or trunk-style workflows where the
+    /// command rebuilds the assets served to the browser. Requires the
+    /// `live-reload` feature.
+    ///
+    /// ```rust,no_run
+    /// use xtask_watch::{CommandList, Watch};
+    ///
+    /// Watch::default()
+    ///     .with_live_reload(35729)
+    ///     .run(CommandList::from_argv(["wasm-pack", "build", "--target", "web"]))
+    ///     .expect("cannot run the watch");
+    /// ```
+    #[cfg(feature = "live-reload")]
+    pub fn with_live_reload(mut self, port: u16) -> Self {
+        self.live_reload_port = Some(port);
+        self
+    }
+
+    /// Set a callback deciding if the command is relaunched, given the paths
+    /// that changed since the last run.
+    ///
+    /// When the callback returns `false`, the current command keeps running and
+    /// the changes are ignored. By default, every change triggers a rerun.
+    pub fn should_rerun(
+        mut self,
+        callback: impl Fn(&[PathBuf]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.should_rerun = Some(Hook(Arc::new(callback)));
+        self
+    }
+
+    /// Set a callback delaying the rerun until it returns `true`, like when a
+    /// generator still holds a lock file.
+    ///
+    /// The callback is checked once changes have been accepted by
+    /// [`should_rerun`](Self::should_rerun), then every 100 milliseconds until
+    /// it returns `true`. The changes detected in the meantime are covered by
+    /// the delayed run.
+    pub fn ready_when(mut self, callback: impl Fn() -> bool + Send + Sync + 'static) -> Self {
+        self.ready_when = Some(Hook(Arc::new(callback)));
+        self
+    }
+
+    /// Set a callback invoked right before the running command is terminated
+    /// to be relaunched, like to flush a log.
+    ///
+    /// The callback is not invoked when the command already exited, nor when
+    /// the watch stops.
+    pub fn before_terminate(mut self, callback: impl Fn() + Send + Sync + 'static) -> Self {
+        self.before_terminate = Some(Hook(Arc::new(callback)));
+        self
+    }
+
+    /// Set a callback rendering the line logged at the end of every run, given
+    /// the exit status of the command, the duration of the run and the paths
+    /// that triggered it.
+    ///
+    /// The line replaces the default `Command succeeded in ...` or
+    /// `Command failed in ...` line.
+    pub fn summary_formatter(
+        mut self,
+        callback: impl Fn(ExitStatus, Duration, &[PathBuf]) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.summary_formatter = Some(Hook(Arc::new(callback)));
+        self
+    }
+
+    /// Check if the command is currently running.
+    ///
+    /// The state is shared between the clones of the watch, so a clone taken
+    /// before calling [`run`](Self::run) can be used to follow it from another
+    /// thread. The flag is set right before spawning the command and cleared
+    /// once the last command of the list exits, so it can be briefly `true`
+    /// while no process exists yet.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst) > 0
+    }
+
+    /// Get the time at which the last successful run ended, if any.
+    ///
+    /// Like [`is_running`](Self::is_running), the state is shared between the
+    /// clones of the watch. It is kept when the following runs fail, so the
+    /// time elapsed since the last good build can be shown. Only the time is
+    /// kept as the status of a successful run is always a success.
+    pub fn last_success(&self) -> Option<Instant> {
+        *self.last_success.lock().expect("not poisoned")
+    }
+
+    /// List the files the watch would react to, to check the configuration
+    /// before starting it.
+    ///
+    /// The watched paths are walked applying the same filters as the changes
+    /// detected at runtime: excluded, hidden and backup files are left out, as
+    /// well as the files not matching a watched glob. The list is sorted.
+    ///
+    /// An empty list is returned, logging the error, if the paths cannot be
+    /// resolved.
+    pub fn preview_watched_files(&self) -> Vec<PathBuf> {
+        let mut watch = self.clone();
+        match watch.resolve_paths(metadata()) {
+            Ok(()) => watch.watched_files(),
+            Err(err) => {
+                log::error!("{err:#}");
+                Vec::new()
+            }
+        }
+    }
+
+    /// Run the given `command`, monitor the watched paths and relaunch the
+    /// command when changes are detected.
+    ///
+    /// Workspace's `target` directory, `CARGO_TARGET_DIR` when set and
+    /// hidden paths are excluded by default.
+    /// Excluded and hidden directories are not watched at all. A directory that
+    /// cannot be watched is retried 3 times in the background, 100
+    /// milliseconds apart, before giving up on it.
+    ///
+    /// When the system drops events, like when its event queue overflows
+    /// during a bulk operation, the command is relaunched as the exact changes
+    /// are unknown.
+    pub fn run(self, commands: impl Into<CommandList>) -> Result<()> {
+        self.with_own_controls()
+            .run_with_channel(commands.into(), mpsc::channel())
+    }
+
+    /// Run the watch on a background thread, returning a handle to control it.
+    ///
+    /// The watch behaves like with [`run`](Self::run) until the session is
+    /// stopped, which also happens when the handle is dropped. Every session
+    /// has its own state, so sessions spawned from clones of a watch don't
+    /// affect each other: use [`WatchSession::status`] to follow it.
+    ///
+    /// ```rust,no_run
+    /// use std::process::Command;
+    /// use xtask_watch::Watch;
+    ///
+    /// let mut check = Command::new("cargo");
+    /// check.arg("check");
+    ///
+    /// let session = Watch::default().spawn_session(check);
+    /// session.trigger();
+    /// session.stop().expect("the watch failed");
+    /// ```
+    pub fn spawn_session(mut self, commands: impl Into<CommandList>) -> WatchSession {
+        let commands = commands.into();
+        let (tx, rx) = mpsc::channel();
+        self.running = Default::default();
+        self.last_success = Default::default();
+        let watch = self.with_own_controls();
+        let this = watch.clone();
+        let thread = {
+            let tx = tx.clone();
+            thread::spawn(move || this.run_with_channel(commands, (tx, rx)))
+        };
+
+        WatchSession {
+            watch,
+            tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// Give the watch its own stop and pause requests, fatal error and
+    /// watched paths, so a watch stopped before, or another watch cloned from
+    /// the same one, doesn't affect it.
+    ///
+    /// The running state and the last success stay shared with the clones to
+    /// follow the watch from another thread.
+    fn with_own_controls(mut self) -> Self {
+        self.stopped = Default::default();
+        self.paused = Default::default();
+        self.fatal_error = Default::default();
+        self.live = Default::default();
+        self.queued_watches = Default::default();
+        self.interrupts = Default::default();
+        self.reloads = Default::default();
+        self
+    }
+
+    fn run_with_channel(
+        self,
+        commands: CommandList,
+        channel: (mpsc::Sender<Trigger>, mpsc::Receiver<Trigger>),
+    ) -> Result<()> {
+        let teardown = self.teardown.clone();
+        let res = self.watch(commands, channel);
+
+        if let Some(mut teardown) = teardown {
+            log::info!("Running teardown command");
+            match teardown.status() {
+                Ok(status) if !status.success() => log::error!("Teardown command failed"),
+                Ok(_) => {}
+                Err(err) => log::error!("Could not execute teardown command: {err}"),
+            }
+        }
+
+        res
+    }
+
+    fn watch(
+        mut self,
+        commands: CommandList,
+        (tx, rx): (mpsc::Sender<Trigger>, mpsc::Receiver<Trigger>),
+    ) -> Result<()> {
+        let metadata = metadata();
+
+        self.wait_for_command()?;
+
+        if self.cargo_json {
+            commands.use_cargo_json();
+            for (_, commands) in &self.bound_commands {
+                commands.use_cargo_json();
+            }
+        }
+        self.configure_commands(&commands);
+        if let Some((commands, _)) = &self.quiet_command {
+            self.configure_commands(commands);
+        }
+        for (_, commands) in &self.bound_commands {
+            self.configure_commands(commands);
+        }
+
+        if let Some(config) = self.config.take() {
+            let config = config
+                .canonicalize()
+                .with_context(|| format!("can't find {}", config.display()))?;
+            let settings = read_config(&config)?;
+            self.apply_config(settings, config.parent().expect("file in a directory"))?;
+            self.config = Some(config);
+        }
+
+        self.resolve_paths(metadata)?;
+
+        if let Some(marker) = self.ready_marker.take() {
+            self.ready_marker = Some(canonicalize_missing(&marker)?);
+        }
+        if let Some(env_file) = self.env_file.take() {
+            self.env_file = Some(canonicalize_missing(&env_file)?);
+        }
+
+        #[cfg(feature = "live-reload")]
+        if let Some(port) = self.live_reload_port {
+            let server = live_reload::LiveReloadServer::bind(port, self.stopped.clone())
+                .with_context(|| format!("cannot start the live reload server on port {port}"))?;
+            log::info!(
+                "Live reload: add <script src=\"http://{}/live-reload.js\"></script> to the page",
+                server.addr()
+            );
+            self.live_reload = Some(server);
+        }
+
+        let (dir_tx, dir_rx) = mpsc::channel();
+
+        if let Some(fifo) = &self.trigger_fifo {
+            spawn_fifo_reader(fifo, tx.clone())?;
+        }
+        if !self.rerun_at.is_empty() {
+            spawn_scheduler(self.rerun_at.clone(), tx.clone(), self.stopped.clone());
+        }
+        if let Some(socket) = &self.control_socket {
+            self.spawn_control_socket(socket, tx.clone())?;
+        }
+
+        self.wake = Some(tx.clone());
+        let mut handler = WatchEventHandler::new(self.clone(), tx);
+        handler.new_dirs = Some(dir_tx.clone());
+        if self.watch_git_head {
+            handler.git_dir = Some(git_dir(metadata.workspace_root.as_std_path())?);
+        }
+        let git_dir = handler.git_dir.clone();
+        let quiet_until = handler.quiet_until.clone();
+
+        // shared with the config reloader
+        let handler = Arc::new(Mutex::new(handler));
+        if self.reload_on_signal {
+            match &self.config {
+                Some(config) => {
+                    self.config_reloader =
+                        Some(config_reloader(Arc::downgrade(&handler), config.clone()));
+                }
+                None => log::warn!("No config file to reload on signal"),
+            }
+        }
+        let watcher = Arc::new(Mutex::new(
+            notify::recommended_watcher(move |event| {
+                handler.lock().expect("not poisoned").handle_event(event);
+            })
+            .context("could not initialize watcher")?,
+        ));
+
+        let mut remaining = self.max_watches;
+        let mut failed = Vec::new();
+        for path in &self.watch_paths {
+            failed.extend(self.watch_directories(
+                &mut *watcher.lock().expect("not poisoned"),
+                path,
+                &mut remaining,
+            ));
+        }
+        for glob in &self.watch_globs {
+            failed.extend(self.watch_glob_directories(
+                &mut *watcher.lock().expect("not poisoned"),
+                &glob_parent(glob),
+                &mut remaining,
+            ));
+        }
+        self.spawn_directory_registrar(Arc::downgrade(&watcher), dir_rx, remaining, failed);
+
+        let config = self.config.clone().filter(|_| self.reload_on_config_change);
+        for dir in [&self.ready_marker, &self.env_file, &config]
+            .into_iter()
+            .filter_map(|x| x.as_deref().and_then(Path::parent))
+        {
+            // the file might be in an excluded directory
+            if let Err(err) = watcher
+                .lock()
+                .expect("not poisoned")
+                .watch(dir, RecursiveMode::NonRecursive)
+            {
+                log::error!("cannot watch {}: {err}", dir.display());
+            }
+        }
+
+        if let Some(git_dir) = git_dir {
+            let mut watcher = watcher.lock().expect("not poisoned");
+            for (path, mode) in [
+                (git_dir.clone(), RecursiveMode::NonRecursive),
+                (git_dir.join("refs"), RecursiveMode::Recursive),
+            ] {
+                if let Err(err) = watcher.watch(&path, mode) {
+                    log::error!("cannot watch {}: {err}", path.display());
+                }
+            }
+        }
+
+        if let Some(quiet) = self.startup_quiet {
+            // registering the paths might take a while on large trees
+            *quiet_until.lock().expect("not poisoned") = Some(Instant::now() + quiet);
+        }
+
+        {
+            let mut live = self.live.write().expect("not poisoned");
+            let mut paths = self.watch_paths.clone();
+            for path in self.queued_watches.lock().expect("not poisoned").drain(..) {
+                if !paths.contains(&path) {
+                    paths.push(path.clone());
+                }
+                let _ = dir_tx.send(path);
+            }
+            *live = Some(LiveWatch {
+                paths,
+                new_dirs: dir_tx,
+                watcher: Arc::downgrade(&watcher),
+            });
+        }
+
+        self.watch_loop(commands, rx);
+
+        // the threads serving the watch end with it
+        self.stopped.store(true, Ordering::SeqCst);
+        *self.live.write().expect("not poisoned") = None;
+        match self.fatal_error.lock().expect("not poisoned").take() {
+            Some(err) => Err(anyhow::anyhow!(err)),
+            None => Ok(()),
+        }
+    }
+
+    /// Resolve the watched and excluded paths the way the watch does before
+    /// registering them.
+    fn resolve_paths(&mut self, metadata: &cargo_metadata::Metadata) -> Result<()> {
+        let submodules = if self.include_submodules {
+            self.add_submodules(metadata.workspace_root.as_std_path())?
+        } else {
+            Vec::new()
+        };
+
+        // follows `CARGO_TARGET_DIR` and the cargo config
+        let target_dir = metadata.target_directory.clone().into_std_path_buf();
+        self.resolve_exclude_paths(std::slice::from_ref(&target_dir))?;
+
+        if let Some(list) = self.watch_from.take() {
+            self.watch_paths.extend(read_path_list(&list)?);
+        }
+
+        if self.dep_info {
+            let sources =
+                dep_info_sources(&target_dir, metadata.workspace_root.as_std_path(), false);
+            if sources.is_empty() {
+                log::warn!("No dep-info file found, build the workspace first");
+            }
+            self.watch_paths.extend(sources);
+        }
+
+        self.resolve_watch_globs()?;
+
+        if self.watch_paths.is_empty() && self.watch_globs.is_empty() {
+            self.watch_paths
+                .push(metadata.workspace_root.clone().into_std_path_buf());
+        }
+        self.watch_paths.extend(submodules);
+
+        if self.dep_info_external {
+            self.watch_paths.extend(dep_info_sources(
+                &target_dir,
+                metadata.workspace_root.as_std_path(),
+                true,
+            ));
+        }
+
+        self.resolve_watch_paths()?;
+
+        for dir in &mut self.inbox {
+            *dir = dir
+                .canonicalize()
+                .with_context(|| format!("can't find {}", dir.display()))?;
+            if !self.watch_paths.iter().any(|x| dir.starts_with(x)) {
+                self.watch_paths.push(dir.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run `build` to completion then start `serve`, a long-lived command
+    /// (like a development server) that will be terminated and relaunched
+    /// after the next successful build when changes are detected.
+    ///
+    /// `serve` is not started if `build` fails: the previous `serve` process
+    /// has already been terminated at that point.
+    pub fn build_then_serve(self, build: Command, serve: Command) -> Result<()> {
+        self.run([build, serve])
+    }
+
+    fn watch_loop(&self, commands: CommandList, rx: mpsc::Receiver<Trigger>) {
+        let mut current_child = SharedChild::new()
+            .kill_escalation(self.kill_escalation.clone())
+            .measure_shutdown(self.measure_shutdown);
+        let mut consecutive_failures = 0;
+        let mut trigger = Trigger::new(TriggerReason::Initial, self.changes_since_last_run());
+        if !trigger.changes.is_empty() {
+            log::info!("{}", self.changes_summary(&trigger.changes));
+        }
+        let mut pause = Pause {
+            requested: self.paused.clone(),
+            keep_pending: self.run_on_resume,
+            ..Pause::default()
+        };
+        if self.pause_when_detached {
+            pause.attached = Some(terminal_attached_check());
+        }
+        if self.skip_on_battery {
+            if !cfg!(any(target_os = "linux", target_os = "macos")) {
+                log::warn!("The power source cannot be checked on this platform");
+            }
+            pause.on_battery = Some(on_battery);
+        }
+        let quiet_tx = self.quiet_command.clone().map(|(commands, quiet)| {
+            spawn_quiet_runner(commands, quiet, self.kill_escalation.clone())
+        });
+        let mut run = 0;
+        let _pause_signal = self.pause_on_signal.then(|| {
+            #[cfg(not(unix))]
+            log::warn!("Pausing with a signal is only supported on Unix");
+            pause.signals = Some(WatchSignal::Pause.received());
+            WatchSignal::Pause.handle()
+        });
+        // an interrupt received before, by another watch, doesn't stop it
+        self.interrupts
+            .store(WatchSignal::Interrupt.received(), Ordering::SeqCst);
+        let _interrupt = self
+            .teardown
+            .is_some()
+            .then(|| WatchSignal::Interrupt.handle());
+        let _reload_signal = self.config_reloader.is_some().then(|| {
+            #[cfg(not(unix))]
+            log::warn!("Reloading the config file on a signal is only supported on Unix");
+            self.reloads
+                .store(WatchSignal::Reload.received(), Ordering::SeqCst);
+            WatchSignal::Reload.handle()
+        });
+        // `run_now` wins over `skip_initial`, as do the changes since the
+        // last run
+        if self.skip_initial && !self.run_now && trigger.changes.is_empty() {
+            log::info!("Waiting for changes before running the command");
+            match self.wait_for_changes(&rx, &mut pause, || false) {
+                Some(res) => trigger = res,
+                None => return,
+            }
+            if !trigger.changes.is_empty() {
+                log::info!("{}", self.changes_summary(&trigger.changes));
+            }
+        }
+        loop {
+            run += 1;
+            if self.show_reason {
+                run_log!(
+                    info,
+                    run,
+                    "Re-running command {}",
+                    self.trigger_tag(&trigger)
+                );
+            } else {
+                run_log!(info, run, "Re-running command");
+            }
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let current_run = self.spawn_commands(
+                run,
+                self.commands_for(&commands, &trigger.changes),
+                current_child.clone(),
+                &trigger.changes,
+                cancelled.clone(),
+            );
+
+            let crashed = || current_run.is_finished() && current_child.has_crashed();
+            let res = self.wait_for_changes(&rx, &mut pause, crashed);
+            let Some(res) = res else {
+                if self.detach_on_exit {
+                    log::info!("Leaving the command running");
+                } else {
+                    cancelled.store(true, Ordering::SeqCst);
+                    current_child.terminate();
+                }
+                break;
+            };
+            trigger = res;
+            if let Some(quiet_tx) = &quiet_tx {
+                let _ = quiet_tx.send(());
+            }
+
+            if !trigger.changes.is_empty() {
+                log::info!("{}", self.changes_summary(&trigger.changes));
+                if self.show_changes {
+                    for line in self.changes_by_kind(&trigger.changes) {
+                        log::info!("{line}");
+                    }
+                }
+            }
+            // the status is only known if the run ended by itself
+            let stopping = Instant::now();
+            let status = match self.on_change_while_running {
+                ChangeWhileRunning::Replace => {
+                    let finished = current_run.is_finished();
+                    if let Some(before_terminate) = self.before_terminate.as_ref() {
+                        if !finished {
+                            (before_terminate.0)();
+                        }
+                    }
+                    // no command is spawned once cancelled, so the runs never
+                    // overlap
+                    cancelled.store(true, Ordering::SeqCst);
+                    current_child.terminate();
+                    let status = current_run.join().ok();
+                    status.filter(|_| finished)
+                }
+                ChangeWhileRunning::Queue => {
+                    if !current_run.is_finished() {
+                        run_log!(info, run, "Waiting for the run to finish");
+                    }
+                    let status = current_run.join().ok();
+                    // every change that occurred in the meantime is covered
+                    // by the next run
+                    rx.try_iter().for_each(drop);
+                    status
+                }
+            };
+            if self.profile {
+                run_log!(
+                    info,
+                    run,
+                    "Profile: previous run stopped in {:.1?}",
+                    stopping.elapsed()
+                );
+            }
+
+            match status {
+                Some(status) if status.success() => consecutive_failures = 0,
+                Some(_) => consecutive_failures += 1,
+                None => {}
+            }
+
+            if self.backoff && consecutive_failures > 0 {
+                let delay = backoff_delay(consecutive_failures, self.backoff_max);
+                run_log!(
+                    info,
+                    run,
+                    "Command failed {consecutive_failures} time(s) in a row, \
+                    waiting {delay:?} before re-running"
+                );
+                let deadline = Instant::now() + delay;
+                while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                    if self.is_stopping() {
+                        current_child.terminate();
+                        return;
+                    }
+                    match rx.recv_timeout(timeout.min(Duration::from_millis(100))) {
+                        Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Apply the options of the watch to `commands`.
+    fn configure_commands(&self, commands: &CommandList) {
+        if let Some(nice) = self.nice {
+            commands.set_niceness(nice);
+        }
+        if let Some(umask) = self.umask {
+            commands.set_umask(umask);
+        }
+        if let Some(triple) = &self.cargo_target {
+            commands.default_env("CARGO_BUILD_TARGET", triple);
+        }
+        if self.clean_env {
+            commands.clear_env();
+        }
+    }
+
+    /// Replace the settings added by the previous config file by the ones of
+    /// `config`, its relative paths being relative to `dir`. Returns the
+    /// paths that weren't watched yet.
+    fn apply_config(&mut self, config: ConfigFile, dir: &Path) -> Result<Vec<PathBuf>> {
+        let exclude_paths = config
+            .exclude_paths
+            .into_iter()
+            .map(|path| {
+                if is_bare_name(&path) {
+                    return Ok(path);
+                }
+                let path = dir.join(path);
+                path.canonicalize()
+                    .with_context(|| format!("can't find {}", path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let watch_paths = config
+            .watch_paths
+            .into_iter()
+            .map(|path| resolve_watch_path(&dir.join(path)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let previous = std::mem::take(&mut self.applied_config);
+        self.exclude_paths
+            .retain(|x| !previous.exclude_paths.contains(x));
+        self.watch_paths
+            .retain(|x| !previous.watch_paths.contains(x));
+        let new_paths = watch_paths
+            .iter()
+            .filter(|x| !self.watch_paths.contains(x))
+            .cloned()
+            .collect();
+        self.exclude_paths.extend(exclude_paths.iter().cloned());
+        self.watch_paths.extend(watch_paths.iter().cloned());
+        if let Some(live) = &mut *self.live.write().expect("not poisoned") {
+            live.paths.retain(|x| !previous.watch_paths.contains(x));
+            for path in &watch_paths {
+                if !live.paths.contains(path) {
+                    live.paths.push(path.clone());
+                }
+            }
+        }
+
+        let default_debounce = previous.default_debounce.unwrap_or(self.debounce);
+        self.debounce = config.debounce.unwrap_or(default_debounce);
+        self.applied_config = AppliedConfig {
+            watch_paths,
+            exclude_paths,
+            default_debounce: Some(default_debounce),
+        };
+        self.exclude_index = Some(Arc::new(ExcludeIndex::new(self)));
+
+        Ok(new_paths)
+    }
+
+    /// Exclude the `.git` of the submodules of the repository in `repo`,
+    /// returning the submodules to watch.
+    fn add_submodules(&mut self, repo: &Path) -> Result<Vec<PathBuf>> {
+        let submodules = submodule_paths(repo)?;
+        for submodule in &submodules {
+            log::trace!("Found submodule {}", submodule.display());
+            let git = submodule.join(".git");
+            if git.exists() {
+                self.exclude_paths.push(git);
+            }
+        }
+        Ok(submodules)
+    }
+
+    /// Canonicalize the excluded paths and exclude the target directories
+    /// unless they're included, and the files written by the watch.
+    fn resolve_exclude_paths(&mut self, target_dirs: &[PathBuf]) -> Result<()> {
+        let mut resolved = Vec::new();
+        for path in std::mem::take(&mut self.exclude_paths) {
+            if is_bare_name(&path) {
+                resolved.push(path);
+                continue;
+            }
+            match path
+                .canonicalize()
+                .with_context(|| format!("can't find {}", path.display()))
+            {
+                Ok(path) => resolved.push(path),
+                Err(err) if self.lenient_paths => log::warn!("Skipping excluded path: {err:#}"),
+                Err(err) => return Err(err),
+            }
+        }
+        self.exclude_paths = resolved;
+
+        for (root, _) in &mut self.root_exclude_paths {
+            *root = root
+                .canonicalize()
+                .with_context(|| format!("can't find {}", root.display()))?;
+        }
+
+        if !self.include_target {
+            let targets = if self.exclude_targets.is_empty() {
+                target_dirs
+            } else {
+                &self.exclude_targets
+            };
+            let targets: Vec<_> = targets
+                .iter()
+                .chain(&self.extra_target_dirs)
+                .map(|x| canonicalize_missing(x))
+                .collect();
+            for target in targets {
+                // the target directory might not be created yet
+                self.exclude_paths.push(target?);
+            }
+        }
+        // written after every successful run
+        if let Some(path) = &self.since_file {
+            self.exclude_paths.push(canonicalize_missing(path)?);
+        }
+        if let Some(dir) = &self.tee {
+            fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+            self.exclude_paths.push(dir.canonicalize()?);
+        }
+
+        self.exclude_index = Some(Arc::new(ExcludeIndex::new(self)));
+
+        Ok(())
+    }
+
+    /// Wait for changes accepted by [`should_rerun`](Self::should_rerun),
+    /// returning `None` when the watch is over.
+    fn wait_for_changes(
+        &self,
+        rx: &mpsc::Receiver<Trigger>,
+        pause: &mut Pause,
+        crashed: impl Fn() -> bool,
+    ) -> Option<Trigger> {
+        loop {
+            let res = if self.pause_on_signal
+                || self.config_reloader.is_some()
+                || self.teardown.is_some()
+                || self.restart_on_crash
+                || pause.attached.is_some()
+                || pause.on_battery.is_some()
+                || pause.paused
+            {
+                match rx.recv_timeout(Duration::from_millis(100)) {
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+                    res => res.ok(),
+                }
+            } else {
+                Some(rx.recv().ok()?)
+            };
+
+            if self.is_stopping() {
+                log::info!("Stopping the watch");
+                return None;
+            }
+            self.reload_config_on_signal();
+
+            if self.restart_on_crash && !pause.paused && crashed() {
+                log::info!("Command crashed, restarting");
+                return Some(Trigger::new(TriggerReason::Crash, Vec::new()));
+            }
+
+            let mut res = res;
+            if pause.update() && !pause.pending.is_empty() {
+                // checked like the other changes
+                let mut pending = Trigger::new(TriggerReason::File, pause.take_pending());
+                pending.changes.extend(res.into_iter().flatten());
+                res = Some(pending);
+            }
+
+            let Some(mut trigger) = res else {
+                continue;
+            };
+            // the batches received together are tagged with the first reason
+            trigger.changes.extend(rx.try_iter().flatten());
+            if pause.paused {
+                log::trace!("Changes ignored while paused");
+                pause.defer(trigger);
+            } else if self.is_rerun_accepted(&trigger.changes) {
+                self.wait_until_settled(rx, &mut trigger.changes)?;
+                self.wait_until_ready(rx, &mut trigger.changes)?;
+                return Some(trigger);
+            } else {
+                log::info!("Changes ignored: rerun rejected by `should_rerun`");
+            }
+        }
+    }
+
+    /// Read the variables of the environment file, if any.
+    ///
+    /// A missing file has no variables. When the file can't be read or is
+    /// malformed, the error is logged and the last valid variables are
+    /// returned.
+    fn load_env_file(&self) -> Vec<(String, String)> {
+        let Some(path) = &self.env_file else {
+            return Vec::new();
+        };
+        let res = match fs::read_to_string(path) {
+            Ok(content) => parse_env_file(&content)
+                .with_context(|| format!("invalid environment file {}", path.display())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => {
+                Err(err).with_context(|| format!("cannot read environment file {}", path.display()))
+            }
+        };
+        let mut last = self.env_file_vars.lock().expect("not poisoned");
+        match res {
+            Ok(envs) => *last = envs,
+            Err(err) => log::error!("{err:#}, using the previous values"),
+        }
+        last.clone()
+    }
+
+    /// Get the commands to run for `changes`: the ones bound to their names
+    /// or extensions and the main `commands` when needed.
+    fn commands_for(&self, commands: &CommandList, changes: &[Change]) -> Vec<CommandList> {
+        if self.bound_commands.is_empty() || changes.is_empty() {
+            return vec![commands.clone()];
+        }
+
+        let mut bound = vec![false; self.bound_commands.len()];
+        let mut unbound = false;
+        for change in changes {
+            // the bindings to a file name win over the ones to its extension
+            let by_name = self.bound_commands.iter().any(|(binding, _)| {
+                matches!(binding, Binding::FileName(_)) && binding.matches(&change.path)
+            });
+            let mut found = false;
+            for (i, (binding, _)) in self.bound_commands.iter().enumerate() {
+                if binding.matches(&change.path)
+                    && (!by_name || matches!(binding, Binding::FileName(_)))
+                {
+                    bound[i] = true;
+                    found = true;
+                }
+            }
+            unbound |= !found;
+        }
+
+        let mut lists: Vec<CommandList> = unbound.then(|| commands.clone()).into_iter().collect();
+        lists.extend(
+            self.bound_commands
+                .iter()
+                .zip(bound)
+                .filter(|(_, bound)| *bound)
+                .map(|((_, commands), _)| commands.clone()),
+        );
+        lists
+    }
+
+    fn spawn_commands(
+        &self,
+        run: u64,
+        lists: Vec<CommandList>,
+        mut current_child: SharedChild,
+        changes: &[Change],
+        cancelled: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<ExitStatus> {
+        let changes: &[PathBuf] = &changes.iter().map(|x| x.path.clone()).collect::<Vec<_>>();
+        let bell = self.bell;
+        let cargo_json = self.cargo_json;
+        let log_file = self.log_file.clone().map(|path| (path, changes.to_vec()));
+        let since_file = self.since_file.clone();
+        let summary_formatter = self
+            .summary_formatter
+            .clone()
+            .map(|formatter| (formatter, changes.to_vec()));
+        let options = SpawnOptions {
+            dir: self.package_dir.then(|| {
+                changed_package(metadata(), changes).map_or_else(
+                    || PathBuf::from("."),
+                    |package| {
+                        package
+                            .manifest_path
+                            .parent()
+                            .expect("manifest in a directory")
+                            .into()
+                    },
+                )
+            }),
+            show_command: self.show_command,
+            pty: self.pty,
+            stdin: self.child_stdin,
+            template: TemplateValues {
+                files: changes.to_vec(),
+                package: changed_package(metadata(), changes).map(|x| x.name.clone()),
+                package_flags: package_flags(metadata(), changes, self.no_changed_package),
+            },
+            on_unresolved_token: self.on_unresolved_token,
+            tee: self.tee.clone(),
+            prefix_output: self.prefix_output,
+            envs: self.load_env_file(),
+            cancelled,
+            capture: None,
+        };
+        let running = self.running.clone();
+        running.fetch_add(1, Ordering::SeqCst);
+        let last_success = self.last_success.clone();
+        let profile = self.profile;
+        let abort = (self.on_missing_program == MissingProgram::Abort).then(|| {
+            (
+                self.fatal_error.clone(),
+                self.stopped.clone(),
+                self.wake.clone(),
+            )
+        });
+        #[cfg(feature = "live-reload")]
+        let live_reload = self.live_reload.clone();
+        thread::spawn(move || {
+            let start = Instant::now();
+            let start_time = SystemTime::now();
+            let mut status = ExitStatus::default();
+            let mut spawning = start;
+            let mut callback = |res: io::Result<Child>, spawned: &Spawned| match res {
+                Err(err) => {
+                    run_log!(error, run, "Could not execute command: {err}");
+                    let missing = err
+                        .get_ref()
+                        .and_then(|x| x.downcast_ref::<ProgramNotFound>());
+                    if let (Some(missing), Some((fatal_error, stopped, wake))) = (missing, &abort) {
+                        *fatal_error.lock().expect("not poisoned") = Some(missing.to_string());
+                        stopped.store(true, Ordering::SeqCst);
+                        // the watch loop might be waiting for changes
+                        if let Some(wake) = wake {
+                            let _ = wake.send(Trigger::manual());
+                        }
+                    }
+                    false
+                }
+                Ok(mut child) => {
+                    run_log!(trace, run, "new child: {}", child.id());
+                    if profile {
+                        run_log!(
+                            info,
+                            run,
+                            "Profile: command spawned in {:.1?}",
+                            spawning.elapsed()
+                        );
+                    }
+                    let running = Instant::now();
+                    let summary = child.stdout.take().filter(|_| cargo_json).map(|stdout| {
+                        let (out, err) = (spawned.stdout.clone(), spawned.stderr.clone());
+                        thread::spawn(move || {
+                            CargoSummary::from_stream(io::BufReader::new(stdout), &out, &err)
+                        })
+                    });
+                    current_child.replace(child);
+                    // spawned right before the run was cancelled, the
+                    // termination might have missed it
+                    if options.cancelled.load(Ordering::SeqCst) {
+                        current_child.terminate();
+                    }
+                    let child_status = current_child.wait(spawned.timeout);
+                    // the run fails if any of its commands failed
+                    if status.success() {
+                        status = child_status;
+                    }
+                    if let Some(Ok(summary)) = summary.map(|x| x.join()) {
+                        run_log!(info, run, "{summary}");
+                    }
+                    if profile {
+                        run_log!(
+                            info,
+                            run,
+                            "Profile: command ran in {:.1?}",
+                            running.elapsed()
+                        );
+                    }
+                    spawning = Instant::now();
+                    child_status.success()
+                }
+            };
+            for mut commands in lists {
+                commands.spawn_in(&options, &mut callback);
+            }
+            let elapsed = start.elapsed();
+            let summary = summary_formatter
+                .map(|(formatter, changes)| (formatter.0)(status, elapsed, &changes));
+            if status.success() {
+                match summary {
+                    Some(summary) => run_log!(info, run, "{summary}"),
+                    None => run_log!(info, run, "Command succeeded in {elapsed:.1?}"),
+                }
+                *last_success.lock().expect("not poisoned") = Some(Instant::now());
+                #[cfg(feature = "live-reload")]
+                if let Some(server) = &live_reload {
+                    server.reload();
+                }
+                if let Some(path) = since_file {
+                    if let Err(err) = fs::write(&path, format_timestamp(start_time) + "\n") {
+                        log::error!("cannot write to {}: {err}", path.display());
+                    }
+                }
+            } else {
+                match (summary, status.code()) {
+                    (Some(summary), _) => run_log!(error, run, "{summary}"),
+                    (None, Some(code)) => run_log!(
+                        error,
+                        run,
+                        "Command failed in {elapsed:.1?} (exit code: {code})"
+                    ),
+                    (None, None) => run_log!(error, run, "Command failed in {elapsed:.1?}"),
+                }
+                if let Some(time) = *last_success.lock().expect("not poisoned") {
+                    run_log!(
+                        warn,
+                        run,
+                        "Last successful run ended {}s ago",
+                        time.elapsed().as_secs()
+                    );
+                }
+            }
+            running.fetch_sub(1, Ordering::SeqCst);
+            if bell && io::stderr().is_terminal() {
+                let _ = ring_bell(&mut io::stderr(), status);
+            }
+            if let Some((path, changes)) = log_file {
+                let line = run_log_line(SystemTime::now(), status, &changes);
+                let res = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .and_then(|mut file| file.write_all(line.as_bytes()));
+                if let Err(err) = res {
+                    log::error!("cannot write to {}: {err}", path.display());
+                }
+            }
+            status
+        })
+    }
+
+    /// Find the watched files modified since the run recorded in the
+    /// [`since_file`](Self::since_file).
+    fn changes_since_last_run(&self) -> Vec<Change> {
+        let Some(path) = &self.since_file else {
+            return Vec::new();
+        };
+        let since = match fs::read_to_string(path) {
+            Ok(content) => match parse_timestamp(&content) {
+                Some(since) => since,
+                None => {
+                    log::warn!("invalid time in {}", path.display());
+                    return Vec::new();
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Vec::new(),
+            Err(err) => {
+                log::error!("cannot read {}: {err}", path.display());
+                return Vec::new();
+            }
+        };
+
+        self.watched_files()
+            .into_iter()
+            .filter(|path| {
+                fs::metadata(path)
+                    .and_then(|x| x.modified())
+                    .is_ok_and(|modified| modified > since)
+            })
+            .map(|path| Change::new(path, ChangeKind::Modified))
+            .collect()
+    }
+
+    /// List the files under the resolved watched paths that pass the filters
+    /// applied to the events.
+    fn watched_files(&self) -> Vec<PathBuf> {
+        let roots = self
+            .watch_paths
+            .iter()
+            .cloned()
+            .chain(self.watch_globs.iter().map(|x| glob_parent(x)));
+        let mut files = Vec::new();
+        for root in roots {
+            if !root.is_dir() {
+                files.push(root);
+                continue;
+            }
+            for dir in self.watched_directories(&root, None) {
+                let Ok(entries) = fs::read_dir(&dir) else {
+                    continue;
+                };
+                files.extend(
+                    entries
+                        .flatten()
+                        .filter(|x| x.file_type().is_ok_and(|x| !x.is_dir()))
+                        .map(|x| x.path()),
+                );
+            }
+        }
+
+        files.retain(|path| {
+            self.is_watched_path(path)
+                && !self.is_excluded_path(path)
+                && !self.is_hidden_path(path)
+                && !self.is_backup_file(path)
+        });
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    /// List `root` and its sub-directories, breadth-first, skipping the
+    /// excluded and hidden ones, up to `max` directories.
+    fn watched_directories(&self, root: &Path, max: Option<usize>) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        let root_depth = self.depth_below_watch_paths(root);
+        if self
+            .max_depth
+            .is_some_and(|max_depth| root_depth > max_depth)
+        {
+            return dirs;
+        }
+        let mut queue = VecDeque::from([(root.to_path_buf(), root_depth)]);
+
+        while let Some((dir, depth)) = queue.pop_front() {
+            if max.is_some_and(|max| dirs.len() >= max) {
+                log::warn!(
+                    "Watch limit reached: {} directories are not watched",
+                    queue.len() + 1
+                );
+                break;
+            }
+
+            match fs::read_dir(&dir) {
+                Ok(entries) => {
+                    for entry in entries.flatten() {
+                        let path = entry.path();
+                        if entry.file_type().is_ok_and(|x| x.is_dir())
+                            && !self.is_excluded_path(&path)
+                            && !self.is_hidden_path(&path)
+                            && self.max_depth.map_or(true, |max_depth| depth < max_depth)
+                        {
+                            queue.push_back((path, depth + 1));
+                        }
+                    }
+                }
+                Err(err) => log::error!("cannot read {}: {err}", dir.display()),
+            }
+            dirs.push(dir);
+        }
+
+        dirs
+    }
+
+    /// Number of directories between `path` and the closest watched path
+    /// containing it.
+    fn depth_below_watch_paths(&self, path: &Path) -> usize {
+        self.watch_paths
+            .iter()
+            .filter_map(|x| path.strip_prefix(x).ok())
+            .map(|x| x.components().count())
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Watch `path` and its sub-directories individually, without recursion,
+    /// so the excluded directories don't produce any event.
+    ///
+    /// Returns the directories that could not be watched, to retry later.
+    fn watch_directories(
+        &self,
+        watcher: &mut impl Watcher,
+        path: &Path,
+        remaining: &mut Option<usize>,
+    ) -> Vec<PathBuf> {
+        // a file is watched through its directory so it's still seen when
+        // replaced by an atomic save, the events are filtered to the file
+        let dirs = if path.is_dir() {
+            self.watched_directories(path, *remaining)
+        } else {
+            vec![path.parent().unwrap_or(path).to_path_buf()]
+        };
+        *remaining = remaining.map(|x| x.saturating_sub(dirs.len()));
+
+        dirs.into_iter()
+            .filter(|path| !watch_directory(watcher, path, true))
+            .collect()
+    }
+
+    /// Watch `dir` if it matches one of the globs, or its sub-directories that
+    /// can match if it's one of their parents.
+    ///
+    /// Returns the directories that could not be watched, to retry later.
+    fn watch_glob_directories(
+        &self,
+        watcher: &mut impl Watcher,
+        dir: &Path,
+        remaining: &mut Option<usize>,
+    ) -> Vec<PathBuf> {
+        if self.is_excluded_path(dir) {
+            return Vec::new();
+        }
+
+        let matches: Vec<GlobMatch> = self
+            .watch_globs
+            .iter()
+            .filter_map(|glob| glob_match(glob, dir))
+            .collect();
+        let mut failed = Vec::new();
+        if matches.contains(&GlobMatch::Full) {
+            log::trace!("{} matches a watched glob", dir.display());
+            failed = self.watch_directories(watcher, dir, remaining);
+        } else if !matches.is_empty() && *remaining != Some(0) {
+            // watched to see the matching directories created later
+            if !watch_directory(watcher, dir, true) {
+                failed.push(dir.to_path_buf());
+            }
+            *remaining = remaining.map(|x| x.saturating_sub(1));
+
+            // they might also have been created before the watch started
+            let Ok(entries) = fs::read_dir(dir) else {
+                return failed;
+            };
+            for entry in entries.flatten() {
+                if entry.file_type().is_ok_and(|x| x.is_dir()) {
+                    failed.extend(self.watch_glob_directories(watcher, &entry.path(), remaining));
+                }
+            }
+        }
+        failed
+    }
+
+    /// Register the directories created after the start of the watch, and
+    /// retry the `failed` ones and the new ones that cannot be watched yet.
+    fn spawn_directory_registrar(
+        &self,
+        watcher: Weak<Mutex<notify::RecommendedWatcher>>,
+        rx: mpsc::Receiver<PathBuf>,
+        mut remaining: Option<usize>,
+        failed: Vec<PathBuf>,
+    ) {
+        let watch = self.clone();
+        thread::spawn(move || {
+            let mut retries = WatchRetries::default();
+            retries.add(failed);
+            loop {
+                let received = match retries.next_due() {
+                    Some(due) => rx.recv_timeout(due.saturating_duration_since(Instant::now())),
+                    None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+                let dir = match received {
+                    Ok(dir) => Some(dir),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                };
+                let Some(watcher) = watcher.upgrade() else {
+                    break;
+                };
+                let watcher = &mut *watcher.lock().expect("not poisoned");
+                match dir {
+                    Some(dir) if watch.is_watched_path(&dir) => {
+                        retries.add(watch.watch_directories(watcher, &dir, &mut remaining));
+                    }
+                    Some(dir) => {
+                        retries.add(watch.watch_glob_directories(watcher, &dir, &mut remaining));
+                    }
+                    None => retries.retry_due(watcher),
+                }
+            }
+        });
+    }
+
+    /// Listen on the Unix domain socket at `path` and answer the commands
+    /// received.
+    #[cfg(unix)]
+    fn spawn_control_socket(&self, path: &Path, tx: mpsc::Sender<Trigger>) -> Result<()> {
+        use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            anyhow::ensure!(
+                metadata.file_type().is_socket(),
+                "{} is not a socket",
+                path.display()
+            );
+            // left by a previous watch
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("cannot listen on {}", path.display()))?;
+
+        let watch = self.clone();
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::error!("cannot accept on {}: {err}", path.display());
+                        continue;
+                    }
+                };
+                let Ok(reader) = stream.try_clone() else {
+                    continue;
+                };
+
+                for line in io::BufRead::lines(io::BufReader::new(reader)).map_while(Result::ok) {
+                    log::trace!("control command received: {line}");
+                    if line.trim_start().starts_with('{') {
+                        let reply = match parse_changed_message(&line) {
+                            Ok(paths) => {
+                                let root = metadata().workspace_root.as_std_path();
+                                let changes = paths
+                                    .into_iter()
+                                    .map(|x| Change::new(root.join(x), ChangeKind::Modified))
+                                    .collect();
+                                let _ = tx.send(Trigger::new(TriggerReason::File, changes));
+                                "ok".to_string()
+                            }
+                            Err(err) => format!("invalid message: {err:#}"),
+                        };
+                        if writeln!(stream, "{reply}").is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    let reply = match line.trim() {
+                        "ping" => "pong",
+                        "rerun" => {
+                            let _ = tx.send(Trigger::manual());
+                            "ok"
+                        }
+                        "status" if watch.is_running() => "running",
+                        "status" => "idle",
+                        "stop" => {
+                            watch.stopped.store(true, Ordering::SeqCst);
+                            // wake up the watch loop
+                            let _ = tx.send(Trigger::manual());
+                            "ok"
+                        }
+                        _ => "unknown command",
+                    };
+                    if writeln!(stream, "{reply}").is_err() {
+                        break;
+                    }
+                }
+
+                if watch.stopped.load(Ordering::SeqCst) {
+                    let _ = fs::remove_file(&path);
+                    break;
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn spawn_control_socket(&self, _path: &Path, _tx: mpsc::Sender<Trigger>) -> Result<()> {
+        anyhow::bail!("control sockets are only supported on Unix")
+    }
+
+    fn wait_for_command(&self) -> Result<()> {
+        let Some((commands, retries)) = &self.wait_for else {
+            return Ok(());
+        };
+        let mut commands = commands.clone();
+
+        for attempt in 0..=*retries {
+            let status = commands.status().context("could not run command")?;
+            if status.success() {
+                return Ok(());
+            }
+            log::warn!(
+                "Command failed (attempt {} of {})",
+                attempt + 1,
+                retries + 1
+            );
+        }
+
+        anyhow::bail!("command still failing after {retries} retries")
+    }
+
+    /// Wait for the changes to stop for the [`settle`](Self::settle) duration,
+    /// gathering them. Returns `None` when the watch is over.
+    fn wait_until_settled(
+        &self,
+        rx: &mpsc::Receiver<Trigger>,
+        changes: &mut Vec<Change>,
+    ) -> Option<()> {
+        let Some(settle) = self.settle else {
+            return Some(());
+        };
+        loop {
+            match rx.recv_timeout(settle) {
+                Ok(_) if self.is_stopping() => return None,
+                Ok(more) => {
+                    log::trace!("Changes still arriving, waiting for them to settle");
+                    changes.extend(more);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => return Some(()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    /// Wait for the [`ready_when`](Self::ready_when) callback to accept the
+    /// rerun, gathering the changes detected in the meantime. Returns `None`
+    /// when the watch is over.
+    fn wait_until_ready(
+        &self,
+        rx: &mpsc::Receiver<Trigger>,
+        changes: &mut Vec<Change>,
+    ) -> Option<()> {
+        let Some(ready_when) = &self.ready_when else {
+            return Some(());
+        };
+        while !(ready_when.0)() {
+            if self.is_stopping() {
+                return None;
+            }
+            log::trace!("Waiting for the rerun to be ready");
+            match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(_) if self.is_stopping() => return None,
+                Ok(more) => changes.extend(more),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+        Some(())
+    }
+
+    /// Reload the config file if `SIGUSR2` has been received since the last
+    /// reload.
+    fn reload_config_on_signal(&self) {
+        if let Some(reload) = &self.config_reloader {
+            let received = WatchSignal::Reload.received();
+            if self.reloads.swap(received, Ordering::SeqCst) != received {
+                (reload.0)();
+            }
+        }
+    }
+
+    /// The watch has been asked to stop by the control socket, a session, or
+    /// by `Ctrl-C` when a teardown command is waiting.
+    fn is_stopping(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+            || self.teardown.is_some()
+                && WatchSignal::Interrupt.received() != self.interrupts.load(Ordering::SeqCst)
+    }
+
+    fn is_rerun_accepted(&self, changes: &[Change]) -> bool {
+        self.should_rerun.as_ref().map_or(true, |should_rerun| {
+            (should_rerun.0)(&changes.iter().map(|x| x.path.clone()).collect::<Vec<_>>())
+        })
+    }
+
+    fn is_event_accepted(&self, path: &Path, kind: &notify::EventKind) -> bool {
+        let event = TriggerEvent::from(kind);
+        if self.inbox.iter().any(|x| path.starts_with(x)) {
+            return event == TriggerEvent::Create
+                || *kind
+                    == notify::EventKind::Modify(notify::event::ModifyKind::Name(
+                        notify::event::RenameMode::To,
+                    ));
+        }
+        if event == TriggerEvent::Chmod && self.on_chmod {
+            return true;
+        }
+        if self.events.is_empty() {
+            event != TriggerEvent::Chmod
+        } else {
+            self.events.contains(&event)
+        }
+    }
+
+    fn is_watched_path(&self, path: &Path) -> bool {
+        let watched = match &*self.live.read().expect("not poisoned") {
+            // removing every watched path doesn't watch everything
+            Some(live) => live.paths.iter().any(|x| path.starts_with(x)),
+            None => {
+                (self.watch_paths.is_empty() && self.watch_globs.is_empty())
+                    || self.watch_paths.iter().any(|x| path.starts_with(x))
+            }
+        };
+        watched || self.glob_root(path).is_some()
+    }
+
+    /// Get the ancestor of `path` matching one of the globs.
+    fn glob_root<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        path.ancestors().find(|x| {
+            self.watch_globs
+                .iter()
+                .any(|glob| glob_match(glob, x) == Some(GlobMatch::Full))
+        })
+    }
+
+    /// Canonicalize the watched paths, remembering the paths as given when
+    /// they differ.
+    fn resolve_watch_paths(&mut self) -> Result<()> {
+        let current_dir = env::current_dir().context("cannot get current directory")?;
+        let mut resolved = Vec::new();
+        for path in std::mem::take(&mut self.watch_paths) {
+            let canonical = match resolve_watch_path(&path) {
+                Ok(canonical) => canonical,
+                Err(err) if self.lenient_paths => {
+                    log::warn!("Skipping watched path: {err:#}");
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let original = current_dir.join(&path);
+            if original != canonical {
+                self.watch_aliases.push((original, canonical.clone()));
+            }
+            resolved.push(canonical);
+        }
+        if resolved.is_empty() && self.watch_globs.is_empty() {
+            anyhow::bail!("none of the watched paths exist");
+        }
+        self.watch_paths = resolved;
+        Ok(())
+    }
+
+    /// Rewrite a path reported under a watched path as given, like a symlink
+    /// or a mount point that the watcher doesn't resolve, to its canonical
+    /// form.
+    fn normalize_path(&self, path: PathBuf) -> PathBuf {
+        self.watch_aliases
+            .iter()
+            .filter(|(_, canonical)| !path.starts_with(canonical))
+            .find_map(|(original, canonical)| {
+                path.strip_prefix(original).ok().map(|x| canonical.join(x))
+            })
+            .unwrap_or(path)
+    }
+
+    /// Move the watched paths containing wildcards to the globs.
+    fn resolve_watch_globs(&mut self) -> Result<()> {
+        let (globs, paths) = std::mem::take(&mut self.watch_paths)
+            .into_iter()
+            .partition(|x| is_glob(x));
+        self.watch_paths = paths;
+        self.watch_globs = globs
+            .iter()
+            .map(|x| resolve_glob(x))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(())
+    }
+
+    fn is_excluded_path(&self, path: &Path) -> bool {
+        // the paths are only indexed once resolved
+        let built;
+        let index = match &self.exclude_index {
+            Some(index) => index,
+            None => {
+                built = ExcludeIndex::new(self);
+                &built
+            }
+        };
+
+        if index.paths.contains_ancestor(path) {
+            return true;
+        }
+
+        for (root, excluded) in &index.roots {
+            let Ok(stripped_path) = path.strip_prefix(root) else {
+                continue;
+            };
+            if excluded.contains_ancestor(stripped_path) {
+                return true;
+            }
+        }
+
+        if let Ok(stripped_path) = path.strip_prefix(metadata().workspace_root.as_std_path()) {
+            if index.workspace.contains_ancestor(stripped_path) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Summarize a batch of changes in a single line, listing up to 3 paths.
+    fn changes_summary(&self, changes: &[Change]) -> String {
+        let mut paths: Vec<&Path> = changes
+            .iter()
+            .map(|x| self.relative_path(&x.path))
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        let listed: Vec<String> = paths
+            .iter()
+            .take(3)
+            .map(|x| x.display().to_string())
+            .collect();
+        let mut summary = format!(
+            "Detected changes in {} file{}: {}",
+            paths.len(),
+            if paths.len() == 1 { "" } else { "s" },
+            listed.join(", ")
+        );
+        if paths.len() > 3 {
+            summary.push_str(&format!(" and {} more", paths.len() - 3));
+        }
+        summary
+    }
+
+    /// Describe what triggered a rerun, like `[file: src/lib.rs]`.
+    fn trigger_tag(&self, trigger: &Trigger) -> String {
+        match trigger.reason {
+            TriggerReason::Initial => "[initial]".to_string(),
+            TriggerReason::File => {
+                let mut paths: Vec<&Path> = trigger
+                    .changes
+                    .iter()
+                    .map(|x| self.relative_path(&x.path))
+                    .collect();
+                paths.sort();
+                paths.dedup();
+                match paths.as_slice() {
+                    [] => "[file]".to_string(),
+                    [path] => format!("[file: {}]", path.display()),
+                    [path, rest @ ..] => {
+                        format!("[file: {} and {} more]", path.display(), rest.len())
+                    }
+                }
+            }
+            TriggerReason::Manual => "[manual]".to_string(),
+            TriggerReason::Git => "[git]".to_string(),
+            TriggerReason::Rescan => "[rescan]".to_string(),
+            TriggerReason::Scheduled => "[scheduled]".to_string(),
+            TriggerReason::Crash => "[crash]".to_string(),
+        }
+    }
+
+    /// List the changed paths grouped by kind, one line per kind.
+    fn changes_by_kind(&self, changes: &[Change]) -> Vec<String> {
+        let mut groups = std::collections::BTreeMap::<ChangeKind, Vec<&Path>>::new();
+        for change in changes {
+            groups
+                .entry(change.kind)
+                .or_default()
+                .push(self.relative_path(&change.path));
+        }
+
+        groups
+            .into_iter()
+            .map(|(kind, mut paths)| {
+                paths.sort();
+                paths.dedup();
+                let label = match kind {
+                    ChangeKind::Created => "Created",
+                    ChangeKind::Modified => "Modified",
+                    ChangeKind::Removed => "Removed",
+                };
+                let paths: Vec<String> = paths.iter().map(|x| x.display().to_string()).collect();
+                format!("{label}: {}", paths.join(", "))
+            })
+            .collect()
+    }
+
+    /// Get `path` relative to its closest watched path, for display.
+    fn relative_path<'a>(&self, path: &'a Path) -> &'a Path {
+        self.watch_root(path)
+            .and_then(|root| path.strip_prefix(root).ok())
+            .filter(|x| !x.as_os_str().is_empty())
+            .unwrap_or(path)
+    }
+
+    /// Get the closest watched path containing `path`, that the path is
+    /// relative to.
+    fn watch_root<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        let live = self.live.read().expect("not poisoned");
+        let watch_paths = live.as_ref().map_or(&self.watch_paths, |x| &x.paths);
+        path.ancestors()
+            .find(|x| watch_paths.iter().any(|watched| watched == x))
+            .into_iter()
+            .chain(self.glob_root(path))
+            .max_by_key(|x| x.components().count())
+    }
+
+    fn is_hidden_path(&self, path: &Path) -> bool {
+        !self.include_hidden
+            && self
+                .watch_root(path)
+                .and_then(|root| path.strip_prefix(root).ok())
+                .and_then(|x| x.components().next())
+                .is_some_and(|x| {
+                    let name = x.as_os_str().to_string_lossy();
+                    name.starts_with('.') && !self.watch_hidden.iter().any(|x| *x == name)
+                })
+    }
+
+    fn is_backup_file(&self, path: &Path) -> bool {
+        self.watch_root(path)
+            .and_then(|root| path.strip_prefix(root).ok())
+            .is_some_and(|x| x.to_string_lossy().ends_with('~'))
+    }
+}
+
+/// What a [`CommandList`] bound to the changed files is selected by.
+#[derive(Clone, Debug)]
+enum Binding {
+    Extension(OsString),
+    FileName(OsString),
+}
+
+impl Binding {
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            Self::Extension(ext) => path.extension() == Some(ext.as_os_str()),
+            Self::FileName(name) => path.file_name() == Some(name.as_os_str()),
+        }
+    }
+}
+
+/// A change detected in a watched path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Change {
+    path: PathBuf,
+    kind: ChangeKind,
+}
+
+impl Change {
+    fn new(path: impl Into<PathBuf>, kind: ChangeKind) -> Self {
+        Self {
+            path: path.into(),
+            kind,
+        }
+    }
+}
+
+/// A batch of changes sent to the watch loop, along with what triggered it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Trigger {
+    reason: TriggerReason,
+    changes: Vec<Change>,
+}
+
+impl Trigger {
+    fn new(reason: TriggerReason, changes: Vec<Change>) -> Self {
+        Self { reason, changes }
+    }
+
+    /// A rerun requested without any change.
+    fn manual() -> Self {
+        Self::new(TriggerReason::Manual, Vec::new())
+    }
+}
+
+impl From<Vec<Change>> for Trigger {
+    fn from(changes: Vec<Change>) -> Self {
+        Self::new(TriggerReason::File, changes)
+    }
+}
+
+impl IntoIterator for Trigger {
+    type Item = Change;
+    type IntoIter = std::vec::IntoIter<Change>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.changes.into_iter()
+    }
+}
+
+/// What triggered a rerun.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TriggerReason {
+    /// The first run of the watch.
+    Initial,
+    /// Changes in the watched files.
+    File,
+    /// A named pipe, the control socket or a [`WatchSession`].
+    Manual,
+    /// A change of the git `HEAD` or of a branch.
+    Git,
+    /// Events were missed by the watcher.
+    Rescan,
+    /// A time given to [`Watch::rerun_at`] was reached.
+    Scheduled,
+    /// The command crashed and is restarted.
+    Crash,
+}
+
+/// Kind of a [`Change`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+}
+
+impl From<&notify::EventKind> for ChangeKind {
+    fn from(kind: &notify::EventKind) -> Self {
+        match kind {
+            notify::EventKind::Create(_) => Self::Created,
+            notify::EventKind::Remove(_) => Self::Removed,
+            _ => Self::Modified,
+        }
+    }
+}
+
+/// Kind of event that can relaunch the command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TriggerEvent {
+    /// A file or directory is created.
+    Create,
+    /// The content of a file changes.
+    Modify,
+    /// A file or directory is removed.
+    Remove,
+    /// The permissions or other metadata of a file change.
+    Chmod,
+}
+
+impl From<&notify::EventKind> for TriggerEvent {
+    fn from(kind: &notify::EventKind) -> Self {
+        match kind {
+            notify::EventKind::Create(_) => Self::Create,
+            notify::EventKind::Remove(_) => Self::Remove,
+            kind if is_metadata_change(kind) => Self::Chmod,
+            _ => Self::Modify,
+        }
+    }
+}
+
+/// What to do when changes are detected while the command is still running.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChangeWhileRunning {
+    /// Terminate the running command and relaunch it immediately.
+    ///
+    /// The command is relaunched once the previous run is over, so it never
+    /// overlaps a command of the previous run still holding a resource like a
+    /// port.
+    #[default]
+    Replace,
+    /// Let the running command finish, then relaunch it once, no matter how
+    /// many changes occurred in the meantime.
+    ///
+    /// A command that never exits (like a server) will never be relaunched.
+    Queue,
+}
+
+/// What to do when the program of a command doesn't exist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MissingProgram {
+    /// Stop the watch with an error naming the program.
+    Abort,
+    /// Log the error and try again on the next change, like for a command
+    /// that failed.
+    #[default]
+    Retry,
+}
+
+/// Error of a command whose program doesn't exist.
+#[derive(Debug)]
+struct ProgramNotFound(OsString);
+
+impl fmt::Display for ProgramNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "program `{}` not found", self.0.to_string_lossy())
+    }
+}
+
+impl std::error::Error for ProgramNotFound {}
+
+/// Standard input of the command.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChildStdin {
+    /// Inherit the standard input of the watch.
+    #[default]
+    Inherit,
+    /// Use an empty standard input (`/dev/null`).
+    Null,
+}
+
+/// Create the named pipe at `path` if needed and trigger a rerun for every
+/// line written to it.
+#[cfg(unix)]
+fn spawn_fifo_reader(path: &Path, tx: mpsc::Sender<Trigger>) -> Result<()> {
+    use std::os::unix::{ffi::OsStrExt, fs::FileTypeExt};
+
+    if !path.exists() {
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .with_context(|| format!("invalid path {}", path.display()))?;
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("cannot create {}", path.display()));
+        }
+    } else if !fs::metadata(path)?.file_type().is_fifo() {
+        anyhow::bail!("{} is not a named pipe", path.display());
+    }
+
+    let path = path.to_path_buf();
+    thread::spawn(move || loop {
+        // blocks until a writer opens the pipe, reaches the end when it's
+        // closed
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                log::error!("cannot read {}: {err}", path.display());
+                break;
+            }
+        };
+        for _ in io::BufRead::lines(io::BufReader::new(file)).map_while(Result::ok) {
+            log::trace!("trigger received from {}", path.display());
+            if tx.send(Trigger::manual()).is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_fifo_reader(_path: &Path, _tx: mpsc::Sender<Trigger>) -> Result<()> {
+    anyhow::bail!("named pipes are only supported on Unix")
+}
+
+const DAY: Duration = Duration::from_secs(24 * 3600);
+
+/// Parse a time of the day given as `HH:MM`, returning the duration since
+/// midnight.
+fn parse_time_of_day(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("`{s}` is not a time of the day (`HH:MM`)");
+    let (hour, minute) = s.trim().split_once(':').ok_or_else(invalid)?;
+    let hour: u64 = hour.parse().map_err(|_| invalid())?;
+    let minute: u64 = minute.parse().map_err(|_| invalid())?;
+    if hour >= 24 || minute >= 60 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(hour * 3600 + minute * 60))
+}
+
+/// Get the local time of the day of `time`, as a duration since midnight.
+///
+/// The local time zone is only known on Unix, UTC is used elsewhere.
+fn time_of_day(time: SystemTime) -> Duration {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    #[cfg(unix)]
+    let offset = unsafe {
+        let secs = since_epoch.as_secs() as libc::time_t;
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        if libc::localtime_r(&secs, &mut tm).is_null() {
+            0
+        } else {
+            tm.tm_gmtoff as i64
+        }
+    };
+    #[cfg(not(unix))]
+    let offset = 0;
+    let secs = (since_epoch.as_secs() as i64 + offset).rem_euclid(DAY.as_secs() as i64);
+    Duration::from_secs(secs as u64) + Duration::from_nanos(since_epoch.subsec_nanos().into())
+}
+
+/// Get the delay until the next of the `times` of the day, from `now`.
+fn next_scheduled_delay(now: Duration, times: &[Duration]) -> Option<Duration> {
+    times
+        .iter()
+        .map(|time| {
+            if *time > now {
+                *time - now
+            } else {
+                *time + DAY - now
+            }
+        })
+        .min()
+}
+
+/// Callback reloading the `config` file of the event `handler`, as long as
+/// the handler is alive.
+fn config_reloader(handler: Weak<Mutex<WatchEventHandler>>, config: PathBuf) -> Hook<ReloadConfig> {
+    Hook(Arc::new(move || {
+        if let Some(handler) = handler.upgrade() {
+            handler.lock().expect("not poisoned").reload_config(&config);
+        }
+    }))
+}
+
+/// Trigger a rerun every day at the given `times`, until the watch is
+/// `stopped`.
+fn spawn_scheduler(times: Vec<Duration>, tx: mpsc::Sender<Trigger>, stopped: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        while let Some(delay) = next_scheduled_delay(time_of_day(SystemTime::now()), &times) {
+            log::trace!("next scheduled rerun in {delay:?}");
+            let due = Instant::now() + delay;
+            loop {
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                let left = due.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    break;
+                }
+                thread::sleep(left.min(Duration::from_millis(100)));
+            }
+            if tx
+                .send(Trigger::new(TriggerReason::Scheduled, Vec::new()))
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+}
+
+/// Signals handled by the watch while it needs them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WatchSignal {
+    /// `SIGINT`, stopping the watch when a teardown command is waiting.
+    Interrupt,
+    /// `SIGUSR1`, toggling the pause.
+    Pause,
+    /// `SIGWINCH`, when the terminal of the watch is resized.
+    Resize,
+    /// `SIGUSR2`, reloading the config file.
+    Reload,
+}
+
+/// Number of signals received for each [`WatchSignal`].
+static SIGNALS_RECEIVED: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+impl WatchSignal {
+    const ALL: [Self; 4] = [Self::Interrupt, Self::Pause, Self::Resize, Self::Reload];
+
+    #[cfg(unix)]
+    fn number(self) -> libc::c_int {
+        match self {
+            Self::Interrupt => libc::SIGINT,
+            Self::Pause => libc::SIGUSR1,
+            Self::Resize => libc::SIGWINCH,
+            Self::Reload => libc::SIGUSR2,
+        }
+    }
+
+    /// Number of times the signal has been received by the process while
+    /// handled.
+    fn received(self) -> usize {
+        SIGNALS_RECEIVED[self as usize].load(Ordering::SeqCst)
+    }
+
+    /// Count the signal while the returned guard is alive, calling the
+    /// handler it replaces too.
+    ///
+    /// The previous handler is restored once the last guard of the signal is
+    /// dropped, as several watches can run in the process.
+    fn handle(self) -> SignalGuard {
+        #[cfg(unix)]
+        {
+            let mut handlers = REPLACED_HANDLERS.lock().expect("not poisoned");
+            let (guards, replaced) = &mut handlers[self as usize];
+            if *guards == 0 {
+                unsafe {
+                    let mut action: libc::sigaction = std::mem::zeroed();
+                    action.sa_sigaction = count_signal
+                        as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void)
+                        as usize;
+                    action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+                    libc::sigemptyset(&mut action.sa_mask);
+                    let mut previous: libc::sigaction = std::mem::zeroed();
+                    if libc::sigaction(self.number(), &action, &mut previous) == 0 {
+                        CHAINED_HANDLERS[self as usize]
+                            .store(previous.sa_sigaction, Ordering::SeqCst);
+                        CHAINED_SIGINFO[self as usize]
+                            .store(previous.sa_flags & libc::SA_SIGINFO != 0, Ordering::SeqCst);
+                        *replaced = Some(previous);
+                    } else {
+                        log::error!(
+                            "cannot handle {self:?} signal: {}",
+                            io::Error::last_os_error()
+                        );
+                    }
+                }
+            }
+            *guards += 1;
+        }
+
+        SignalGuard(self)
+    }
+}
+
+/// Keeps the watch's handler of a [`WatchSignal`] installed.
+#[derive(Debug)]
+struct SignalGuard(#[cfg_attr(not(unix), allow(dead_code))] WatchSignal);
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            let mut handlers = REPLACED_HANDLERS.lock().expect("not poisoned");
+            let (guards, replaced) = &mut handlers[self.0 as usize];
+            *guards -= 1;
+            if *guards == 0 {
+                if let Some(previous) = replaced.take() {
+                    unsafe {
+                        libc::sigaction(self.0.number(), &previous, std::ptr::null_mut());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Number of guards and handler replaced for each [`WatchSignal`].
+#[cfg(unix)]
+static REPLACED_HANDLERS: Mutex<[(usize, Option<libc::sigaction>); 4]> = Mutex::new([(0, None); 4]);
+
+/// Handler replaced for each [`WatchSignal`], called by [`count_signal`].
+#[cfg(unix)]
+static CHAINED_HANDLERS: [AtomicUsize; 4] = [
+    AtomicUsize::new(libc::SIG_DFL),
+    AtomicUsize::new(libc::SIG_DFL),
+    AtomicUsize::new(libc::SIG_DFL),
+    AtomicUsize::new(libc::SIG_DFL),
+];
+
+/// The replaced handler takes the signal information.
+#[cfg(unix)]
+static CHAINED_SIGINFO: [AtomicBool; 4] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
+
+#[cfg(unix)]
+extern "C" fn count_signal(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    let Some(watch_signal) = WatchSignal::ALL.into_iter().find(|x| x.number() == signal) else {
+        return;
+    };
+    SIGNALS_RECEIVED[watch_signal as usize].fetch_add(1, Ordering::SeqCst);
+
+    let chained = CHAINED_HANDLERS[watch_signal as usize].load(Ordering::SeqCst);
+    if chained == libc::SIG_DFL || chained == libc::SIG_IGN {
+        return;
+    }
+    unsafe {
+        if CHAINED_SIGINFO[watch_signal as usize].load(Ordering::SeqCst) {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                std::mem::transmute(chained);
+            handler(signal, info, context);
+        } else {
+            let handler: extern "C" fn(libc::c_int) = std::mem::transmute(chained);
+            handler(signal);
+        }
+    }
+}
+
+/// Handle to a watch running on a background thread, created by
+/// [`Watch::spawn_session`].
+///
+/// Dropping the handle stops the watch, terminating the running command.
+#[derive(Debug)]
+pub struct WatchSession {
+    watch: Watch,
+    tx: mpsc::Sender<Trigger>,
+    thread: Option<thread::JoinHandle<Result<()>>>,
+}
+
+impl WatchSession {
+    /// Relaunch the command as if changes were detected.
+    pub fn trigger(&self) {
+        let _ = self.tx.send(Trigger::manual());
+    }
+
+    /// Pause the watch: changes are still detected but the command is not
+    /// relaunched.
+    ///
+    /// Like with [`Watch::pause_on_signal`], the command is relaunched when
+    /// resuming if changes occurred in the meantime and `run_on_resume` is
+    /// set.
+    pub fn pause(&self) {
+        self.watch.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resume the watch after [`pause`](Self::pause).
+    pub fn resume(&self) {
+        self.watch.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Start watching `path` while the watch is running, like the paths given
+    /// to [`Watch::watch_path`].
+    ///
+    /// The excluded and hidden paths are skipped in the new path like in the
+    /// others. A path added while the watch is starting is watched once it
+    /// has started. Fails when the path doesn't exist or the watch is over.
+    pub fn add_watch(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = resolve_watch_path(path.as_ref())?;
+        let mut live = self.watch.live.write().expect("not poisoned");
+        let Some(live) = live.as_mut() else {
+            anyhow::ensure!(
+                self.status() != SessionStatus::Stopped,
+                "the watch is not running"
+            );
+            // taken by the watch with the lock held when it starts
+            self.watch
+                .queued_watches
+                .lock()
+                .expect("not poisoned")
+                .push(path);
+            return Ok(());
+        };
+        if !live.paths.contains(&path) {
+            live.paths.push(path.clone());
+        }
+        // registered by the watch, with its resolved exclusions
+        let _ = live.new_dirs.send(path);
+        Ok(())
+    }
+
+    /// Stop watching `path`, a path given to [`Watch::watch_path`] or
+    /// [`add_watch`](Self::add_watch), while the watch is running.
+    ///
+    /// The directories still in other watched paths are left alone. Fails
+    /// when the watch isn't running.
+    pub fn remove_watch(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let (paths, watcher) = {
+            let mut live = self.watch.live.write().expect("not poisoned");
+            let live = live.as_mut().context("the watch is not running")?;
+            live.paths.retain(|x| *x != path);
+            (live.paths.clone(), live.watcher.clone())
+        };
+
+        let Some(watcher) = watcher.upgrade() else {
+            return Ok(());
+        };
+        let mut watcher = watcher.lock().expect("not poisoned");
+        let mut queue = vec![path];
+        while let Some(dir) = queue.pop() {
+            if paths.iter().any(|x| dir.starts_with(x)) {
+                continue;
+            }
+            for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+                if entry.file_type().is_ok_and(|x| x.is_dir()) {
+                    queue.push(entry.path());
+                }
+            }
+            // the directories that were excluded were not watched
+            let _ = watcher.unwatch(&dir);
+        }
+        Ok(())
+    }
+
+    /// Get the current status of the session.
+    pub fn status(&self) -> SessionStatus {
+        if self.thread.as_ref().map_or(true, |x| x.is_finished()) {
+            SessionStatus::Stopped
+        } else if self.watch.paused.load(Ordering::SeqCst) {
+            SessionStatus::Paused
+        } else if self.watch.is_running() {
+            SessionStatus::Running
+        } else {
+            SessionStatus::Idle
+        }
+    }
+
+    /// Stop the watch, terminating the running command, and wait for it to
+    /// finish, returning its error if it failed.
+    pub fn stop(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        self.watch.stopped.store(true, Ordering::SeqCst);
+        // wake up the watch loop
+        let _ = self.tx.send(Trigger::manual());
+        thread
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("the watch panicked")))
+    }
+}
+
+impl Drop for WatchSession {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown() {
+            log::error!("{err}");
+        }
+    }
+}
+
+/// Watched paths of a running watch, changed by a [`WatchSession`].
+#[derive(Debug)]
+struct LiveWatch {
+    /// Watched paths, replacing [`Watch::watch_paths`] once the watch runs.
+    paths: Vec<PathBuf>,
+    /// Directories to register in the watcher.
+    new_dirs: mpsc::Sender<PathBuf>,
+    watcher: Weak<Mutex<notify::RecommendedWatcher>>,
+}
+
+/// Status of a [`WatchSession`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionStatus {
+    /// The command is running.
+    Running,
+    /// The watch waits for changes.
+    Idle,
+    /// The watch is paused.
+    Paused,
+    /// The watch is over.
+    Stopped,
+}
+
+/// Run `commands` after every period of `quiet` without changes, returning
+/// the sender to notify the changes.
+///
+/// The commands are terminated when the sender is dropped.
+fn spawn_quiet_runner(
+    commands: CommandList,
+    quiet: Duration,
+    kill_escalation: Vec<KillStep>,
+) -> mpsc::Sender<()> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut current_child = SharedChild::new().kill_escalation(kill_escalation);
+        let mut current_run = None;
+        /// Skip the remaining commands of the run and terminate it.
+        fn stop_run(
+            run: Option<(thread::JoinHandle<()>, Arc<AtomicBool>)>,
+            current_child: &mut SharedChild,
+        ) {
+            if let Some((run, cancelled)) = run {
+                cancelled.store(true, Ordering::SeqCst);
+                current_child.terminate();
+                let _ = run.join();
+            }
+        }
+        let mut pending = true;
+        loop {
+            let res = if pending {
+                rx.recv_timeout(quiet)
+            } else {
+                rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+            };
+            match res {
+                Ok(()) => {
+                    stop_run(current_run.take(), &mut current_child);
+                    pending = true;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    pending = false;
+                    let mut commands = commands.clone();
+                    let mut current_child = current_child.clone();
+                    let options = SpawnOptions::default();
+                    let cancelled = options.cancelled.clone();
+                    let run = thread::spawn(move || {
+                        log::info!("Running the quiet command");
+                        let start = Instant::now();
+                        let mut success = true;
+                        commands.spawn_in(&options, |res, spawned| {
+                            success = match res {
+                                Ok(child) => {
+                                    current_child.replace(child);
+                                    // spawned right before the run was
+                                    // cancelled
+                                    if options.cancelled.load(Ordering::SeqCst) {
+                                        current_child.terminate();
+                                    }
+                                    current_child.wait(spawned.timeout).success()
+                                }
+                                Err(err) => {
+                                    log::error!("Could not execute quiet command: {err}");
+                                    false
+                                }
+                            };
+                            success
+                        });
+                        let elapsed = start.elapsed();
+                        if success {
+                            log::info!("Quiet command succeeded in {elapsed:.1?}");
+                        } else {
+                            log::error!("Quiet command failed in {elapsed:.1?}");
+                        }
+                    });
+                    current_run = Some((run, cancelled));
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        stop_run(current_run, &mut current_child);
+    });
+    tx
+}
+
+/// Pause state of the watch loop.
+#[derive(Debug, Default)]
+struct Pause {
+    paused: bool,
+    /// Changes that occurred while paused, once per path, kept only when
+    /// relaunching the command on resume.
+    pending: Vec<Change>,
+    /// Position of the paths in `pending`.
+    pending_index: HashMap<PathBuf, usize>,
+    keep_pending: bool,
+    /// Number of `SIGUSR1` seen when pausing with the signal.
+    signals: Option<usize>,
+    /// Paused by the signal.
+    signal_paused: bool,
+    /// Check if the terminal is still attached, the watch is paused when it
+    /// isn't.
+    attached: Option<fn() -> bool>,
+    /// Pause requested by a [`WatchSession`].
+    requested: Arc<AtomicBool>,
+    /// Check if the machine runs on battery, the watch is paused when it does.
+    on_battery: Option<fn() -> bool>,
+}
+
+impl Pause {
+    /// Sync with the signals received, the terminal, the power source and the
+    /// session, returning `true` when resuming.
+    fn update(&mut self) -> bool {
+        if let Some(seen) = &mut self.signals {
+            // every signal received since toggles the pause
+            let received = WatchSignal::Pause.received();
+            if received.wrapping_sub(*seen) % 2 == 1 {
+                self.signal_paused = !self.signal_paused;
+            }
+            *seen = received;
+        }
+        let detached = self.attached.is_some_and(|attached| !attached());
+        let battery = self.on_battery.is_some_and(|on_battery| on_battery());
+        let paused =
+            self.signal_paused || self.requested.load(Ordering::SeqCst) || detached || battery;
+        if paused == self.paused {
+            return false;
+        }
+
+        self.paused = paused;
+        if paused {
+            if detached {
+                log::info!("Paused: the terminal is detached");
+            } else if battery {
+                log::info!("Paused: running on battery");
+            } else {
+                log::info!("Paused");
+            }
+            false
+        } else {
+            log::info!("Resumed");
+            true
+        }
+    }
+
+    /// Remember `changes` for when resuming, if they're needed then.
+    fn defer(&mut self, changes: impl IntoIterator<Item = Change>) {
+        if !self.keep_pending {
+            return;
+        }
+        for change in changes {
+            match self.pending_index.get(&change.path) {
+                Some(&i) => self.pending[i] = change,
+                None => {
+                    self.pending_index
+                        .insert(change.path.clone(), self.pending.len());
+                    self.pending.push(change);
+                }
+            }
+        }
+    }
+
+    fn take_pending(&mut self) -> Vec<Change> {
+        self.pending_index.clear();
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Interval between two checks of the power source.
+const POWER_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Check if the machine runs on battery, at most every
+/// [`POWER_CHECK_INTERVAL`].
+fn on_battery() -> bool {
+    static LAST_CHECK: Mutex<Option<(Instant, bool)>> = Mutex::new(None);
+
+    let mut last_check = LAST_CHECK.lock().expect("not poisoned");
+    match *last_check {
+        Some((time, on_battery)) if time.elapsed() < POWER_CHECK_INTERVAL => on_battery,
+        _ => {
+            let on_battery = query_on_battery();
+            *last_check = Some((Instant::now(), on_battery));
+            on_battery
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn query_on_battery() -> bool {
+    power_supply_on_battery(Path::new("/sys/class/power_supply"))
+}
+
+#[cfg(target_os = "macos")]
+fn query_on_battery() -> bool {
+    Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()
+        .is_ok_and(|x| String::from_utf8_lossy(&x.stdout).contains("'Battery Power'"))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn query_on_battery() -> bool {
+    false
+}
+
+/// A battery of the power supply class directory `dir` (like
+/// `/sys/class/power_supply`) is discharging.
+#[cfg(target_os = "linux")]
+fn power_supply_on_battery(dir: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    let read = |path: PathBuf| fs::read_to_string(path).unwrap_or_default();
+    entries.flatten().any(|entry| {
+        read(entry.path().join("type")).trim() == "Battery"
+            && read(entry.path().join("status")).trim() == "Discharging"
+    })
+}
+
+/// Get a check of the standard streams that are terminals now still being
+/// terminals.
+fn terminal_attached_check() -> fn() -> bool {
+    match (io::stdin().is_terminal(), io::stdout().is_terminal()) {
+        (true, true) => || io::stdin().is_terminal() && io::stdout().is_terminal(),
+        (true, false) => || io::stdin().is_terminal(),
+        (false, true) => || io::stdout().is_terminal(),
+        (false, false) => {
+            log::warn!("Neither the standard input nor output is a terminal");
+            || true
+        }
+    }
+}
+
+/// Find the workspace package containing `path`.
+fn path_package<'a>(
+    metadata: &'a cargo_metadata::Metadata,
+    path: &Path,
+) -> Option<&'a cargo_metadata::Package> {
+    metadata
+        .workspace_packages()
+        .into_iter()
+        .filter(|package| {
+            package
+                .manifest_path
+                .parent()
+                .is_some_and(|dir| path.starts_with(dir))
+        })
+        .max_by_key(|package| package.manifest_path.components().count())
+}
+
+/// How a path matches a glob.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GlobMatch {
+    /// The path is a parent of the paths that can match.
+    Partial,
+    /// The path matches.
+    Full,
+}
+
+/// The path contains wildcards.
+fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?'])
+}
+
+/// Make `glob` absolute and canonicalize its parent without wildcards.
+fn resolve_glob(glob: &Path) -> Result<PathBuf> {
+    let glob = env::current_dir()
+        .context("cannot get the current directory")?
+        .join(glob);
+    let parent = glob_parent(&glob);
+    let pattern = glob.strip_prefix(&parent).expect("parent of the glob");
+    let parent = parent
+        .canonicalize()
+        .with_context(|| format!("can't find {}", parent.display()))?;
+    Ok(parent.join(pattern))
+}
+
+/// Get the longest parent of `glob` without wildcards.
+fn glob_parent(glob: &Path) -> PathBuf {
+    glob.components()
+        .take_while(|x| !is_glob(x.as_ref()))
+        .collect()
+}
+
+/// Match `path` against `glob`, component by component.
+fn glob_match(glob: &Path, path: &Path) -> Option<GlobMatch> {
+    let mut patterns = glob.components();
+    for component in path.components() {
+        let pattern = patterns.next()?;
+        if !wildcard_match(
+            &pattern.as_os_str().to_string_lossy(),
+            &component.as_os_str().to_string_lossy(),
+        ) {
+            return None;
+        }
+    }
+
+    Some(if patterns.next().is_some() {
+        GlobMatch::Partial
+    } else {
+        GlobMatch::Full
+    })
+}
+
+/// Match `name` against `pattern`, where `*` matches any sequence of
+/// characters and `?` any single character. Like in shells, hidden names only
+/// match patterns starting with a `.`.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and of the name when it was met
+    let mut star = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the `*` match one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+/// Number of retries when a directory cannot be watched.
+const WATCH_RETRIES: u32 = 3;
+/// Delay between the retries when a directory cannot be watched.
+const WATCH_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Watch `path` without recursion, returning `false` if it failed and will
+/// be retried when `retrying` is set.
+fn watch_directory(watcher: &mut impl Watcher, path: &Path, retrying: bool) -> bool {
+    match watcher.watch(path, RecursiveMode::NonRecursive) {
+        Ok(()) => {
+            log::trace!("Watching {}", path.display());
+            true
+        }
+        Err(err) if retrying => {
+            log::trace!("cannot watch {}, retrying: {err}", path.display());
+            false
+        }
+        Err(err) => {
+            log::error!("cannot watch {}: {err}", path.display());
+            false
+        }
+    }
+}
+
+/// Directories that could not be watched, retried a few times as they may be
+/// briefly unavailable (like a directory swapped by another tool).
+#[derive(Debug, Default)]
+struct WatchRetries {
+    /// The directories with their number of retries and the time of the next
+    /// one.
+    pending: Vec<(PathBuf, u32, Instant)>,
+}
+
+impl WatchRetries {
+    /// Schedule the first retry of `dirs`.
+    fn add(&mut self, dirs: Vec<PathBuf>) {
+        let due = Instant::now() + WATCH_RETRY_DELAY;
+        self.pending
+            .extend(dirs.into_iter().map(|dir| (dir, 0, due)));
+    }
+
+    /// Time of the next retry, if any.
+    fn next_due(&self) -> Option<Instant> {
+        self.pending.iter().map(|(_, _, due)| *due).min()
+    }
+
+    /// Retry the directories whose time has come, giving up on them after
+    /// the last retry.
+    fn retry_due(&mut self, watcher: &mut impl Watcher) {
+        let now = Instant::now();
+        self.pending.retain_mut(|(dir, retries, due)| {
+            if *due > now {
+                return true;
+            }
+            *retries += 1;
+            *due = now + WATCH_RETRY_DELAY;
+            !watch_directory(watcher, dir, *retries < WATCH_RETRIES) && *retries < WATCH_RETRIES
+        });
+    }
+}
+
+/// The path is a single name, matched against every component of the paths
+/// instead of being a prefix.
+fn is_bare_name(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(_)), None)
+    )
+}
+
+/// Excluded paths of a watch, indexed so checking a path doesn't depend on
+/// the number of excluded paths.
+#[derive(Debug, Default)]
+struct ExcludeIndex {
+    paths: PathSet,
+    roots: Vec<(PathBuf, PathSet)>,
+    /// Relative to the workspace root.
+    workspace: PathSet,
+}
+
+impl ExcludeIndex {
+    fn new(watch: &Watch) -> Self {
+        Self {
+            paths: PathSet::new(&watch.exclude_paths, true),
+            roots: watch
+                .root_exclude_paths
+                .iter()
+                .map(|(root, excluded)| (root.clone(), PathSet::new(excluded, true)))
+                .collect(),
+            workspace: PathSet::new(&watch.workspace_exclude_paths, false),
+        }
+    }
+}
+
+/// Set of paths stored as a tree of their components, to find an ancestor of
+/// a path in a time proportional to its depth.
+#[derive(Debug, Default)]
+struct PathSet {
+    /// Bare names, matching any component of a path.
+    names: std::collections::HashSet<OsString>,
+    root: PathNode,
+}
+
+#[derive(Debug, Default)]
+struct PathNode {
+    /// A path of the set ends here.
+    end: bool,
+    children: HashMap<OsString, PathNode>,
+}
+
+impl PathSet {
+    /// Build a set of `paths`, the bare names matching any component when
+    /// `bare_names` is set, or the first one like the other paths otherwise.
+    fn new(paths: &[PathBuf], bare_names: bool) -> Self {
+        let mut set = Self::default();
+        for path in paths {
+            if bare_names && is_bare_name(path) {
+                set.names.insert(path.as_os_str().to_os_string());
+                continue;
+            }
+            let mut node = &mut set.root;
+            for component in path.components() {
+                node = node
+                    .children
+                    .entry(component.as_os_str().to_os_string())
+                    .or_default();
+            }
+            node.end = true;
+        }
+        set
+    }
+
+    /// Returns `true` if `path` or one of its ancestors is in the set.
+    fn contains_ancestor(&self, path: &Path) -> bool {
+        if !self.names.is_empty()
+            && path
+                .components()
+                .any(|x| self.names.contains(x.as_os_str()))
+        {
+            return true;
+        }
+
+        let mut node = &self.root;
+        if node.end {
+            return true;
+        }
+        for component in path.components() {
+            match node.children.get(component.as_os_str()) {
+                Some(child) if child.end => return true,
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+/// Canonicalize a watched path, following the symlinks to their target.
+fn resolve_watch_path(path: &Path) -> Result<PathBuf> {
+    if path.is_symlink() && !path.exists() {
+        anyhow::bail!("{} is a broken symlink", path.display());
+    }
+    path.canonicalize()
+        .with_context(|| format!("can't find {}", path.display()))
+}
+
+/// Read a list of paths, one per line, from a file or from the standard input
+/// when `path` is `-`.
+fn read_path_list(path: &Path) -> Result<Vec<PathBuf>> {
+    let res = if path == Path::new("-") {
+        parse_path_list(io::stdin().lock())
+    } else {
+        fs::File::open(path).and_then(|file| parse_path_list(io::BufReader::new(file)))
+    };
+    res.with_context(|| format!("cannot read paths from {}", path.display()))
+}
+
+/// Parse a list of paths, skipping blank lines and `#` comments.
+fn parse_path_list(reader: impl io::BufRead) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            paths.push(PathBuf::from(line));
+        }
+    }
+    Ok(paths)
+}
+
+/// List the workspace's source files found in the dep-info files of the
+/// profiles in `target_dir`, like `target/debug/foo.d`, or the existing files
+/// outside the workspace when `external` is set.
+fn dep_info_sources(target_dir: &Path, workspace_root: &Path, external: bool) -> Vec<PathBuf> {
+    let mut sources = Vec::new();
+    let profiles = fs::read_dir(target_dir).into_iter().flatten().flatten();
+    for profile in profiles.filter(|x| x.file_type().is_ok_and(|x| x.is_dir())) {
+        let files = fs::read_dir(profile.path()).into_iter().flatten().flatten();
+        for file in files.filter(|x| x.path().extension() == Some(OsStr::new("d"))) {
+            match fs::read_to_string(file.path()) {
+                Ok(content) => sources.extend(
+                    parse_dep_info(&content)
+                        .into_iter()
+                        .map(|x| workspace_root.join(x))
+                        .filter(|x| {
+                            if external {
+                                !x.starts_with(workspace_root) && x.is_file()
+                            } else {
+                                x.starts_with(workspace_root) && !x.starts_with(target_dir)
+                            }
+                        }),
+                ),
+                Err(err) => log::error!("cannot read {}: {err}", file.path().display()),
+            }
+        }
+    }
+    sources.sort();
+    sources.dedup();
+    sources
+}
+
+/// Parse the dependencies of the rules of a dep-info file, in the Makefile
+/// syntax used by cargo and rustc.
+fn parse_dep_info(content: &str) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for line in content.replace("\\\n", " ").lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let Some((_, deps)) = line.split_once(": ") else {
+            continue;
+        };
+
+        let mut path = String::new();
+        let mut chars = deps.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(' ') => path.push(' '),
+                    Some(c) => {
+                        path.push('\\');
+                        path.push(c);
+                    }
+                    None => path.push('\\'),
+                },
+                ' ' => {
+                    if !path.is_empty() {
+                        paths.push(PathBuf::from(std::mem::take(&mut path)));
+                    }
+                }
+                c => path.push(c),
+            }
+        }
+        if !path.is_empty() {
+            paths.push(PathBuf::from(path));
+        }
+    }
+    paths
+}
+
+/// Find the workspace package containing all the `paths`, if any.
+fn changed_package<'a>(
+    metadata: &'a cargo_metadata::Metadata,
+    paths: &[PathBuf],
+) -> Option<&'a cargo_metadata::Package> {
+    let (first, rest) = paths.split_first()?;
+    let package = path_package(metadata, first)?;
+    rest.iter()
+        .all(|path| path_package(metadata, path).map(|x| &x.id) == Some(&package.id))
+        .then_some(package)
+}
+
+/// Flags selecting the workspace packages containing the `paths`, like
+/// `-p foo -p bar`, or `no_package` when there is none.
+fn package_flags(
+    metadata: &cargo_metadata::Metadata,
+    paths: &[PathBuf],
+    no_package: NoChangedPackage,
+) -> Vec<String> {
+    let mut packages = Vec::new();
+    for package in paths.iter().filter_map(|x| path_package(metadata, x)) {
+        if !packages.contains(&&package.name) {
+            packages.push(&package.name);
+        }
+    }
+
+    if packages.is_empty() {
+        return match no_package {
+            NoChangedPackage::Workspace => vec!["--workspace".to_string()],
+            NoChangedPackage::Empty => Vec::new(),
+        };
+    }
+    packages
+        .into_iter()
+        .flat_map(|name| ["-p".to_string(), name.clone()])
+        .collect()
+}
+
+/// Delay before re-running a command that failed `failures` times in a row.
+fn backoff_delay(failures: u32, max: Duration) -> Duration {
+    match failures {
+        0 => Duration::ZERO,
+        n => Duration::from_secs(1)
+            .saturating_mul(1 << (n - 1).min(31))
+            .min(max),
+    }
+}
+
+/// Canonicalize a path that might not exist yet, as long as its parent
+/// directory exists.
+fn canonicalize_missing(path: &Path) -> Result<PathBuf> {
+    if let Ok(path) = path.canonicalize() {
+        return Ok(path);
+    }
+
+    let name = path
+        .file_name()
+        .with_context(|| format!("invalid path {}", path.display()))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let parent = parent
+        .canonicalize()
+        .with_context(|| format!("can't find {}", parent.display()))?;
+
+    Ok(parent.join(name))
+}
+
+fn workspace_source_dirs(metadata: &cargo_metadata::Metadata) -> Vec<PathBuf> {
+    metadata
+        .workspace_packages()
+        .into_iter()
+        .filter_map(|package| package.manifest_path.parent())
+        .map(|dir| dir.join("src").into_std_path_buf())
+        .filter(|dir| dir.is_dir())
+        .collect()
+}
+
+type ShouldRerun = dyn Fn(&[PathBuf]) -> bool + Send + Sync;
+type ReadyWhen = dyn Fn() -> bool + Send + Sync;
+type BeforeTerminate = dyn Fn() + Send + Sync;
+type ReloadConfig = dyn Fn() + Send + Sync;
+type SummaryFormatter = dyn Fn(ExitStatus, Duration, &[PathBuf]) -> String + Send + Sync;
+
+/// A user-provided callback stored in [`Watch`].
+struct Hook<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for Hook<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+fn ring_bell(out: &mut impl Write, status: ExitStatus) -> io::Result<()> {
+    if !status.success() {
+        out.write_all(b"\x07")?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Format `time` as seconds since the Unix epoch, with milliseconds.
+fn format_timestamp(time: SystemTime) -> String {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:03}", time.as_secs(), time.subsec_millis())
+}
+
+/// Parse a time formatted by [`format_timestamp`].
+fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    let secs: f64 = s.trim().parse().ok()?;
+    (secs.is_finite() && secs >= 0.0).then(|| UNIX_EPOCH + Duration::from_secs_f64(secs))
+}
+
+/// Line written to the log file at the end of a run, like:
+/// `1700000000.123 exit=1 "src/lib.rs" "src/main.rs"`.
+fn run_log_line(time: SystemTime, status: ExitStatus, changes: &[PathBuf]) -> String {
+    let mut line = format_timestamp(time) + " ";
+
+    #[cfg(unix)]
+    let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+    #[cfg(not(unix))]
+    let signal: Option<i32> = None;
+    match (status.code(), signal) {
+        (Some(code), _) => line.push_str(&format!("exit={code}")),
+        (None, Some(signal)) => line.push_str(&format!("signal={signal}")),
+        (None, None) => line.push_str("exit=?"),
+    }
+
+    if changes.is_empty() {
+        line.push_str(" initial");
+    }
+    for path in changes {
+        line.push_str(&format!(" {path:?}"));
+    }
+    line.push('\n');
+    line
+}
+
+/// What to do with a command containing a token without value, like `{file}`
+/// on the first run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnresolvedToken {
+    /// Don't run the command.
+    #[default]
+    Skip,
+    /// Replace the token with an empty value.
+    Empty,
+}
+
+/// What the token `{packages}` is replaced by when no package contains changed
+/// paths.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NoChangedPackage {
+    /// Select all the packages with `--workspace`.
+    #[default]
+    Workspace,
+    /// Replace the token with nothing, letting cargo pick the default
+    /// packages.
+    Empty,
+}
+
+/// Values of the template tokens for a run.
+#[derive(Debug, Default, Clone)]
+struct TemplateValues {
+    /// The changed paths that triggered the run.
+    files: Vec<PathBuf>,
+    /// Name of the package containing all the changed paths.
+    package: Option<String>,
+    /// Flags selecting the packages containing the changed paths.
+    package_flags: Vec<String>,
+}
+
+impl TemplateValues {
+    /// Replace the tokens in `args`, returning `None` if a token has no value
+    /// and `empty` is false.
+    ///
+    /// An argument that is exactly `{files}` is replaced with one argument per
+    /// changed path, and likewise for `{packages}` with one argument per flag.
+    fn expand(&self, args: &[&OsStr], empty: bool) -> Option<Vec<OsString>> {
+        let file = self.files.first();
+        let values = [
+            (
+                "{files}",
+                (!self.files.is_empty()).then(|| {
+                    let files: Vec<_> =
+                        self.files.iter().map(|x| x.display().to_string()).collect();
+                    files.join(" ")
+                }),
+            ),
+            ("{file}", file.map(|x| x.display().to_string())),
+            (
+                "{dir}",
+                file.and_then(|x| x.parent())
+                    .map(|x| x.display().to_string()),
+            ),
+            ("{package}", self.package.clone()),
+        ];
+
+        let mut expanded = Vec::with_capacity(args.len());
+        for arg in args {
+            let Some(mut arg) = arg.to_str().map(str::to_string) else {
+                expanded.push(arg.to_os_string());
+                continue;
+            };
+            if arg == "{files}" && (empty || !self.files.is_empty()) {
+                expanded.extend(self.files.iter().map(|x| x.as_os_str().to_os_string()));
+                continue;
+            }
+            if arg == "{packages}" {
+                expanded.extend(self.package_flags.iter().map(OsString::from));
+                continue;
+            }
+            if arg.contains("{packages}") {
+                // the flags are separate arguments
+                log::warn!("`{{packages}}` must be a whole argument, not part of `{arg}`");
+                return None;
+            }
+            for (token, value) in &values {
+                if arg.contains(token) {
+                    match value {
+                        Some(value) => arg = arg.replace(token, value),
+                        None if empty => arg = arg.replace(token, ""),
+                        None => return None,
+                    }
+                }
+            }
+            expanded.push(arg.into());
+        }
+        Some(expanded)
+    }
+}
+
+/// Count of the diagnostics emitted by cargo during a run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct CargoSummary {
+    errors: usize,
+    warnings: usize,
+}
+
+impl CargoSummary {
+    /// Read cargo's JSON messages, copying the rendered diagnostics to
+    /// `stderr` and the regular output of the command to `stdout` along the
+    /// way.
+    fn from_stream(reader: impl io::BufRead, stdout: &OutputSink, stderr: &OutputSink) -> Self {
+        use cargo_metadata::{diagnostic::DiagnosticLevel, Message};
+
+        let mut summary = Self::default();
+        for message in Message::parse_stream(reader) {
+            match message {
+                Ok(Message::CompilerMessage(message)) => {
+                    let diagnostic = message.message;
+                    // rustc's own summaries are not diagnostics
+                    if diagnostic.message.starts_with("aborting due to")
+                        || diagnostic.message.ends_with("emitted")
+                    {
+                        continue;
+                    }
+                    if let Some(rendered) = &diagnostic.rendered {
+                        stderr.write(rendered.as_bytes());
+                    }
+                    match diagnostic.level {
+                        DiagnosticLevel::Error | DiagnosticLevel::Ice => summary.errors += 1,
+                        DiagnosticLevel::Warning => summary.warnings += 1,
+                        _ => {}
+                    }
+                }
+                Ok(Message::TextLine(line)) => stdout.write(format!("{line}\n").as_bytes()),
+                Ok(_) => {}
+                Err(err) => {
+                    log::error!("could not read cargo output: {err}");
+                    break;
+                }
+            }
+        }
+        summary
+    }
+}
+
+impl fmt::Display for CargoSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+        write!(
+            f,
+            "{} error{}, {} warning{}",
+            self.errors,
+            plural(self.errors),
+            self.warnings,
+            plural(self.warnings),
+        )
+    }
+}
+
+/// Parse the `KEY=VALUE` lines of an environment file.
+///
+/// Blank lines and lines starting with `#` are ignored, the keys can be
+/// prefixed with `export` and the values can be quoted.
+fn parse_env_file(content: &str) -> Result<Vec<(String, String)>> {
+    let mut envs = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            anyhow::bail!("line {}: expected `KEY=VALUE`", i + 1);
+        };
+        let key = key.trim();
+        if key.is_empty()
+            || key.starts_with(|c: char| c.is_ascii_digit())
+            || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            anyhow::bail!("line {}: invalid variable name `{key}`", i + 1);
+        }
+        let value = value.trim();
+        let value = ['"', '\'']
+            .into_iter()
+            .find_map(|quote| {
+                value
+                    .strip_prefix(quote)
+                    .and_then(|x| x.strip_suffix(quote))
+            })
+            .unwrap_or(value);
+        envs.push((key.to_string(), value.to_string()));
+    }
+    Ok(envs)
+}
+
+/// Settings read from a config file, see [`Watch::config`].
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ConfigFile {
+    watch_paths: Vec<PathBuf>,
+    exclude_paths: Vec<PathBuf>,
+    debounce: Option<Duration>,
+}
+
+/// Settings of the config file applied to a watch, resolved.
+#[derive(Clone, Debug, Default)]
+struct AppliedConfig {
+    watch_paths: Vec<PathBuf>,
+    exclude_paths: Vec<PathBuf>,
+    /// Debounce of the watch without the config file.
+    default_debounce: Option<Duration>,
+}
+
+/// Parse the `KEY = VALUE` lines of a config file.
+fn parse_config(content: &str) -> Result<ConfigFile> {
+    let mut config = ConfigFile::default();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            anyhow::bail!("line {}: expected `KEY = VALUE`", i + 1);
+        };
+        let value = value.trim();
+        match key.trim() {
+            "watch" => config.watch_paths.push(value.into()),
+            "exclude" => config.exclude_paths.push(value.into()),
+            "debounce" => {
+                config.debounce = Some(
+                    parse_millis(value).map_err(|err| anyhow::anyhow!("line {}: {err}", i + 1))?,
+                )
+            }
+            key => anyhow::bail!("line {}: unknown setting `{key}`", i + 1),
+        }
+    }
+    Ok(config)
+}
+
+/// Read the config file at `path`.
+fn read_config(path: &Path) -> Result<ConfigFile> {
+    fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| parse_config(&content))
+        .with_context(|| format!("invalid config file {}", path.display()))
+}
+
+/// Create a new [`Command`] with the same program, environment and working
+/// directory as `command` but with different arguments.
+fn rebuild_command(
+    command: &Command,
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Command {
+    let mut rebuilt = Command::new(command.get_program());
+    rebuilt.args(args);
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => rebuilt.env(key, value),
+            None => rebuilt.env_remove(key),
+        };
+    }
+    if let Some(dir) = command.get_current_dir() {
+        rebuilt.current_dir(dir);
+    }
+    rebuilt
+}
+
+/// A change of the metadata of a file other than its modification time, like
+/// its permissions.
+fn is_metadata_change(kind: &notify::EventKind) -> bool {
+    use notify::event::{MetadataKind, ModifyKind};
+
+    matches!(
+        kind,
+        notify::EventKind::Modify(ModifyKind::Metadata(x)) if *x != MetadataKind::WriteTime
+    )
+}
+
+/// FSEvents reports creations as `Create(File)` or `Create(Folder)`, an
+/// untyped `Create(Any)` only comes from coalesced flags that don't describe an
+/// actual creation. Other backends use `Create(Any)` for every new file
+/// (Windows, poll), so it is a legitimate change there.
+fn is_untyped_create(kind: &notify::EventKind) -> bool {
+    cfg!(target_os = "macos") && *kind == notify::EventKind::Create(notify::event::CreateKind::Any)
+}
+
+/// How long the changes are ignored after a change of the git `HEAD`.
+const GIT_CHECKOUT_QUIET_PERIOD: Duration = Duration::from_secs(1);
+
+/// Parse a `{"changed": ["path", ...]}` message of the control socket.
+#[cfg(unix)]
+fn parse_changed_message(message: &str) -> Result<Vec<PathBuf>> {
+    let message: serde_json::Value = serde_json::from_str(message)?;
+    let changed = message
+        .get("changed")
+        .and_then(serde_json::Value::as_array)
+        .context("no `changed` array")?;
+    anyhow::ensure!(!changed.is_empty(), "no changed path");
+    changed
+        .iter()
+        .map(|x| {
+            x.as_str()
+                .map(PathBuf::from)
+                .context("paths must be strings")
+        })
+        .collect()
+}
+
+/// Find the git directory of the repository containing `dir`.
+fn git_dir(dir: &Path) -> Result<PathBuf> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--absolute-git-dir"])
+        .current_dir(dir)
+        .stderr(Stdio::inherit())
+        .output()
+        .context("could not run git")?;
+    anyhow::ensure!(output.status.success(), "could not find the git directory");
+
+    Ok(PathBuf::from(
+        String::from_utf8(output.stdout)
+            .context("invalid git directory")?
+            .trim_end(),
+    ))
+}
+
+/// List the checked out submodules declared in the `.gitmodules` of `repo`.
+fn submodule_paths(repo: &Path) -> Result<Vec<PathBuf>> {
+    if !repo.join(".gitmodules").exists() {
+        return Ok(Vec::new());
+    }
+    let output = Command::new("git")
+        .args(["config", "--file", ".gitmodules", "-z", "--get-regexp"])
+        .arg(r"^submodule\..*\.path$")
+        .current_dir(repo)
+        .stderr(Stdio::inherit())
+        .output()
+        .context("could not run git")?;
+    // git exits with 1 when nothing matches
+    anyhow::ensure!(
+        output.status.success() || output.status.code() == Some(1),
+        "could not read {}",
+        repo.join(".gitmodules").display()
+    );
+
+    let output = String::from_utf8(output.stdout).context("invalid submodule path")?;
+    Ok(output
+        .split_terminator('\0')
+        .filter_map(|entry| entry.split_once('\n'))
+        .map(|(_, path)| repo.join(path))
+        .filter(|path| {
+            let checked_out = path.is_dir();
+            if !checked_out {
+                log::debug!("Submodule {} is not checked out", path.display());
+            }
+            checked_out
+        })
+        .collect())
+}
+
+struct WatchEventHandler {
+    watch: Watch,
+    tx: mpsc::Sender<Trigger>,
+    /// Receives the directories created after the start of the watch so they
+    /// can be watched too.
+    new_dirs: Option<mpsc::Sender<PathBuf>>,
+    /// Git directory when watching the git `HEAD`.
+    git_dir: Option<PathBuf>,
+    /// Changes are ignored until then, after a change of the git `HEAD`.
+    suppress_until: Option<Instant>,
+    /// Changes are ignored until then, right after starting to watch.
+    quiet_until: Arc<Mutex<Option<Instant>>>,
+    command_start: Instant,
+    /// Debounce of the last run, depending on the kind of its trigger.
+    debounce: Duration,
+    /// The watch loop is over, the events are ignored.
+    disconnected: bool,
+    /// Changes waiting for their directory to be quiet.
+    directory_batches: Arc<Mutex<DirectoryBatches>>,
+    /// Last time a change of each file relaunched the command.
+    file_triggers: HashMap<PathBuf, Instant>,
+}
+
+impl WatchEventHandler {
+    fn new(watch: Watch, tx: mpsc::Sender<Trigger>) -> Self {
+        Self {
+            tx,
+            new_dirs: None,
+            git_dir: None,
+            suppress_until: None,
+            quiet_until: Arc::new(Mutex::new(watch.startup_quiet.map(|x| Instant::now() + x))),
+            command_start: Instant::now(),
+            debounce: watch.debounce,
+            disconnected: false,
+            directory_batches: Default::default(),
+            file_triggers: HashMap::new(),
+            watch,
+        }
+    }
+
+    /// Send the changes to the watch loop, ignoring the following events if
+    /// it's over.
+    fn send(&mut self, reason: TriggerReason, changes: Vec<Change>) {
+        if self.tx.send(Trigger::new(reason, changes)).is_err() {
+            log::trace!("The watch loop is over, ignoring the following events");
+            self.disconnected = true;
+        }
+    }
+
+    /// Collect `changes` by directory, sending them once their directory has
+    /// been quiet for `debounce`.
+    fn batch_by_directory(&mut self, changes: Vec<Change>, debounce: Duration) {
+        let mut batches = self.directory_batches.lock().expect("not poisoned");
+        let now = Instant::now();
+        for change in changes {
+            let dir = change.path.parent().unwrap_or(&change.path).to_path_buf();
+            let (last, batch) = batches.dirs.entry(dir).or_insert((now, Vec::new()));
+            *last = now;
+            if !batch.contains(&change) {
+                batch.push(change);
+            }
+        }
+        if batches.flushing {
+            return;
+        }
+        batches.flushing = true;
+
+        let batches = self.directory_batches.clone();
+        let tx = self.tx.clone();
+        thread::spawn(move || loop {
+            let mut guard = batches.lock().expect("not poisoned");
+            let now = Instant::now();
+            let quiet: Vec<PathBuf> = guard
+                .dirs
+                .iter()
+                .filter(|(_, (last, _))| now.duration_since(*last) >= debounce)
+                .map(|(dir, _)| dir.clone())
+                .collect();
+            let changes: Vec<Change> = quiet
+                .iter()
+                .filter_map(|dir| guard.dirs.remove(dir))
+                .flat_map(|(_, batch)| batch)
+                .collect();
+            if !changes.is_empty() && tx.send(Trigger::new(TriggerReason::File, changes)).is_err() {
+                guard.dirs.clear();
+            }
+            let Some(next) = guard.dirs.values().map(|(last, _)| *last + debounce).min() else {
+                guard.flushing = false;
+                break;
+            };
+            drop(guard);
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+        });
+    }
+
+    /// A change of `path` relaunched the command less than
+    /// [`Watch::file_debounce`] ago.
+    fn is_file_debounced(&self, path: &Path) -> bool {
+        self.watch.file_debounce.is_some_and(|debounce| {
+            self.file_triggers
+                .get(path)
+                .is_some_and(|time| time.elapsed() < debounce)
+        })
+    }
+
+    fn is_git_head_path(&self, path: &Path) -> bool {
+        self.git_dir.as_ref().is_some_and(|git_dir| {
+            (path == git_dir.join("HEAD") || path.starts_with(git_dir.join("refs")))
+                && path.extension().map_or(true, |x| x != "lock")
+        })
+    }
+
+    fn is_triggering_path(&self, path: &Path, kind: &notify::EventKind) -> bool {
+        if let Some(marker) = &self.watch.ready_marker {
+            return path == marker && path.exists();
+        }
+        if self.watch.env_file.as_deref() == Some(path) {
+            return true;
+        }
+
+        let is_delete = matches!(kind, notify::EventKind::Remove(_))
+            && (self.watch.delete_debounce.is_some()
+                || self.watch.events.contains(&TriggerEvent::Remove));
+        self.watch.is_watched_path(path)
+            && !self.watch.is_excluded_path(path)
+            && (path.exists() || is_delete)
+            && !self.watch.is_hidden_path(path)
+            && !self.watch.is_backup_file(path)
+            && !is_untyped_create(kind)
+            && self.watch.is_event_accepted(path, kind)
+            && *kind
+                != notify::EventKind::Modify(notify::event::ModifyKind::Name(
+                    notify::event::RenameMode::Any,
+                ))
+    }
+
+    /// Apply the settings of the config file at `path`, watching the paths
+    /// it adds.
+    fn reload_config(&mut self, path: &Path) {
+        let dir = path.parent().expect("file in a directory");
+        match read_config(path).and_then(|config| self.watch.apply_config(config, dir)) {
+            Ok(new_paths) => {
+                log::info!("Config reloaded from {}", path.display());
+                if let Some(new_dirs) = &self.new_dirs {
+                    for path in new_paths {
+                        let _ = new_dirs.send(path);
+                    }
+                }
+            }
+            Err(err) => log::error!("Config not reloaded: {err:#}"),
+        }
+    }
+}
+
+impl EventHandler for WatchEventHandler {
+    fn handle_event(&mut self, event: Result<Event, notify::Error>) {
+        if self.disconnected {
+            return;
+        }
+
+        match event {
+            Ok(event) if event.need_rescan() => {
+                // the exact changes are unknown, anything might have changed
+                log::warn!("Some changes were missed, re-running the command");
+                self.command_start = Instant::now();
+                self.debounce = self.watch.debounce;
+                self.send(TriggerReason::Rescan, Vec::new());
+            }
+            Ok(mut event) => {
+                let received = Instant::now();
+                if !self.watch.watch_aliases.is_empty() {
+                    event.paths = std::mem::take(&mut event.paths)
+                        .into_iter()
+                        .map(|x| self.watch.normalize_path(x))
+                        .collect();
+                }
+                if let Some(config) = self
+                    .watch
+                    .config
+                    .clone()
+                    .filter(|x| self.watch.reload_on_config_change && event.paths.contains(x))
+                {
+                    // never a change relaunching the command
+                    if config.exists() {
+                        self.reload_config(&config);
+                    }
+                    return;
+                }
+                if let Some(new_dirs) = &self.new_dirs {
+                    // a directory moved into a watched one is new too
+                    if matches!(
+                        event.kind,
+                        notify::EventKind::Create(_)
+                            | notify::EventKind::Modify(notify::event::ModifyKind::Name(
+                                notify::event::RenameMode::To | notify::event::RenameMode::Both
+                            ))
+                    ) {
+                        for path in event.paths.iter().filter(|x| {
+                            x.is_dir()
+                                && !self.watch.is_excluded_path(x)
+                                && !self.watch.is_hidden_path(x)
+                        }) {
+                            let _ = new_dirs.send(path.clone());
+                        }
+                    }
+                }
+
+                let mut quiet_until = self.quiet_until.lock().expect("not poisoned");
+                if let Some(until) = *quiet_until {
+                    if Instant::now() < until {
+                        log::trace!("Ignoring changes on startup in {event:?}");
+                        return;
+                    }
+                    *quiet_until = None;
+                }
+                drop(quiet_until);
+
+                if let Some(until) = self.suppress_until {
+                    if Instant::now() < until {
+                        log::trace!("Ignoring changes after a git checkout in {event:?}");
+                        return;
+                    }
+                    self.suppress_until = None;
+                }
+
+                let kind = ChangeKind::from(&event.kind);
+                let git_paths: Vec<Change> = event
+                    .paths
+                    .iter()
+                    .filter(|x| self.is_git_head_path(x))
+                    .map(|x| Change::new(x, kind))
+                    .collect();
+                if !git_paths.is_empty() {
+                    self.suppress_until = Some(Instant::now() + GIT_CHECKOUT_QUIET_PERIOD);
+                    if self.command_start.elapsed() >= self.debounce {
+                        log::trace!("Git HEAD changed in {event:?}");
+                        self.command_start = Instant::now();
+                        self.debounce = self.watch.debounce;
+                        self.send(TriggerReason::Git, git_paths);
+                    }
+                    return;
+                }
+
+                let paths: Vec<Change> = event
+                    .paths
+                    .iter()
+                    .filter(|x| {
+                        self.is_triggering_path(x, &event.kind)
+                            // collected until their directory is quiet instead
+                            && (self.watch.directory_debounce.is_some()
+                                || self.command_start.elapsed() >= self.debounce)
+                            && !self.is_file_debounced(x)
+                    })
+                    .map(|x| Change::new(x, kind))
+                    .collect();
+
+                if !paths.is_empty() {
+                    log::trace!("Changes detected in {event:?}");
+                    if let Some(debounce) = self.watch.file_debounce {
+                        let now = Instant::now();
+                        self.file_triggers
+                            .retain(|_, time| now.duration_since(*time) < debounce);
+                        for change in &paths {
+                            self.file_triggers.insert(change.path.clone(), now);
+                        }
+                    }
+                    if let Some(debounce) = self.watch.directory_debounce {
+                        self.batch_by_directory(paths, debounce);
+                        return;
+                    }
+                    self.command_start = Instant::now();
+                    self.debounce = match (&event.kind, self.watch.delete_debounce) {
+                        (notify::EventKind::Remove(_), Some(debounce)) => debounce,
+                        _ => self.watch.debounce,
+                    };
+
+                    if self.watch.profile {
+                        log::info!("Profile: event accepted in {:.1?}", received.elapsed());
+                    }
+                    self.send(TriggerReason::File, paths);
+                } else {
+                    log::trace!("Ignoring changes in {event:?}");
+                }
+            }
+            Err(err) => log::error!("watch error: {err}"),
+        }
+    }
+}
+
+/// Changes collected by directory until the directory is quiet for
+/// [`Watch::directory_debounce`].
+#[derive(Debug, Default)]
+struct DirectoryBatches {
+    /// Time of the last change and the changes of each directory.
+    dirs: HashMap<PathBuf, (Instant, Vec<Change>)>,
+    /// A thread sends the batches once their directory is quiet.
+    flushing: bool,
+}
+
+#[derive(Debug, Clone)]
+struct SharedChild {
+    child: Arc<Mutex<Option<Child>>>,
+    /// Signals sent to terminate the child.
+    kill_escalation: Vec<KillStep>,
+    /// Log the time taken by the child to exit when terminated.
+    measure_shutdown: bool,
+}
+
+impl SharedChild {
+    fn new() -> Self {
+        Self {
+            child: Default::default(),
+            kill_escalation: Vec::new(),
+            measure_shutdown: false,
+        }
+    }
+
+    fn kill_escalation(mut self, steps: Vec<KillStep>) -> Self {
+        self.kill_escalation = steps;
+        self
+    }
+
+    fn measure_shutdown(mut self, enabled: bool) -> Self {
+        self.measure_shutdown = enabled;
+        self
+    }
+
+    fn replace(&mut self, child: impl Into<Option<Child>>) {
+        *self.child.lock().expect("not poisoned") = child.into();
+    }
+
+    /// Wait for the child to exit, terminating it after `timeout`.
+    fn wait(&mut self, timeout: Option<Duration>) -> ExitStatus {
+        let mut deadline = timeout.map(|x| Instant::now() + x);
+        loop {
+            let mut child = self.child.lock().expect("not poisoned");
+            match child.as_mut().map(|child| child.try_wait()) {
+                Some(Ok(Some(status))) => {
+                    break status;
+                }
+                Some(Ok(None)) => {
+                    drop(child);
+                    if deadline.is_some_and(|x| Instant::now() >= x) {
+                        log::error!(
+                            "Command timed out after {:.1?}",
+                            timeout.unwrap_or_default()
+                        );
+                        deadline = None;
+                        self.terminate();
+                        continue;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Some(Err(err)) => {
+                    log::error!("could not wait for child process: {err}");
+                    break Default::default();
+                }
+                None => {
+                    break Default::default();
+                }
+            }
+        }
+    }
+
+    /// The child exited with a failure.
+    fn has_crashed(&self) -> bool {
+        self.child
+            .lock()
+            .expect("not poisoned")
+            .as_mut()
+            .and_then(|child| child.try_wait().ok().flatten())
+            .is_some_and(|status| !status.success())
+    }
+
+    fn terminate(&mut self) {
+        if let Some(child) = self.child.lock().expect("not poisoned").as_mut() {
+            let killing_start = Instant::now();
+            // an exited child isn't measured
+            let running = matches!(child.try_wait(), Ok(None));
+            #[cfg(unix)]
+            {
+                let default = [KillStep {
+                    signal: libc::SIGTERM,
+                    delay: Duration::ZERO,
+                }];
+                let mut steps = if self.kill_escalation.is_empty() {
+                    default.to_vec()
+                } else {
+                    self.kill_escalation.clone()
+                };
+                steps.sort_by_key(|x| x.delay);
+                let last = steps.last().map_or(Duration::ZERO, |x| x.delay);
+
+                let mut steps = steps.into_iter().peekable();
+                while killing_start.elapsed() < last + Duration::from_secs(2) {
+                    if let Ok(Some(_)) = child.try_wait() {
+                        break;
+                    }
+                    while let Some(step) = steps.next_if(|x| killing_start.elapsed() >= x.delay) {
+                        log::trace!("sending signal {} to {}", step.signal, child.id());
+                        unsafe {
+                            libc::kill(child.id() as _, step.signal);
+                        }
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+
+            let killed = match child.try_wait() {
+                Ok(Some(_)) => false,
+                _ => {
+                    log::trace!("killing {}", child.id());
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    true
+                }
+            };
+            if self.measure_shutdown && running {
+                let elapsed = killing_start.elapsed();
+                if killed {
+                    log::info!("Shutdown: command killed after {elapsed:.1?}");
+                } else {
+                    log::info!("Shutdown: command exited in {elapsed:.1?}");
+                }
+            }
+        } else {
+            log::trace!("nothing to terminate");
+        }
+    }
+}
+
+/// A signal sent to terminate the command, see [`Watch::kill_escalation`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KillStep {
+    /// Number of the signal.
+    pub signal: i32,
+    /// Delay since the start of the termination.
+    pub delay: Duration,
+}
+
+/// Parse a `SIGNAL:DELAY` step, the signal being a name like `TERM` or
+/// `SIGTERM` or a number, and the delay a number of seconds.
+fn parse_kill_step(s: &str) -> Result<KillStep, String> {
+    let (signal, delay) = s
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| format!("`{s}` is not a `SIGNAL:DELAY` pair"))?;
+    let delay = delay
+        .parse::<f64>()
+        .ok()
+        .and_then(|x| Duration::try_from_secs_f64(x).ok())
+        .ok_or_else(|| format!("`{delay}` is not a number of seconds"))?;
+    let signal = match signal.parse::<i32>() {
+        Ok(signal) => signal,
+        Err(_) => signal_number(signal).ok_or_else(|| format!("unknown signal `{signal}`"))?,
+    };
+    Ok(KillStep { signal, delay })
+}
+
+/// Get the number of a signal from its name, with or without the `SIG`
+/// prefix.
+fn signal_number(name: &str) -> Option<i32> {
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    #[cfg(unix)]
+    {
+        [
+            ("HUP", libc::SIGHUP),
+            ("INT", libc::SIGINT),
+            ("QUIT", libc::SIGQUIT),
+            ("KILL", libc::SIGKILL),
+            ("USR1", libc::SIGUSR1),
+            ("USR2", libc::SIGUSR2),
+            ("TERM", libc::SIGTERM),
+        ]
+        .into_iter()
+        .find(|(x, _)| *x == name)
+        .map(|(_, signal)| signal)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = name;
+        None
+    }
+}
+
+/// Lower the scheduling priority of the process spawned by `command`.
+fn apply_niceness(command: &mut Command, nice: i32) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        let nice = nice.clamp(-20, 19);
+        // SAFETY: `setpriority` is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+
+        const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x0000_8000;
+        const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+        const IDLE_PRIORITY_CLASS: u32 = 0x0000_0040;
+
+        match nice {
+            10.. => command.creation_flags(IDLE_PRIORITY_CLASS),
+            1..=9 => command.creation_flags(BELOW_NORMAL_PRIORITY_CLASS),
+            0 => command,
+            _ => command.creation_flags(ABOVE_NORMAL_PRIORITY_CLASS),
+        };
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (command, nice);
+        log::warn!("--nice is not supported on this platform");
+    }
+}
+
+/// Parse a duration in milliseconds.
+fn parse_millis(s: &str) -> Result<Duration, String> {
+    s.parse()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("`{s}` is not a number of milliseconds"))
+}
+
+/// Parse a duration in seconds, possibly fractional.
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    s.parse()
+        .ok()
+        .and_then(|x| Duration::try_from_secs_f64(x).ok())
+        .ok_or_else(|| format!("`{s}` is not a number of seconds"))
+}
+
+/// Parse an octal file mode creation mask.
+fn parse_umask(s: &str) -> Result<u32, String> {
+    let s = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(s, 8) {
+        Ok(umask) if umask <= 0o777 => Ok(umask),
+        Ok(_) => Err("the umask must be between 0 and 777".to_string()),
+        Err(_) => Err(format!("`{s}` is not an octal number")),
+    }
+}
+
+/// Set the file mode creation mask of the process spawned by `command`.
+fn apply_umask(command: &mut Command, umask: u32) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        let umask = (umask & 0o777) as libc::mode_t;
+        // SAFETY: `umask` is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                libc::umask(umask);
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (command, umask);
+        log::warn!("--umask is not supported on this platform");
+    }
+}
+
+/// Clear the inherited environment of `command`, keeping the variables set
+/// explicitly.
+fn clear_env(command: &mut Command) {
+    let envs: Vec<(OsString, Option<OsString>)> = command
+        .get_envs()
+        .map(|(key, value)| (key.to_os_string(), value.map(OsStr::to_os_string)))
+        .collect();
+    command.env_clear();
+    for (key, value) in envs {
+        if let Some(value) = value {
+            command.env(key, value);
+        }
+    }
+}
+
+/// A command of a [`CommandList`] along with its options.
+#[derive(Debug)]
+struct CommandSpec {
+    command: Command,
+    /// The working directory has been set by the user.
+    explicit_dir: bool,
+    /// The standard output is read by the watch.
+    piped_stdout: bool,
+    /// Niceness of the command.
+    nice: Option<i32>,
+    /// File mode creation mask of the command.
+    umask: Option<u32>,
+    /// The command doesn't inherit the environment.
+    clean_env: bool,
+    /// The template tokens in the arguments are replaced.
+    template: bool,
+    /// Variables set on the command from the environment file.
+    env_file_keys: Vec<String>,
+    /// Redirection of the output installed on the command.
+    #[cfg(unix)]
+    redirect: Option<Arc<OutputRedirect>>,
+    /// The command is terminated when it runs longer than this.
+    timeout: Option<Duration>,
+    /// The command only runs when a changed path is in one of these paths.
+    filter: Option<Vec<PathBuf>>,
+    /// When the command runs depending on the previous ones.
+    condition: RunCondition,
+}
+
+impl From<Command> for CommandSpec {
+    fn from(command: Command) -> Self {
+        Self {
+            explicit_dir: command.get_current_dir().is_some(),
+            piped_stdout: false,
+            nice: None,
+            umask: None,
+            clean_env: false,
+            timeout: None,
+            filter: None,
+            condition: RunCondition::OnSuccess,
+            template: false,
+            env_file_keys: Vec::new(),
+            #[cfg(unix)]
+            redirect: None,
+            command,
+        }
+    }
+}
+
+impl CommandSpec {
+    /// Set the variables of the environment file on the command, removing
+    /// the ones set by a previous run that are no longer in the file.
+    ///
+    /// The variables set explicitly on the command are kept.
+    fn set_env_file_vars(&mut self, envs: &[(String, String)]) {
+        let previous = std::mem::take(&mut self.env_file_keys);
+        for key in &previous {
+            if envs.iter().any(|(x, _)| x == key) {
+                continue;
+            }
+            match env::var_os(key).filter(|_| !self.clean_env) {
+                Some(value) => self.command.env(key, value),
+                None => self.command.env_remove(key),
+            };
+        }
+        for (key, value) in envs {
+            let explicit = self.command.get_envs().any(|(x, _)| x == OsStr::new(key));
+            if !explicit || previous.contains(key) {
+                self.command.env(key, value);
+                self.env_file_keys.push(key.clone());
+            }
+        }
+    }
+}
+
+/// Options applied to the commands of a [`CommandList`] for a single run.
+#[derive(Debug, Default)]
+struct SpawnOptions {
+    /// Working directory of the commands that don't have their own.
+    dir: Option<PathBuf>,
+    /// Log the commands before spawning them.
+    show_command: bool,
+    /// Give a pseudo-terminal to the commands.
+    pty: bool,
+    /// Values of the template tokens.
+    template: TemplateValues,
+    /// What to do with the commands containing a token without value.
+    on_unresolved_token: UnresolvedToken,
+    /// Standard input of the commands.
+    stdin: ChildStdin,
+    /// Directory where the output of the commands is copied.
+    tee: Option<PathBuf>,
+    /// Start the lines of output with the name of the command.
+    prefix_output: bool,
+    /// Variables read from the environment file.
+    envs: Vec<(String, String)>,
+    /// Set when the run is replaced by a new one, the remaining commands are
+    /// skipped.
+    cancelled: Arc<AtomicBool>,
+    /// Keep the output copied from the commands in memory instead of writing
+    /// it to the streams of the watch.
+    capture: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+/// A command spawned by [`CommandList::spawn_in`], given to its callback.
+#[derive(Debug)]
+struct Spawned {
+    /// Time after which the command is terminated.
+    timeout: Option<Duration>,
+    /// Where the output read from the command's standard output is copied.
+    stdout: OutputSink,
+    /// Where the output read from the command's standard error is copied.
+    stderr: OutputSink,
+}
+
+/// Stream of the watch receiving the output copied from the commands.
+#[derive(Clone, Debug)]
+enum OutputStream {
+    Stdout,
+    Stderr,
+    /// Kept in memory instead.
+    Buffer(Arc<Mutex<Vec<u8>>>),
+}
+
+/// Where the output copied from a command goes: a stream of the watch and
+/// the output file of the run, the lines starting with the name of the
+/// command when given.
+#[derive(Clone, Debug)]
+struct OutputSink {
+    stream: OutputStream,
+    file: Option<Arc<Mutex<fs::File>>>,
+    prefix: Option<String>,
+}
+
+impl OutputSink {
+    /// Copy `data`, made of whole lines when prefixed.
+    fn write(&self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let mut buf = Vec::new();
+        let data = match &self.prefix {
+            Some(prefix) => {
+                for line in data.split_inclusive(|x| *x == b'\n') {
+                    buf.extend_from_slice(prefix.as_bytes());
+                    buf.extend_from_slice(line);
+                }
+                if !buf.ends_with(b"\n") {
+                    buf.push(b'\n');
+                }
+                &buf
+            }
+            None => data,
+        };
+        // a single write so the lines of parallel commands don't mix
+        match &self.stream {
+            OutputStream::Stdout => {
+                let mut out = io::stdout();
+                let _ = out.write_all(data);
+                let _ = out.flush();
+            }
+            OutputStream::Stderr => {
+                let _ = io::stderr().write_all(data);
+            }
+            OutputStream::Buffer(buf) => buf.lock().expect("not poisoned").extend_from_slice(data),
+        }
+        if let Some(file) = &self.file {
+            let _ = file.lock().expect("not poisoned").write_all(data);
+        }
+    }
+}
+
+/// Create the file receiving the output of the commands of a run in `dir`.
+fn create_tee_file(dir: &Path) -> io::Result<fs::File> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("run-{}.log", format_timestamp(SystemTime::now())));
+    fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// How long the output of a command is still copied once it exits.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Copy everything read from `reader` to `sink`, line by line when the lines
+/// are prefixed.
+fn spawn_pump(reader: impl io::Read + Send + 'static, sink: OutputSink) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut reader = io::BufReader::new(reader);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match &sink.prefix {
+                Some(_) => match reader.read_until(b'\n', &mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                },
+                None => {
+                    let n = match reader.fill_buf() {
+                        Ok([]) | Err(_) => break,
+                        Ok(chunk) => {
+                            buf.extend_from_slice(chunk);
+                            chunk.len()
+                        }
+                    };
+                    reader.consume(n);
+                }
+            }
+            sink.write(&buf);
+        }
+    })
+}
+
+/// Name the commands in the output, adding their position in the list to
+/// the name of their program when it's not unique.
+fn output_prefixes(specs: &[CommandSpec]) -> Vec<String> {
+    let names: Vec<String> = specs
+        .iter()
+        .map(|spec| {
+            let program = Path::new(spec.command.get_program());
+            program
+                .file_name()
+                .unwrap_or(program.as_os_str())
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            if names.iter().filter(|x| *x == name).count() > 1 {
+                format!("[{name}#{}] ", i + 1)
+            } else {
+                format!("[{name}] ")
+            }
+        })
+        .collect()
+}
+
+/// Redirection of the standard output and error of a command, applied in the
+/// child only when they are inherited from the watch so the streams
+/// configured on the command are kept.
+#[cfg(unix)]
+#[derive(Debug, Default)]
+struct OutputRedirect {
+    /// File descriptors replacing the standard output and error, or -1.
+    fds: [std::sync::atomic::AtomicI32; 2],
+    /// Device and inode of the standard output and error of the watch.
+    inherited: [(std::sync::atomic::AtomicU64, std::sync::atomic::AtomicU64); 2],
+}
+
+#[cfg(unix)]
+impl OutputRedirect {
+    /// Install a redirection on `command`, doing nothing until it's
+    /// [`set`](Self::set).
+    fn install(command: &mut Command) -> Arc<Self> {
+        use std::os::unix::process::CommandExt;
+
+        let redirect = Arc::new(Self::default());
+        redirect.set([None, None]);
+        let shared = redirect.clone();
+        // SAFETY: `fstat` and `dup2` are async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                for (target, (fd, (dev, ino))) in [libc::STDOUT_FILENO, libc::STDERR_FILENO]
+                    .into_iter()
+                    .zip(shared.fds.iter().zip(&shared.inherited))
+                {
+                    let fd = fd.load(Ordering::SeqCst);
+                    let inherited = (dev.load(Ordering::SeqCst), ino.load(Ordering::SeqCst));
+                    if fd >= 0 && file_id(target) == Some(inherited) && libc::dup2(fd, target) == -1
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        redirect
+    }
+
+    /// Redirect the standard output and error of the next spawned child to
+    /// the given file descriptors, if they are inherited.
+    fn set(&self, fds: [Option<libc::c_int>; 2]) {
+        for ((source, target), (fd, (dev, ino))) in [libc::STDOUT_FILENO, libc::STDERR_FILENO]
+            .into_iter()
+            .zip(fds)
+            .zip(self.fds.iter().zip(&self.inherited))
+        {
+            let id = file_id(source);
+            let (x, y) = id.unwrap_or_default();
+            dev.store(x, Ordering::SeqCst);
+            ino.store(y, Ordering::SeqCst);
+            fd.store(
+                target.filter(|_| id.is_some()).unwrap_or(-1),
+                Ordering::SeqCst,
+            );
+        }
+    }
+}
+
+/// Device and inode of the file open as `fd`.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn file_id(fd: libc::c_int) -> Option<(u64, u64)> {
+    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    (unsafe { libc::fstat(fd, &mut stat) } == 0).then_some((stat.st_dev as u64, stat.st_ino as u64))
+}
+
+/// Create a pipe whose ends are closed on exec, returning its read and write
+/// ends.
+#[cfg(unix)]
+fn output_pipe() -> io::Result<(fs::File, std::os::fd::OwnedFd)> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    let mut fds = [-1; 2];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for fd in fds {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        Ok((
+            OwnedFd::from_raw_fd(fds[0]).into(),
+            OwnedFd::from_raw_fd(fds[1]),
+        ))
+    }
+}
+
+/// Size of the terminal of the watch, if any.
+#[cfg(unix)]
+fn terminal_size() -> Option<libc::winsize> {
+    [libc::STDOUT_FILENO, libc::STDERR_FILENO, libc::STDIN_FILENO]
+        .into_iter()
+        .find_map(|fd| {
+            let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+            let res = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) };
+            (res == 0 && size.ws_row > 0 && size.ws_col > 0).then_some(size)
+        })
+}
+
+/// Pseudo-terminal used as the standard output and error of a child process.
+#[cfg(unix)]
+struct Pty {
+    master: fs::File,
+}
+
+#[cfg(unix)]
+impl Pty {
+    /// Allocate a pseudo-terminal of the given size, returning it along with
+    /// the terminal's side given to the child.
+    fn open(size: Option<libc::winsize>) -> io::Result<(Self, std::os::fd::OwnedFd)> {
+        use std::os::fd::{FromRawFd, OwnedFd};
+
+        let mut master = -1;
+        let mut slave = -1;
+        let mut size = size;
+        let (master, slave) = unsafe {
+            if libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                size.as_mut()
+                    .map_or(std::ptr::null_mut(), |x| x as *mut libc::winsize),
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave))
+        };
+
+        // keep the line endings untouched
+        unsafe {
+            use std::os::fd::AsRawFd;
+
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) == 0 {
+                termios.c_oflag &= !libc::ONLCR;
+                libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios);
+            }
+        }
+
+        Ok((
+            Self {
+                master: master.into(),
+            },
+            slave,
+        ))
+    }
+
+    /// Copy the output of the child to `sink`, resizing the pseudo-terminal
+    /// and notifying the child `pid` when the terminal of the watch is
+    /// resized, until the returned guard is dropped once the child is reaped.
+    fn forward(self, pid: u32, sink: OutputSink) -> (ResizeForwarding, thread::JoinHandle<()>) {
+        use std::os::fd::AsRawFd;
+
+        let done = Arc::new(AtomicBool::new(false));
+        if let Ok(master) = self.master.try_clone() {
+            let done = done.clone();
+            thread::spawn(move || {
+                // the handler of the embedding application is still called
+                let _resize = WatchSignal::Resize.handle();
+                let mut resizes = WatchSignal::Resize.received();
+                while !done.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(100));
+                    let current = WatchSignal::Resize.received();
+                    if current == resizes {
+                        continue;
+                    }
+                    resizes = current;
+                    // the pid might have been reused once the child is reaped
+                    if done.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Some(size) = terminal_size() {
+                        unsafe {
+                            libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size);
+                            libc::kill(pid as _, libc::SIGWINCH);
+                        }
+                    }
+                }
+            });
+        }
+        // ends with an error once the child's side is closed
+        let pump = spawn_pump(self.master, sink);
+        (ResizeForwarding(done), pump)
+    }
+}
+
+/// Stops notifying a child in a pseudo-terminal of the resizes when dropped.
+#[cfg(unix)]
+struct ResizeForwarding(Arc<AtomicBool>);
+
+#[cfg(unix)]
+impl Drop for ResizeForwarding {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Format a command like it would be typed in a shell.
+fn format_command(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|x| {
+            let x = x.to_string_lossy();
+            if !x.is_empty()
+                && x.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_=+./:,@%".contains(c))
+            {
+                x.into_owned()
+            } else {
+                format!("'{}'", x.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A list of commands to run.
 #[derive(Debug, Clone)]
-struct SharedChild {
-    child: Arc<Mutex<Option<Child>>>,
+pub struct CommandList {
+    commands: Arc<Mutex<Vec<CommandSpec>>>,
+}
+
+/// Description of a command of a [`CommandList`], returned by
+/// [`CommandList::describe`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandDescription {
+    /// Program run by the command.
+    pub program: OsString,
+    /// Arguments given to the program.
+    pub args: Vec<OsString>,
+    /// Environment variables set explicitly, `None` when removed.
+    pub envs: Vec<(OsString, Option<OsString>)>,
+    /// Working directory set explicitly.
+    pub current_dir: Option<PathBuf>,
+}
+
+/// When a command of a [`CommandList`] runs, depending on the commands before
+/// it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunCondition {
+    /// Run only if none of the previous commands failed.
+    #[default]
+    OnSuccess,
+    /// Run only if one of the previous commands failed.
+    OnFailure,
+    /// Run whether the previous commands failed or not.
+    Always,
+}
+
+impl RunCondition {
+    fn is_met(self, failed: bool) -> bool {
+        match self {
+            Self::OnSuccess => !failed,
+            Self::OnFailure => failed,
+            Self::Always => true,
+        }
+    }
+}
+
+/// Build a command from its program followed by its arguments.
+fn command_from_argv(argv: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Command {
+    let mut argv = argv.into_iter();
+    let program = argv.next().expect("argv has a program");
+    let mut command = Command::new(program);
+    command.args(argv);
+    command
+}
+
+impl From<Command> for CommandList {
+    fn from(command: Command) -> Self {
+        Self {
+            commands: Arc::new(Mutex::new(vec![command.into()])),
+        }
+    }
+}
+
+impl From<Vec<Command>> for CommandList {
+    fn from(commands: Vec<Command>) -> Self {
+        Self {
+            commands: Arc::new(Mutex::new(commands.into_iter().map(Into::into).collect())),
+        }
+    }
+}
+
+impl<const SIZE: usize> From<[Command; SIZE]> for CommandList {
+    fn from(commands: [Command; SIZE]) -> Self {
+        Self {
+            commands: Arc::new(Mutex::new(commands.into_iter().map(Into::into).collect())),
+        }
+    }
+}
+
+impl CommandList {
+    /// Create a list of a single command from its program followed by its
+    /// arguments.
+    ///
+    /// ```rust,no_run
+    /// use xtask_watch::CommandList;
+    ///
+    /// let commands = CommandList::from_argv(["cargo", "check", "--workspace"]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `argv` is empty.
+    pub fn from_argv(argv: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Self {
+        command_from_argv(argv).into()
+    }
+
+    /// Create a list of commands, each given by its program followed by its
+    /// arguments.
+    ///
+    /// ```rust,no_run
+    /// use xtask_watch::CommandList;
+    ///
+    /// let commands = CommandList::from_argvs([
+    ///     vec!["cargo", "check"],
+    ///     vec!["cargo", "test", "--workspace"],
+    /// ]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if any argv is empty.
+    pub fn from_argvs(
+        argvs: impl IntoIterator<Item = impl IntoIterator<Item = impl AsRef<OsStr>>>,
+    ) -> Self {
+        argvs
+            .into_iter()
+            .map(command_from_argv)
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    /// Add a command, given by its program followed by its arguments, whose
+    /// template tokens like `{file}` are replaced on every run.
+    ///
+    /// See [`Watch::on_unresolved_token`] for the tokens available. Only the
+    /// commands added this way are templated, the arguments of the others are
+    /// passed as is. The command is built again from its program and the
+    /// replaced arguments on every run, which is why it can't be given as a
+    /// [`Command`] with its own environment or standard streams.
+    ///
+    /// ```rust,no_run
+    /// use xtask_watch::CommandList;
+    ///
+    /// let commands = CommandList::from_argv(["cargo", "fmt"])
+    ///     .templated(["cargo", "test", "{packages}"]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `argv` is empty.
+    pub fn templated(self, argv: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Self {
+        let mut spec = CommandSpec::from(command_from_argv(argv));
+        spec.template = true;
+        self.commands.lock().expect("not poisoned").push(spec);
+        self
+    }
+
+    /// Describe the commands of the list, without running them.
+    ///
+    /// The template tokens, like `{file}`, are not expanded.
+    pub fn describe(&self) -> Vec<CommandDescription> {
+        self.commands
+            .lock()
+            .expect("not poisoned")
+            .iter()
+            .map(|spec| CommandDescription {
+                program: spec.command.get_program().to_os_string(),
+                args: spec.command.get_args().map(OsStr::to_os_string).collect(),
+                envs: spec
+                    .command
+                    .get_envs()
+                    .map(|(key, value)| (key.to_os_string(), value.map(OsStr::to_os_string)))
+                    .collect(),
+                current_dir: spec.command.get_current_dir().map(Path::to_path_buf),
+            })
+            .collect()
+    }
+
+    /// Returns `true` if the list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.commands.lock().expect("not poisoned").is_empty()
+    }
+
+    /// Add a command that only runs when a changed path is in one of `paths`.
+    ///
+    /// Relative paths are relative to the workspace root. The command always
+    /// runs on the first run.
+    ///
+    /// ```rust,no_run
+    /// use std::process::Command;
+    /// use xtask_watch::CommandList;
+    ///
+    /// let commands = CommandList::from(Vec::new())
+    ///     .when_changed(Command::new("npm"), ["frontend"])
+    ///     .when_changed(Command::new("cargo"), ["backend"]);
+    /// ```
+    pub fn when_changed(
+        self,
+        command: Command,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Self {
+        let filter = paths
+            .into_iter()
+            .map(|path| {
+                let path = metadata().workspace_root.as_std_path().join(path);
+                path.canonicalize().unwrap_or(path)
+            })
+            .collect();
+        let mut spec = CommandSpec::from(command);
+        spec.filter = Some(filter);
+        self.commands.lock().expect("not poisoned").push(spec);
+        self
+    }
+
+    /// Add a command that is terminated when it runs longer than `timeout`.
+    ///
+    /// The timeout is enforced by the watch, not by [`spawn`](Self::spawn).
+    ///
+    /// ```rust,no_run
+    /// use std::{process::Command, time::Duration};
+    /// use xtask_watch::CommandList;
+    ///
+    /// let mut fmt = Command::new("cargo");
+    /// fmt.arg("fmt");
+    /// let mut test = Command::new("cargo");
+    /// test.arg("test");
+    ///
+    /// let commands = CommandList::from(Vec::new())
+    ///     .with_timeout(fmt, Duration::from_secs(5))
+    ///     .with_timeout(test, Duration::from_secs(300));
+    /// ```
+    pub fn with_timeout(self, command: Command, timeout: Duration) -> Self {
+        let mut spec = CommandSpec::from(command);
+        spec.timeout = Some(timeout);
+        self.commands.lock().expect("not poisoned").push(spec);
+        self
+    }
+
+    /// Add a command that only runs when `condition` is met by the previous
+    /// commands.
+    ///
+    /// The other commands use [`RunCondition::OnSuccess`]: a failure skips
+    /// them, but not the following commands running on failure or always.
+    ///
+    /// ```rust,no_run
+    /// use std::process::Command;
+    /// use xtask_watch::{CommandList, RunCondition};
+    ///
+    /// let mut build = Command::new("cargo");
+    /// build.arg("build");
+    /// let mut test = Command::new("cargo");
+    /// test.arg("test");
+    /// let mut notify = Command::new("notify-send");
+    /// notify.arg("Build failed");
+    ///
+    /// let commands = CommandList::from(Vec::new())
+    ///     .with_condition(build, RunCondition::Always)
+    ///     .with_condition(test, RunCondition::OnSuccess)
+    ///     .with_condition(notify, RunCondition::OnFailure);
+    /// ```
+    pub fn with_condition(self, command: Command, condition: RunCondition) -> Self {
+        let mut spec = CommandSpec::from(command);
+        spec.condition = condition;
+        self.commands.lock().expect("not poisoned").push(spec);
+        self
+    }
+
+    /// Spawn each command of the list one after the other.
+    ///
+    /// The callback returns `false` when the command failed, the following
+    /// commands then only run if their [`RunCondition`] allows it. The caller
+    /// is responsible to wait the commands.
+    pub fn spawn(&mut self, mut callback: impl FnMut(io::Result<Child>) -> bool) {
+        self.spawn_in(&SpawnOptions::default(), |res, _| callback(res))
+    }
+
+    /// Spawn each command of the list one after the other, applying the
+    /// `options` of the run. The callback also gets the timeout of the
+    /// command and where to copy the output it reads from it.
+    fn spawn_in(
+        &mut self,
+        options: &SpawnOptions,
+        mut callback: impl FnMut(io::Result<Child>, &Spawned) -> bool,
+    ) {
+        let tee_file = options
+            .tee
+            .as_deref()
+            .and_then(|dir| match create_tee_file(dir) {
+                Ok(file) => Some(Arc::new(Mutex::new(file))),
+                Err(err) => {
+                    log::error!("cannot create output file in {}: {err}", dir.display());
+                    None
+                }
+            });
+
+        let mut specs = self.commands.lock().expect("not poisoned");
+        let prefixes = if options.prefix_output {
+            output_prefixes(&specs)
+        } else {
+            Vec::new()
+        };
+        let mut failed = false;
+        for (index, spec) in specs.iter_mut().enumerate() {
+            if options.cancelled.load(Ordering::SeqCst) {
+                break;
+            }
+            if !spec.condition.is_met(failed) {
+                log::trace!(
+                    "Skipping `{}`: its condition is not met",
+                    format_command(&spec.command)
+                );
+                continue;
+            }
+            let changes = &options.template.files;
+            if let Some(filter) = &spec.filter {
+                if !changes.is_empty()
+                    && !changes
+                        .iter()
+                        .any(|change| filter.iter().any(|path| change.starts_with(path)))
+                {
+                    log::trace!(
+                        "Skipping `{}`: no change in its paths",
+                        format_command(&spec.command)
+                    );
+                    continue;
+                }
+            }
+
+            spec.set_env_file_vars(&options.envs);
+            let mut expanded;
+            let command = if spec.template {
+                let args: Vec<&OsStr> = spec.command.get_args().collect();
+                let empty = options.on_unresolved_token == UnresolvedToken::Empty;
+                let Some(args) = options.template.expand(&args, empty) else {
+                    log::info!(
+                        "Skipping `{}`: a token has no value",
+                        format_command(&spec.command)
+                    );
+                    continue;
+                };
+                expanded = rebuild_command(&spec.command, args);
+                if spec.piped_stdout {
+                    expanded.stdout(Stdio::piped());
+                }
+                if let Some(nice) = spec.nice {
+                    apply_niceness(&mut expanded, nice);
+                }
+                if let Some(umask) = spec.umask {
+                    apply_umask(&mut expanded, umask);
+                }
+                if spec.clean_env {
+                    clear_env(&mut expanded);
+                }
+                &mut expanded
+            } else {
+                &mut spec.command
+            };
+
+            if let Some(dir) = options.dir.as_ref().filter(|_| !spec.explicit_dir) {
+                command.current_dir(dir);
+            }
+            if options.show_command {
+                log::info!("Running `{}`", format_command(command));
+            }
+            if options.stdin == ChildStdin::Null {
+                command.stdin(Stdio::null());
+            }
+
+            #[cfg(unix)]
+            let pty = if options.pty {
+                match Pty::open(terminal_size()) {
+                    Ok(pty) => Some(pty),
+                    Err(err) => {
+                        log::error!("cannot allocate a pseudo-terminal: {err}");
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            let tee_file = tee_file.as_ref();
+            let prefix = prefixes.get(index);
+            let pumping = tee_file.is_some() || prefix.is_some();
+            let sink = |stream| OutputSink {
+                stream: options.capture.clone().map_or(stream, OutputStream::Buffer),
+                file: tee_file.cloned(),
+                prefix: prefix.cloned(),
+            };
+            let spawned = Spawned {
+                timeout: spec.timeout,
+                stdout: sink(OutputStream::Stdout),
+                stderr: sink(OutputStream::Stderr),
+            };
+
+            // the output is redirected in the child, keeping the streams
+            // configured on the command
+            #[cfg(unix)]
+            let mut pipes = Vec::new();
+            #[cfg(unix)]
+            let redirect = if pty.is_none() && !pumping {
+                None
+            } else if spec.template {
+                // a templated command is built again on every run
+                Some(OutputRedirect::install(command))
+            } else {
+                Some(
+                    spec.redirect
+                        .get_or_insert_with(|| OutputRedirect::install(command))
+                        .clone(),
+                )
+            };
+            #[cfg(unix)]
+            if let Some(redirect) = &redirect {
+                use std::os::fd::AsRawFd;
+
+                if let Some((_, slave)) = &pty {
+                    redirect.set([Some(slave.as_raw_fd()); 2]);
+                } else {
+                    match (output_pipe(), output_pipe()) {
+                        (Ok(stdout), Ok(stderr)) => {
+                            redirect.set([Some(stdout.1.as_raw_fd()), Some(stderr.1.as_raw_fd())]);
+                            pipes = vec![stdout, stderr];
+                        }
+                        (Err(err), _) | (_, Err(err)) => {
+                            log::error!("cannot create a pipe for the output: {err}");
+                        }
+                    }
+                }
+            }
+            #[cfg(not(unix))]
+            if pumping {
+                command.stderr(Stdio::piped());
+                if !spec.piped_stdout {
+                    command.stdout(Stdio::piped());
+                }
+            }
+
+            #[cfg_attr(unix, allow(unused_mut))]
+            let mut res = command.spawn().map_err(|err| {
+                // a missing working directory is reported the same way
+                if err.kind() == io::ErrorKind::NotFound
+                    && command.get_current_dir().map_or(true, Path::exists)
+                {
+                    io::Error::new(
+                        err.kind(),
+                        ProgramNotFound(command.get_program().to_os_string()),
+                    )
+                } else {
+                    err
+                }
+            });
+
+            let mut pumps = Vec::new();
+            #[cfg(unix)]
+            {
+                if let Some(redirect) = &redirect {
+                    redirect.set([None, None]);
+                }
+                // the write ends are only held by the child now
+                let mut readers = pipes.into_iter().map(|(reader, _)| reader);
+                if let (Some(stdout), Some(stderr), true) =
+                    (readers.next(), readers.next(), res.is_ok())
+                {
+                    pumps.push(spawn_pump(stderr, spawned.stderr.clone()));
+                    pumps.push(spawn_pump(stdout, spawned.stdout.clone()));
+                }
+            }
+            // the streams can't be read back from the command, they are
+            // replaced on the platforms without redirection in the child
+            #[cfg(not(unix))]
+            if pumping {
+                command.stderr(Stdio::inherit());
+                if !spec.piped_stdout {
+                    command.stdout(Stdio::inherit());
+                }
+                if let Ok(child) = &mut res {
+                    if let Some(stderr) = child.stderr.take() {
+                        pumps.push(spawn_pump(stderr, spawned.stderr.clone()));
+                    }
+                    if !spec.piped_stdout {
+                        if let Some(stdout) = child.stdout.take() {
+                            pumps.push(spawn_pump(stdout, spawned.stdout.clone()));
+                        }
+                    }
+                }
+            }
+
+            #[cfg(unix)]
+            let mut resizes = None;
+            #[cfg(unix)]
+            if let Some((pty, slave)) = pty {
+                // release the terminal's side given to the child
+                drop(slave);
+                if let Ok(child) = &res {
+                    let (forwarding, pump) = pty.forward(child.id(), spawned.stdout.clone());
+                    resizes = Some(forwarding);
+                    pumps.push(pump);
+                }
+            }
+
+            let succeeded = callback(res, &spawned);
+            // the child is reaped
+            #[cfg(unix)]
+            drop(resizes);
+            // the output is entirely copied once the command exits, unless a
+            // process it started keeps it open
+            let deadline = Instant::now() + OUTPUT_DRAIN_TIMEOUT;
+            for pump in pumps {
+                while !pump.is_finished() && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                if pump.is_finished() {
+                    let _ = pump.join();
+                }
+            }
+            failed |= !succeeded;
+        }
+    }
+
+    /// Run the commands of the list with the given niceness.
+    fn set_niceness(&self, nice: i32) {
+        for spec in self.commands.lock().expect("not poisoned").iter_mut() {
+            spec.nice = Some(nice);
+            apply_niceness(&mut spec.command, nice);
+        }
+    }
+
+    /// Run the commands of the list with the given file mode creation mask.
+    fn set_umask(&self, umask: u32) {
+        for spec in self.commands.lock().expect("not poisoned").iter_mut() {
+            spec.umask = Some(umask);
+            apply_umask(&mut spec.command, umask);
+        }
+    }
+
+    /// Set an environment variable on the commands of the list that don't set
+    /// it explicitly.
+    fn default_env(&self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) {
+        for spec in self.commands.lock().expect("not poisoned").iter_mut() {
+            if !spec.command.get_envs().any(|(x, _)| x == key.as_ref()) {
+                spec.command.env(&key, &value);
+            }
+        }
+    }
+
+    /// Run the commands of the list without inheriting the environment.
+    fn clear_env(&self) {
+        for spec in self.commands.lock().expect("not poisoned").iter_mut() {
+            spec.clean_env = true;
+            clear_env(&mut spec.command);
+        }
+    }
+
+    /// Add `--message-format=json` to the cargo commands of the list and pipe
+    /// their output.
+    fn use_cargo_json(&self) {
+        for spec in self.commands.lock().expect("not poisoned").iter_mut() {
+            let command = &mut spec.command;
+            if Path::new(command.get_program()).file_stem() != Some(OsStr::new("cargo"))
+                || command
+                    .get_args()
+                    .any(|x| x.to_string_lossy().starts_with("--message-format"))
+            {
+                continue;
+            }
+
+            let mut args: Vec<&OsStr> = command.get_args().collect();
+            match args.iter().position(|x| *x == "--") {
+                None => {
+                    command.arg("--message-format=json");
+                }
+                // templated commands are rebuilt on every run anyway
+                Some(position) if spec.template => {
+                    args.insert(position, OsStr::new("--message-format=json"));
+                    *command = rebuild_command(command, args);
+                }
+                Some(_) => {
+                    log::warn!(
+                        "Cannot use --cargo-json with `{}`: it has arguments after `--`",
+                        format_command(command)
+                    );
+                    continue;
+                }
+            }
+            command.stdout(Stdio::piped());
+            spec.piped_stdout = true;
+        }
+    }
+
+    /// Run all the commands sequentially using [`std::process::Command::status`], skipping the
+    /// ones whose [`RunCondition`] isn't met, and return the status of the first failure.
+    pub fn status(&mut self) -> io::Result<ExitStatus> {
+        let mut status = ExitStatus::default();
+        for spec in self.commands.lock().expect("not poisoned").iter_mut() {
+            if !spec.condition.is_met(!status.success()) {
+                continue;
+            }
+            let exit_status = spec.command.status()?;
+            if status.success() {
+                status = exit_status;
+            }
+        }
+        Ok(status)
+    }
 }
 
-impl SharedChild {
-    fn new() -> Self {
-        Self {
-            child: Default::default(),
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Inject synthetic events in a [`WatchEventHandler`], without any actual
+    /// watcher.
+    struct EventProbe {
+        handler: WatchEventHandler,
+        rx: mpsc::Receiver<Trigger>,
+    }
+
+    impl EventProbe {
+        fn new(watch: Watch) -> Self {
+            let (tx, rx) = mpsc::channel();
+            Self {
+                handler: WatchEventHandler::new(watch, tx),
+                rx,
+            }
+        }
+
+        /// Return `true` if the event triggered a rerun.
+        fn send(&mut self, kind: notify::EventKind, path: impl AsRef<Path>) -> bool {
+            self.handler
+                .handle_event(Ok(Event::new(kind).add_path(path.as_ref().to_path_buf())));
+            self.rx.try_iter().count() > 0
+        }
+    }
+
+    fn create() -> notify::EventKind {
+        notify::EventKind::Create(notify::event::CreateKind::File)
+    }
+
+    #[test]
+    fn register_moved_directories() {
+        use notify::event::{ModifyKind, RenameMode};
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("created")).unwrap();
+        std::fs::create_dir(root.join("moved")).unwrap();
+
+        let mut probe = EventProbe::new(Watch::default().watch_path(&root));
+        let (dir_tx, dir_rx) = mpsc::channel();
+        probe.handler.new_dirs = Some(dir_tx);
+        probe.send(create(), root.join("created"));
+        probe.send(
+            notify::EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+            root.join("moved"),
+        );
+        probe
+            .handler
+            .handle_event(Ok(Event::new(notify::EventKind::Modify(ModifyKind::Name(
+                RenameMode::Both,
+            )))
+            .add_path(root.join("old"))
+            .add_path(root.join("moved"))));
+        probe.send(
+            notify::EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+            root.join("moved"),
+        );
+        assert_eq!(
+            dir_rx.try_iter().collect::<Vec<_>>(),
+            [root.join("created"), root.join("moved"), root.join("moved")]
+        );
+    }
+
+    fn modified(path: impl Into<PathBuf>) -> Change {
+        Change::new(path, ChangeKind::Modified)
+    }
+
+    /// Logger keeping the messages logged by every test.
+    struct CapturedLogs;
+
+    static CAPTURED_LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    impl log::Log for CapturedLogs {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED_LOGS
+                .lock()
+                .unwrap()
+                .push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_logs() {
+        if log::set_logger(&CapturedLogs).is_ok() {
+            log::set_max_level(log::LevelFilter::Info);
+        }
+    }
+
+    #[test]
+    fn exclude_relative_path() {
+        let watch = Watch {
+            debounce: Default::default(),
+            watch_paths: Vec::new(),
+            exclude_paths: Vec::new(),
+            workspace_exclude_paths: vec![PathBuf::from("src/watch.rs")],
+            ..Default::default()
+        };
+
+        assert!(watch.is_excluded_path(
+            metadata()
+                .workspace_root
+                .join("src")
+                .join("watch.rs")
+                .as_std_path()
+        ));
+        assert!(!watch.is_excluded_path(metadata().workspace_root.join("src").as_std_path()));
+    }
+
+    #[test]
+    fn exclude_bare_name() {
+        let watch = Watch::default()
+            .exclude_path("Cargo.lock")
+            .exclude_path("/workspace/docs");
+
+        assert!(watch.is_excluded_path(Path::new("/workspace/Cargo.lock")));
+        assert!(watch.is_excluded_path(Path::new("/workspace/examples/demo/Cargo.lock")));
+        assert!(!watch.is_excluded_path(Path::new("/workspace/Cargo.lock.bak")));
+        assert!(watch.is_excluded_path(Path::new("/workspace/docs/index.md")));
+        assert!(!watch.is_excluded_path(Path::new("/workspace/examples/docs/index.md")));
+        assert!(!is_bare_name(Path::new("./Cargo.lock")));
+        assert!(!is_bare_name(Path::new("src/Cargo.lock")));
+    }
+
+    #[test]
+    fn workspace_sources() {
+        let metadata = cargo_metadata::MetadataCommand::new()
+            .current_dir(metadata().workspace_root.join("examples").join("demo"))
+            .no_deps()
+            .exec()
+            .unwrap();
+        let mut dirs = workspace_source_dirs(&metadata);
+        dirs.sort();
+
+        assert_eq!(
+            dirs,
+            vec![
+                metadata
+                    .workspace_root
+                    .join("my-project")
+                    .join("src")
+                    .into_std_path_buf(),
+                metadata
+                    .workspace_root
+                    .join("xtask")
+                    .join("src")
+                    .into_std_path_buf(),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait_for_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("counter");
+
+        let command = || {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(format!(
+                "n=$(cat {0} 2>/dev/null || echo 0); n=$((n + 1)); echo $n > {0}; [ $n -ge 3 ]",
+                counter.display(),
+            ));
+            command
+        };
+
+        assert!(Watch::default()
+            .wait_for(command(), 3)
+            .wait_for_command()
+            .is_ok());
+        assert_eq!(std::fs::read_to_string(&counter).unwrap().trim(), "3");
+
+        std::fs::remove_file(&counter).unwrap();
+        assert!(Watch::default()
+            .wait_for(command(), 1)
+            .wait_for_command()
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bell_on_failure() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut out = Vec::new();
+        ring_bell(&mut out, ExitStatus::from_raw(0)).unwrap();
+        assert!(out.is_empty());
+
+        ring_bell(&mut out, ExitStatus::from_raw(1 << 8)).unwrap();
+        assert_eq!(out, b"\x07");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serve_not_started_on_build_failure() {
+        use std::os::unix::net::UnixStream;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let builds = root.join(".builds");
+        let served = root.join(".served");
+        let socket = root.join(".control.sock");
+
+        let mut build = Command::new("sh");
+        build
+            .arg("-c")
+            .arg(format!("echo build >> {}; exit 1", builds.display()));
+        let mut serve = Command::new("touch");
+        serve.arg(&served);
+
+        let watch = Watch::default()
+            .watch_path(&root)
+            .debounce(Duration::from_millis(100))
+            .control_socket(&socket);
+        let handle = thread::spawn(move || watch.build_then_serve(build, serve));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !socket.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        let mut replies = io::BufRead::lines(io::BufReader::new(stream.try_clone().unwrap()));
+        let mut send = |command: &str| {
+            writeln!(stream, "{command}").unwrap();
+            replies.next().unwrap().unwrap()
+        };
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(send("rerun"), "ok");
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(send("stop"), "ok");
+        handle.join().unwrap().unwrap();
+
+        assert_eq!(std::fs::read_to_string(&builds).unwrap(), "build\nbuild\n");
+        assert!(!served.exists());
+    }
+
+    #[test]
+    fn should_rerun_veto() {
+        let watch = Watch::default().should_rerun(|paths| {
+            !paths
+                .iter()
+                .all(|x| x.extension().is_some_and(|ext| ext == "md"))
+        });
+
+        assert!(!watch.is_rerun_accepted(&[modified("README.md")]));
+        assert!(watch.is_rerun_accepted(&[modified("README.md"), modified("src/lib.rs")]));
+        assert!(Watch::default().is_rerun_accepted(&[modified("README.md")]));
+    }
+
+    #[test]
+    fn ready_when_delay() {
+        let checks = Arc::new(AtomicUsize::new(0));
+        let watch = Watch::default().ready_when({
+            let checks = checks.clone();
+            move || checks.fetch_add(1, Ordering::SeqCst) > 0
+        });
+
+        let (tx, rx) = mpsc::channel();
+        tx.send(vec![modified("a")].into()).unwrap();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            tx.send(vec![modified("b")].into()).unwrap();
+        });
+
+        let start = Instant::now();
+        let changes = watch.wait_for_changes(&rx, &mut Pause::default(), || false);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(checks.load(Ordering::SeqCst), 2);
+        assert_eq!(changes, Some(vec![modified("a"), modified("b")].into()));
+    }
+
+    #[test]
+    fn pause_when_detached() {
+        let watch = Watch::default().pause_when_detached();
+
+        let (tx, rx) = mpsc::channel();
+        tx.send(vec![modified("a")].into()).unwrap();
+        let mut pause = Pause {
+            attached: Some(|| true),
+            ..Pause::default()
+        };
+        let changes = watch.wait_for_changes(&rx, &mut pause, || false);
+        assert_eq!(changes, Some(vec![modified("a")].into()));
+
+        tx.send(vec![modified("b")].into()).unwrap();
+        let mut pause = Pause {
+            attached: Some(|| false),
+            ..Pause::default()
+        };
+        pause.update();
+        pause.defer(rx.try_iter().flatten());
+        assert!(pause.paused);
+        assert!(pause.pending.is_empty());
+
+        // only kept when relaunching on resume, once per path
+        let mut pause = Pause {
+            attached: Some(|| false),
+            keep_pending: true,
+            ..Pause::default()
+        };
+        tx.send(vec![modified("b"), modified("c")].into()).unwrap();
+        tx.send(vec![modified("b")].into()).unwrap();
+        drop(tx);
+        let changes = watch.wait_for_changes(&rx, &mut pause, || false);
+        assert_eq!(changes, None);
+        assert_eq!(pause.pending, [modified("b"), modified("c")]);
+    }
+
+    #[test]
+    fn resume_checks_should_rerun() {
+        static ON_BATTERY: AtomicBool = AtomicBool::new(true);
+
+        let mut watch = Watch::default()
+            .skip_on_battery()
+            .should_rerun(|paths| !paths.contains(&PathBuf::from("a")));
+        watch.run_on_resume = true;
+        let (tx, rx) = mpsc::channel();
+        tx.send(vec![modified("a")].into()).unwrap();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            ON_BATTERY.store(false, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(300));
+            drop(tx);
+        });
+
+        let mut pause = Pause {
+            on_battery: Some(|| ON_BATTERY.load(Ordering::SeqCst)),
+            keep_pending: true,
+            ..Pause::default()
+        };
+        let changes = watch.wait_for_changes(&rx, &mut pause, || false);
+        assert_eq!(changes, None);
+        assert!(!pause.paused);
+    }
+
+    #[test]
+    fn skip_on_battery() {
+        static ON_BATTERY: AtomicBool = AtomicBool::new(true);
+
+        let mut watch = Watch::default().skip_on_battery();
+        watch.run_on_resume = true;
+        let (tx, rx) = mpsc::channel();
+        tx.send(vec![modified("a")].into()).unwrap();
+        tx.send(vec![modified("b")].into()).unwrap();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            ON_BATTERY.store(false, Ordering::SeqCst);
+            // keep the channel open
+            thread::sleep(Duration::from_secs(1));
+            drop(tx);
+        });
+
+        let mut pause = Pause {
+            on_battery: Some(|| ON_BATTERY.load(Ordering::SeqCst)),
+            keep_pending: true,
+            ..Pause::default()
+        };
+        let start = Instant::now();
+        let changes = watch.wait_for_changes(&rx, &mut pause, || false);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(changes, Some(vec![modified("a"), modified("b")].into()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn power_supply() {
+        let dir = tempfile::tempdir().unwrap();
+        let supply = |name: &str, kind: &str, status: Option<&str>| {
+            let path = dir.path().join(name);
+            std::fs::create_dir(&path).unwrap();
+            std::fs::write(path.join("type"), format!("{kind}\n")).unwrap();
+            if let Some(status) = status {
+                std::fs::write(path.join("status"), format!("{status}\n")).unwrap();
+            }
+        };
+
+        supply("AC", "Mains", None);
+        supply("BAT0", "Battery", Some("Full"));
+        assert!(!power_supply_on_battery(dir.path()));
+
+        supply("BAT1", "Battery", Some("Discharging"));
+        assert!(power_supply_on_battery(dir.path()));
+        assert!(!power_supply_on_battery(&dir.path().join("missing")));
+    }
+
+    #[test]
+    fn cargo_json_summary() {
+        let message = |level: &str, message: &str| {
+            format!(
+                r#"{{"reason":"compiler-message","package_id":"foo 0.1.0 (path+file:///foo)","target":{{"kind":["lib"],"crate_types":["lib"],"name":"foo","src_path":"/foo/src/lib.rs"}},"message":{{"rendered":null,"children":[],"level":"{level}","message":"{message}","spans":[],"code":null}}}}"#
+            )
+        };
+        let stream = [
+            message("error", "mismatched types"),
+            message("error", "cannot find value `x` in this scope"),
+            message("warning", "unused variable: `y`"),
+            message("error", "aborting due to 2 previous errors"),
+            message("warning", "1 warning emitted"),
+            message("failure-note", "For more information about an error"),
+            "output of a build script".to_string(),
+            r#"{"reason":"build-finished","success":false}"#.to_string(),
+        ]
+        .join("\n");
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let sink = OutputSink {
+            stream: OutputStream::Buffer(output.clone()),
+            file: None,
+            prefix: None,
+        };
+        let summary = CargoSummary::from_stream(stream.as_bytes(), &sink, &sink);
+        assert_eq!(*output.lock().unwrap(), b"output of a build script\n");
+        assert_eq!(
+            summary,
+            CargoSummary {
+                errors: 2,
+                warnings: 1,
+            }
+        );
+        assert_eq!(summary.to_string(), "2 errors, 1 warning");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cargo_json_tee() {
+        let dir = tempfile::tempdir().unwrap();
+        let cargo = dir.path().join("cargo");
+        let message = r#"{"reason":"compiler-message","package_id":"foo 0.1.0 (path+file:///foo)","target":{"kind":["lib"],"crate_types":["lib"],"name":"foo","src_path":"/foo/src/lib.rs"},"message":{"rendered":"warning: unused variable\n --> src/lib.rs:1:5\n","children":[],"level":"warning","message":"unused variable","spans":[],"code":null}}"#;
+        std::os::unix::fs::symlink("/bin/sh", &cargo).unwrap();
+        let mut command = Command::new(&cargo);
+        command
+            .arg("-c")
+            .arg(format!("printf '%s\\n' '{message}' 'build script output'"));
+        let mut commands = CommandList::from(command);
+        // the output read from the JSON messages goes through the same copies
+        // as the other output
+        commands.use_cargo_json();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let options = SpawnOptions {
+            tee: Some(dir.path().join("cargo-logs")),
+            prefix_output: true,
+            capture: Some(output.clone()),
+            ..Default::default()
+        };
+        let mut summary = None;
+        commands.spawn_in(&options, |res, spawned| {
+            let mut child = res.unwrap();
+            let stdout = io::BufReader::new(child.stdout.take().unwrap());
+            summary = Some(CargoSummary::from_stream(
+                stdout,
+                &spawned.stdout,
+                &spawned.stderr,
+            ));
+            child.wait().unwrap().success()
+        });
+        assert_eq!(summary.unwrap().warnings, 1);
+        let expected = "[cargo] warning: unused variable\n\
+            [cargo]  --> src/lib.rs:1:5\n\
+            [cargo] build script output\n";
+        assert_eq!(
+            String::from_utf8(output.lock().unwrap().clone()).unwrap(),
+            expected
+        );
+        let file = std::fs::read_dir(dir.path().join("cargo-logs"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(file.path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn cargo_json_args() {
+        let mut build = Command::new("cargo");
+        build.arg("build").current_dir("/tmp");
+        let mut run = Command::new("cargo");
+        run.args(["run", "--", "--foo"]);
+        let mut other = Command::new("echo");
+        other.arg("run");
+
+        let commands =
+            CommandList::from([build, run, other]).templated(["cargo", "run", "--", "{file}"]);
+        commands.use_cargo_json();
+
+        let commands = commands.commands.lock().unwrap();
+        let args = |i: usize| commands[i].command.get_args().collect::<Vec<_>>();
+        assert_eq!(args(0), ["build", "--message-format=json"]);
+        assert_eq!(
+            commands[0].command.get_current_dir(),
+            Some(Path::new("/tmp"))
+        );
+        assert_eq!(args(1), ["run", "--", "--foo"]);
+        assert!(!commands[1].piped_stdout);
+        assert_eq!(args(2), ["run"]);
+        assert_eq!(args(3), ["run", "--message-format=json", "--", "{file}"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn queue_changes_while_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!(
+            "echo start >> {0}; sleep 0.5; echo end >> {0}",
+            log.display()
+        ));
+
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default().on_change_while_running(ChangeWhileRunning::Queue);
+        let handle = thread::spawn(move || watch.watch_loop(command.into(), rx));
+
+        thread::sleep(Duration::from_millis(100));
+        tx.send(vec![modified("a")].into()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        tx.send(vec![modified("b")].into()).unwrap();
+        thread::sleep(Duration::from_millis(1500));
+        drop(tx);
+        handle.join().unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "start\nend\nstart\nend\n"
+        );
+    }
+
+    #[test]
+    fn create_triggers() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("new.rs");
+        std::fs::write(&file, "").unwrap();
+
+        let mut probe = EventProbe::new(Watch::default().watch_path(dir.path()));
+        assert!(probe.send(create(), &file));
+        assert_eq!(
+            probe.send(
+                notify::EventKind::Create(notify::event::CreateKind::Any),
+                &file
+            ),
+            !cfg!(target_os = "macos")
+        );
+    }
+
+    #[test]
+    fn event_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        for path in ["src/lib.rs", "src/lib.rs~", ".git/index", "target/foo"] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+
+        let mut probe = EventProbe::new(
+            Watch::default()
+                .watch_path(&root)
+                .exclude_path(root.join("target")),
+        );
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Data(
+            notify::event::DataChange::Any,
+        ));
+        let rename = notify::EventKind::Modify(notify::event::ModifyKind::Name(
+            notify::event::RenameMode::Any,
+        ));
+
+        assert!(probe.send(modify, root.join("src/lib.rs")));
+        assert!(!probe.send(rename, root.join("src/lib.rs")));
+        assert!(!probe.send(modify, root.join("src/lib.rs~")));
+        assert!(!probe.send(modify, root.join(".git/index")));
+        assert!(!probe.send(modify, root.join("target/foo")));
+        assert!(!probe.send(modify, root.join("src/missing.rs")));
+    }
+
+    #[test]
+    fn event_debounce() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        std::fs::write(&file, "").unwrap();
+
+        let mut probe = EventProbe::new(
+            Watch::default()
+                .watch_path(dir.path())
+                .debounce(Duration::from_millis(200)),
+        );
+        assert!(!probe.send(create(), &file));
+        thread::sleep(Duration::from_millis(200));
+        assert!(probe.send(create(), &file));
+        assert!(!probe.send(create(), &file));
+    }
+
+    #[test]
+    fn delete_debounce() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let remove = notify::EventKind::Remove(notify::event::RemoveKind::File);
+
+        let mut probe = EventProbe::new(Watch::default().watch_path(dir.path()));
+        assert!(!probe.send(remove, &file));
+
+        let mut probe = EventProbe::new(
+            Watch::default()
+                .watch_path(dir.path())
+                .delete_debounce(Duration::from_millis(200)),
+        );
+        assert!(probe.send(remove, &file));
+        std::fs::write(&file, "").unwrap();
+        assert!(!probe.send(create(), &file));
+        thread::sleep(Duration::from_millis(200));
+        assert!(probe.send(create(), &file));
+        assert!(probe.send(create(), &file));
+    }
+
+    #[test]
+    fn watch_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("paths.txt");
+        std::fs::write(&list, "# sources\nsrc\n\n  tests  \n# docs\nREADME.md\n").unwrap();
+
+        assert_eq!(
+            read_path_list(&list).unwrap(),
+            [
+                PathBuf::from("src"),
+                PathBuf::from("tests"),
+                PathBuf::from("README.md")
+            ]
+        );
+        assert!(read_path_list(&dir.path().join("missing.txt")).is_err());
+    }
+
+    #[test]
+    fn cargo_target_dir_exclusion() {
+        let metadata = metadata();
+        let tmp = tempfile::tempdir().unwrap();
+        let target_dir = tmp.path().join("target");
+        let built = tmp.path().canonicalize().unwrap().join("target/debug/foo");
+
+        let mut watch = Watch::default();
+        watch
+            .resolve_exclude_paths(std::slice::from_ref(&target_dir))
+            .unwrap();
+        assert!(watch.is_excluded_path(&built));
+        assert!(!watch.is_excluded_path(&metadata.target_directory.as_std_path().join("debug")));
+
+        let mut watch = Watch::default().include_target();
+        watch
+            .resolve_exclude_paths(std::slice::from_ref(&target_dir))
+            .unwrap();
+        assert!(!watch.is_excluded_path(&built));
+
+        let vendored = tmp.path().canonicalize().unwrap().join("vendor/foo/target");
+        std::fs::create_dir_all(vendored.parent().unwrap()).unwrap();
+        let mut watch = Watch::default().exclude_target(&vendored);
+        watch
+            .resolve_exclude_paths(std::slice::from_ref(&target_dir))
+            .unwrap();
+        assert!(watch.is_excluded_path(&vendored.join("debug/foo")));
+        assert!(!watch.is_excluded_path(&built));
+    }
+
+    #[test]
+    fn extra_target_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let cross = root.join("target-wasm");
+        let built = root.join("target/x86_64-unknown-linux-gnu/debug/foo");
+
+        let mut watch = Watch::default()
+            .watch_path(&root)
+            .extra_target_dir(&cross)
+            .debounce(Duration::ZERO);
+        watch.resolve_exclude_paths(&[root.join("target")]).unwrap();
+        assert!(watch.is_excluded_path(&built));
+        assert!(watch.is_excluded_path(&cross.join("wasm32-unknown-unknown/debug/foo.wasm")));
+
+        std::fs::create_dir_all(cross.join("debug")).unwrap();
+        std::fs::write(cross.join("debug/foo"), "").unwrap();
+        std::fs::write(root.join("lib.rs"), "").unwrap();
+        let mut probe = EventProbe::new(watch);
+        assert!(!probe.send(create(), cross.join("debug/foo")));
+        assert!(probe.send(create(), root.join("lib.rs")));
+
+        let mut watch = Watch::default()
+            .exclude_target(root.join("vendor-target"))
+            .extra_target_dir(&cross)
+            .include_target();
+        watch.resolve_exclude_paths(&[root.join("target")]).unwrap();
+        assert!(!watch.is_excluded_path(&cross.join("debug/foo")));
+    }
+
+    #[test]
+    fn dep_info_files() {
+        assert_eq!(
+            parse_dep_info(
+                "/ws/target/debug/foo: /ws/src/lib.rs /ws/my\\ dir/a.rs \\\n /ws/build.rs\n\n\
+                /ws/src/lib.rs:\n# env-dep:CARGO_PKG_NAME=foo\n"
+            ),
+            [
+                PathBuf::from("/ws/src/lib.rs"),
+                PathBuf::from("/ws/my dir/a.rs"),
+                PathBuf::from("/ws/build.rs"),
+            ]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let target = root.join("target");
+        std::fs::create_dir_all(target.join("debug")).unwrap();
+        std::fs::write(
+            target.join("debug/foo.d"),
+            format!(
+                "{0}/target/debug/foo: {0}/src/main.rs {0}/target/debug/build/out.rs \
+                /registry/dep/src/lib.rs\n",
+                root.display()
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            target.join("debug/bar.d"),
+            format!(
+                "{0}/target/debug/bar: src/main.rs src/bar.rs\n",
+                root.display()
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(
+            dep_info_sources(&target, &root, false),
+            [root.join("src/bar.rs"), root.join("src/main.rs")]
+        );
+        assert_eq!(
+            dep_info_sources(&target, &root, true),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[test]
+    fn dep_info_external() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let workspace = root.join("workspace");
+        let target = workspace.join("target");
+        let included = root.join("shared/data.txt");
+        std::fs::create_dir_all(target.join("debug")).unwrap();
+        std::fs::create_dir_all(included.parent().unwrap()).unwrap();
+        std::fs::write(&included, "").unwrap();
+        std::fs::write(
+            target.join("debug/foo.d"),
+            format!(
+                "{}/debug/foo: {}/src/main.rs {} {}/missing.txt\n",
+                target.display(),
+                workspace.display(),
+                included.display(),
+                root.display(),
+            ),
+        )
+        .unwrap();
+
+        let sources = dep_info_sources(&target, &workspace, true);
+        assert_eq!(sources, std::slice::from_ref(&included));
+
+        let mut probe =
+            EventProbe::new(Watch::default().watch_path(&workspace).watch_paths(sources));
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Data(
+            notify::event::DataChange::Any,
+        ));
+        std::fs::write(root.join("shared/other.txt"), "").unwrap();
+        assert!(probe.send(modify, &included));
+        assert!(!probe.send(modify, root.join("shared/other.txt")));
+    }
+
+    #[test]
+    fn pruned_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        for path in ["src/bin", "target/debug", ".git/objects"] {
+            std::fs::create_dir_all(root.join(path)).unwrap();
+        }
+
+        let watch = Watch::default()
+            .watch_path(&root)
+            .exclude_path(root.join("target"));
+
+        let mut dirs = watch.watched_directories(&root, None);
+        dirs.sort();
+        assert_eq!(dirs, [root.clone(), root.join("src"), root.join("src/bin")]);
+
+        assert_eq!(
+            watch.watched_directories(&root, Some(2)),
+            [root.clone(), root.join("src")]
+        );
+    }
+
+    #[test]
+    fn excluded_directories_not_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("target")).unwrap();
+
+        let watch = Watch::default()
+            .watch_path(&root)
+            .exclude_path(root.join("target"));
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            let _ = tx.send(res.unwrap());
+        })
+        .unwrap();
+        watch.watch_directories(&mut watcher, &root, &mut None);
+
+        std::fs::write(root.join("target").join("foo"), "foo").unwrap();
+        std::fs::write(root.join("src").join("lib.rs"), "").unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        let paths: Vec<PathBuf> = rx.try_iter().flat_map(|x| x.paths).collect();
+        assert!(paths.contains(&root.join("src").join("lib.rs")));
+        assert!(!paths.iter().any(|x| x.starts_with(root.join("target"))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("dotfiles")).unwrap();
+        let target = root.join("dotfiles/config.toml");
+        std::fs::write(&target, "").unwrap();
+        let link = root.join("config.toml");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let broken = root.join("broken.toml");
+        std::os::unix::fs::symlink(root.join("missing.toml"), &broken).unwrap();
+
+        assert!(resolve_watch_path(&broken)
+            .unwrap_err()
+            .to_string()
+            .contains("broken symlink"));
+        let resolved = resolve_watch_path(&link).unwrap();
+        assert_eq!(resolved, target);
+
+        let watch = Watch::default().watch_path(&resolved);
+        let (tx, rx) = mpsc::channel();
+        let mut watcher =
+            notify::recommended_watcher(WatchEventHandler::new(watch.clone(), tx)).unwrap();
+        watch.watch_directories(&mut watcher, &resolved, &mut None);
+
+        std::fs::write(root.join("dotfiles/other.toml"), "").unwrap();
+        std::fs::write(&target, "edited").unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        let paths: Vec<PathBuf> = rx.try_iter().flatten().map(|x| x.path).collect();
+        assert!(paths.contains(&target), "{paths:?}");
+        assert!(!paths.contains(&root.join("dotfiles/other.toml")));
+    }
+
+    #[test]
+    fn max_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("l1/l2/l3")).unwrap();
+
+        let watch = Watch::default().watch_path(&root).max_depth(1);
+        assert_eq!(
+            watch.watched_directories(&root, None),
+            [root.clone(), root.join("l1")]
+        );
+        assert!(watch
+            .watched_directories(&root.join("l1/l2"), None)
+            .is_empty());
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            let _ = tx.send(res.unwrap());
+        })
+        .unwrap();
+        watch.watch_directories(&mut watcher, &root, &mut None);
+
+        std::fs::write(root.join("l1/l2/l3/deep.rs"), "").unwrap();
+        std::fs::write(root.join("l1/shallow.rs"), "").unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        let paths: Vec<PathBuf> = rx.try_iter().flat_map(|x| x.paths).collect();
+        assert!(paths.contains(&root.join("l1/shallow.rs")));
+        assert!(!paths.contains(&root.join("l1/l2/l3/deep.rs")));
+    }
+
+    #[test]
+    fn root_exclude_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let frontend = root.join("frontend");
+        let backend = root.join("backend");
+        for path in [&frontend, &backend] {
+            std::fs::create_dir(path).unwrap();
+        }
+
+        let mut watch = Watch::default()
+            .watch_paths([&frontend, &backend])
+            .exclude_path_in(&frontend, "node_modules")
+            .exclude_path_in(&backend, "target")
+            .exclude_path_in(&backend, "generated/schema.rs");
+        watch.resolve_exclude_paths(&[root.join("target")]).unwrap();
+        assert_eq!(watch.root_exclude_paths.len(), 2);
+
+        assert!(watch.is_excluded_path(&frontend.join("node_modules/react/index.js")));
+        assert!(watch.is_excluded_path(&frontend.join("app/node_modules/x.js")));
+        assert!(!watch.is_excluded_path(&backend.join("node_modules/x.js")));
+        assert!(watch.is_excluded_path(&backend.join("target/debug/app")));
+        assert!(!watch.is_excluded_path(&frontend.join("target/index.js")));
+        assert!(watch.is_excluded_path(&backend.join("generated/schema.rs")));
+        assert!(!watch.is_excluded_path(&backend.join("generated/other.rs")));
+    }
+
+    #[test]
+    fn lenient_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let missing = root.join("missing");
+
+        let mut watch = Watch::default().watch_path(&root).watch_path(&missing);
+        assert!(watch.clone().resolve_watch_paths().is_err());
+        assert!(watch
+            .clone()
+            .exclude_path(&missing)
+            .resolve_exclude_paths(&[root.join("target")])
+            .is_err());
+
+        capture_logs();
+        watch = watch.exclude_path(&missing).lenient_paths();
+        watch.resolve_watch_paths().unwrap();
+        watch.resolve_exclude_paths(&[root.join("target")]).unwrap();
+        assert_eq!(watch.watch_paths, std::slice::from_ref(&root));
+        assert_eq!(watch.exclude_paths, [root.join("target")]);
+
+        let logs = CAPTURED_LOGS.lock().unwrap();
+        for message in ["Skipping watched path", "Skipping excluded path"] {
+            let message = format!("{message}: can't find {}", missing.display());
+            assert!(
+                logs.iter().any(|x| x.starts_with(&message)),
+                "no {message:?} in {logs:?}"
+            );
+        }
+        drop(logs);
+
+        let mut watch = Watch::default().watch_path(&missing).lenient_paths();
+        assert!(watch.resolve_watch_paths().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_watch_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let real = root.join("real");
+        let link = root.join("link");
+        std::fs::create_dir(&real).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        for name in [".hidden", "lib.rs~", "lib.rs"] {
+            std::fs::write(real.join(name), "").unwrap();
+        }
+
+        let mut watch = Watch::default().watch_path(&link);
+        watch.resolve_watch_paths().unwrap();
+        assert_eq!(watch.watch_paths, std::slice::from_ref(&real));
+        assert_eq!(
+            watch.normalize_path(link.join("lib.rs")),
+            real.join("lib.rs")
+        );
+
+        let mut probe = EventProbe::new(watch);
+        assert!(!probe.send(create(), link.join(".hidden")));
+        assert!(!probe.send(create(), link.join("lib.rs~")));
+        probe
+            .handler
+            .handle_event(Ok(Event::new(create()).add_path(link.join("lib.rs"))));
+        assert_eq!(
+            probe.rx.try_iter().flatten().collect::<Vec<_>>(),
+            [Change::new(real.join("lib.rs"), ChangeKind::Created)]
+        );
+    }
+
+    #[test]
+    fn external_watch_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let workspace = root.join("workspace");
+        let sibling = root.join("sibling");
+        let config = workspace.join(".config/app");
+        for path in [&workspace, &sibling, &config] {
+            std::fs::create_dir_all(path).unwrap();
+        }
+        for file in [
+            sibling.join(".hidden"),
+            sibling.join("lib.rs"),
+            sibling.join("lib.rs~"),
+            config.join("app.toml"),
+            workspace.join(".env"),
+        ] {
+            std::fs::write(file, "").unwrap();
+        }
+
+        let watch = Watch::default()
+            .watch_path(&workspace)
+            .watch_external(&sibling)
+            .watch_path(&config);
+        assert_eq!(
+            watch.watch_root(&config.join("app.toml")),
+            Some(config.as_path())
+        );
+
+        let mut probe = EventProbe::new(watch);
+        assert!(!probe.send(create(), sibling.join(".hidden")));
+        assert!(!probe.send(create(), sibling.join("lib.rs~")));
+        assert!(!probe.send(create(), workspace.join(".env")));
+        assert!(probe.send(create(), sibling.join("lib.rs")));
+        assert!(probe.send(create(), config.join("app.toml")));
+    }
+
+    #[test]
+    fn watch_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let swapped = root.join("swapped");
+
+        let (tx, rx) = mpsc::channel();
+        let watcher = Arc::new(Mutex::new(
+            notify::recommended_watcher(move |res: notify::Result<Event>| {
+                let _ = tx.send(res.unwrap());
+            })
+            .unwrap(),
+        ));
+        assert!(!watch_directory(
+            &mut *watcher.lock().unwrap(),
+            &swapped,
+            true
+        ));
+
+        // the retries happen in the background
+        let (_dir_tx, dir_rx) = mpsc::channel();
+        let start = Instant::now();
+        Watch::default().spawn_directory_registrar(
+            Arc::downgrade(&watcher),
+            dir_rx,
+            None,
+            vec![swapped.clone(), root.join("missing")],
+        );
+        assert!(start.elapsed() < WATCH_RETRY_DELAY);
+
+        thread::sleep(WATCH_RETRY_DELAY + WATCH_RETRY_DELAY / 2);
+        std::fs::create_dir(&swapped).unwrap();
+        thread::sleep(WATCH_RETRY_DELAY * 2);
+
+        std::fs::write(swapped.join("lib.rs"), "").unwrap();
+        thread::sleep(Duration::from_millis(500));
+        let paths: Vec<PathBuf> = rx.try_iter().flat_map(|x| x.paths).collect();
+        assert!(paths.contains(&swapped.join("lib.rs")));
+    }
+
+    #[test]
+    fn ready_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let marker = canonicalize_missing(&root.join(".ready")).unwrap();
+        assert_eq!(marker, root.join(".ready"));
+
+        let mut probe = EventProbe::new(Watch::default().watch_path(&root).ready_marker(&marker));
+        for path in [root.join("a.rs"), root.join("b.rs")] {
+            std::fs::write(&path, "").unwrap();
+            assert!(!probe.send(create(), path));
+        }
+        std::fs::write(&marker, "").unwrap();
+        assert!(probe.send(create(), &marker));
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let max = Duration::from_secs(10);
+        assert_eq!(
+            (0..7).map(|x| backoff_delay(x, max)).collect::<Vec<_>>(),
+            [0, 1, 2, 4, 8, 10, 10].map(Duration::from_secs),
+        );
+        assert_eq!(backoff_delay(u32::MAX, max), max);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backoff_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("runs");
+        let runs = || std::fs::read_to_string(&output).map_or(0, |x| x.lines().count());
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo run >> {}; exit 1", output.display()));
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default().backoff(Duration::from_millis(800));
+        let handle = thread::spawn(move || watch.watch_loop(command.into(), rx));
+
+        thread::sleep(Duration::from_millis(200));
+        tx.send(vec![modified("a")].into()).unwrap();
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(runs(), 1);
+        thread::sleep(Duration::from_millis(1000));
+        assert_eq!(runs(), 2);
+        drop(tx);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn changed_packages() {
+        let metadata = cargo_metadata::MetadataCommand::new()
+            .current_dir(metadata().workspace_root.join("examples").join("demo"))
+            .no_deps()
+            .exec()
+            .unwrap();
+        let root = metadata.workspace_root.as_std_path();
+        let project = root.join("my-project");
+        let xtask = root.join("xtask");
+
+        let package = |paths: &[PathBuf]| changed_package(&metadata, paths).map(|x| &x.name);
+        assert_eq!(
+            package(&[project.join("src/lib.rs"), project.join("Cargo.toml")]),
+            Some(&"my-project".to_string())
+        );
+        assert_eq!(
+            package(&[project.join("src/lib.rs"), xtask.join("src/main.rs")]),
+            None
+        );
+        assert_eq!(package(&[root.join("Cargo.toml")]), None);
+        assert_eq!(package(&[]), None);
+
+        let files = vec![
+            project.join("src/lib.rs"),
+            xtask.join("src/main.rs"),
+            project.join("Cargo.toml"),
+        ];
+        let values = TemplateValues {
+            package: None,
+            package_flags: package_flags(&metadata, &files, NoChangedPackage::Workspace),
+            files,
+        };
+        let args = ["test", "{packages}", "--", "--nocapture"].map(OsStr::new);
+        assert_eq!(
+            values.expand(&args, false).unwrap(),
+            [
+                "test",
+                "-p",
+                "my-project",
+                "-p",
+                "xtask",
+                "--",
+                "--nocapture"
+            ]
+        );
+        let args = ["test", "--flags={packages}"].map(OsStr::new);
+        assert_eq!(values.expand(&args, true), None);
+        assert_eq!(
+            package_flags(&metadata, &[], NoChangedPackage::Workspace),
+            ["--workspace"]
+        );
+        assert!(package_flags(
+            &metadata,
+            &[root.join("Cargo.toml")],
+            NoChangedPackage::Empty
+        )
+        .is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_in_package_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let package = root.join("package");
+        let explicit = root.join("explicit");
+        std::fs::create_dir_all(&package).unwrap();
+        std::fs::create_dir_all(&explicit).unwrap();
+
+        let pwd = |output: &str| {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("pwd > {}", root.join(output).display()));
+            command
+        };
+        let mut explicit_command = pwd("explicit.txt");
+        explicit_command.current_dir(&explicit);
+
+        let mut commands = CommandList::from([pwd("package.txt"), explicit_command]);
+        let options = SpawnOptions {
+            dir: Some(package.clone()),
+            ..Default::default()
+        };
+        commands.spawn_in(&options, |res, _| res.unwrap().wait().unwrap().success());
+
+        let read = |output: &str| std::fs::read_to_string(root.join(output)).unwrap();
+        assert_eq!(read("package.txt").trim(), package.to_str().unwrap());
+        assert_eq!(read("explicit.txt").trim(), explicit.to_str().unwrap());
+    }
+
+    #[test]
+    fn template_tokens() {
+        let values = TemplateValues {
+            files: vec![PathBuf::from("src/lib.rs"), PathBuf::from("src/main.rs")],
+            package: None,
+            ..Default::default()
+        };
+        let args = ["test", "{file}", "--dir={dir}", "{files}", "-p={package}"].map(OsStr::new);
+        assert_eq!(values.expand(&args, false), None);
+        assert_eq!(
+            values.expand(&args, true).unwrap(),
+            [
+                "test",
+                "src/lib.rs",
+                "--dir=src",
+                "src/lib.rs",
+                "src/main.rs",
+                "-p="
+            ]
+        );
+        assert_eq!(TemplateValues::default().expand(&args[..2], false), None);
+        assert_eq!(
+            TemplateValues::default().expand(&args[..2], true).unwrap(),
+            ["test", ""]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn templated_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("file");
+
+        let script = format!("echo \"$1\" > {}", output.display());
+        let mut commands =
+            CommandList::from(Vec::new()).templated(["sh", "-c", &script, "sh", "{file}"]);
+
+        commands.spawn(|res| res.unwrap().wait().unwrap().success());
+        assert!(!output.exists());
+
+        let mut plain = CommandList::from_argv(["sh", "-c", &script, "sh", "{file}"]);
+        plain.spawn(|res| res.unwrap().wait().unwrap().success());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "{file}\n");
+
+        let options = SpawnOptions {
+            template: TemplateValues {
+                files: vec![PathBuf::from("src/lib.rs")],
+                package: None,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        commands.spawn_in(&options, |res, _| res.unwrap().wait().unwrap().success());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "src/lib.rs\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filtered_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let touch = |name: &str| {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("echo run >> {}", root.join(name).display()));
+            command
+        };
+
+        let mut commands = CommandList::from(touch("always.log"))
+            .when_changed(touch("frontend.log"), [root.join("frontend")])
+            .when_changed(touch("backend.log"), [root.join("backend")]);
+        let options = SpawnOptions {
+            template: TemplateValues {
+                files: vec![root.join("backend/src/lib.rs")],
+                package: None,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        commands.spawn(|res| res.unwrap().wait().unwrap().success());
+        commands.spawn_in(&options, |res, _| res.unwrap().wait().unwrap().success());
+
+        let runs = |name: &str| {
+            std::fs::read_to_string(root.join(name))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        assert_eq!(runs("always.log"), 2);
+        assert_eq!(runs("frontend.log"), 1);
+        assert_eq!(runs("backend.log"), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn quiet_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = |name: &str| {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("echo run >> {}", dir.path().join(name).display()));
+            command
+        };
+        let runs = |name: &str| {
+            std::fs::read_to_string(dir.path().join(name)).map_or(0, |x| x.lines().count())
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default().quiet_command(log("slow"), Duration::from_millis(400));
+        let handle = thread::spawn({
+            let fast = log("fast");
+            move || watch.watch_loop(fast.into(), rx)
+        });
+        for _ in 0..5 {
+            thread::sleep(Duration::from_millis(150));
+            tx.send(vec![modified("a")].into()).unwrap();
+        }
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs("slow"), 0);
+        thread::sleep(Duration::from_millis(500));
+        drop(tx);
+        handle.join().unwrap();
+
+        assert_eq!(runs("fast"), 6);
+        assert_eq!(runs("slow"), 1);
+
+        // a change while the first command ends skips the next ones
+        let started = dir.path().join("started");
+        let mut first = Command::new("sh");
+        first.arg("-c").arg(format!(
+            "trap '' TERM; touch {}; sleep 0.5",
+            started.display()
+        ));
+        let mut second = Command::new("touch");
+        second.arg(dir.path().join("second"));
+        let tx = spawn_quiet_runner(
+            CommandList::from([first, second]),
+            Duration::from_millis(200),
+            Vec::new(),
+        );
+        thread::sleep(Duration::from_millis(400));
+        assert!(started.exists());
+        tx.send(()).unwrap();
+        drop(tx);
+        thread::sleep(Duration::from_secs(1));
+        assert!(!dir.path().join("second").exists());
+    }
+
+    #[test]
+    fn parse_env_file() {
+        let content = "# comment\n\nA=1\nexport B = two words \nC=\"quoted\"\nD='x=y'\nE=\n";
+        assert_eq!(
+            super::parse_env_file(content).unwrap(),
+            [
+                ("A", "1"),
+                ("B", "two words"),
+                ("C", "quoted"),
+                ("D", "x=y"),
+                ("E", "")
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string())),
+        );
+        assert!(super::parse_env_file("A=1\nB\n").is_err());
+        assert!(super::parse_env_file("1A=1").is_err());
+        assert!(super::parse_env_file("A-B=1").is_err());
+    }
+
+    #[test]
+    fn parse_config() {
+        let content = "# comment\n\nwatch = src\nwatch = assets\nexclude = gen\ndebounce = 300\n";
+        assert_eq!(
+            super::parse_config(content).unwrap(),
+            ConfigFile {
+                watch_paths: vec!["src".into(), "assets".into()],
+                exclude_paths: vec!["gen".into()],
+                debounce: Some(Duration::from_millis(300)),
+            }
+        );
+        for (content, err) in [
+            ("watch", "line 1: expected `KEY = VALUE`"),
+            ("\nfoo = 1", "line 2: unknown setting `foo`"),
+            (
+                "debounce = 1s",
+                "line 1: `1s` is not a number of milliseconds",
+            ),
+        ] {
+            assert_eq!(super::parse_config(content).unwrap_err().to_string(), err);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn config_in_watched_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let config = root.join("watch.conf");
+        let log = root.join(".log");
+        std::fs::write(&config, "debounce = 100\n").unwrap();
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo run >> {}", log.display()));
+        let runs = || std::fs::read_to_string(&log).unwrap().lines().count();
+
+        let session = Watch::default()
+            .watch_path(&root)
+            .config(&config)
+            .reload_on_config_change()
+            .spawn_session(command);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(runs(), 1);
+
+        // edited in place and saved atomically
+        std::fs::write(&config, "debounce = 50\n").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        std::fs::write(root.join(".watch.conf.tmp"), "debounce = 100\n").unwrap();
+        std::fs::rename(root.join(".watch.conf.tmp"), &config).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(runs(), 1);
+
+        std::fs::write(root.join("lib.rs"), "").unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(runs(), 2);
+        session.stop().unwrap();
+    }
+
+    #[test]
+    fn reload_on_config_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let config = root.join("watch.conf");
+        for path in ["docs", "gen", "assets"] {
+            std::fs::create_dir_all(root.join(path)).unwrap();
+        }
+        std::fs::write(&config, "exclude = ./docs\n").unwrap();
+        for file in ["gen/a.rs", "gen/b.rs", "docs/a.md", "docs/b.md"] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+
+        let mut watch = Watch::default()
+            .watch_path(root.join("docs"))
+            .watch_path(root.join("gen"))
+            .config(&config)
+            .reload_on_config_change();
+        watch
+            .apply_config(read_config(&config).unwrap(), &root)
+            .unwrap();
+        watch.resolve_exclude_paths(&[root.join("target")]).unwrap();
+        watch.resolve_watch_paths().unwrap();
+
+        let mut probe = EventProbe::new(watch);
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Data(
+            notify::event::DataChange::Any,
+        ));
+        assert!(probe.send(create(), root.join("gen/a.rs")));
+        assert!(!probe.send(create(), root.join("docs/a.md")));
+
+        std::fs::write(&config, "exclude = ./gen\nwatch = assets\ndebounce = 250\n").unwrap();
+        assert!(!probe.send(modify, &config));
+        assert!(!probe.send(create(), root.join("gen/b.rs")));
+        assert!(probe.send(create(), root.join("docs/b.md")));
+        let watch = &probe.handler.watch;
+        assert_eq!(watch.debounce, Duration::from_millis(250));
+        assert!(watch.exclude_paths.contains(&root.join("gen")));
+        assert!(!watch.exclude_paths.contains(&root.join("docs")));
+        assert!(watch.watch_paths.contains(&root.join("assets")));
+
+        // an invalid config file keeps the current settings
+        std::fs::write(&config, "exclude = ./missing\n").unwrap();
+        assert!(!probe.send(modify, &config));
+        assert!(probe
+            .handler
+            .watch
+            .exclude_paths
+            .contains(&root.join("gen")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let env_file = root.join(".env");
+        let output = root.join("output");
+        std::fs::write(&env_file, "GREETING=hello\n").unwrap();
+
+        let mut probe = EventProbe::new(Watch::default().watch_path(&root).env_file(&env_file));
+        assert!(probe.send(create(), &env_file));
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo $GREETING >> {}", output.display()));
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default().env_file(&env_file);
+        let handle = thread::spawn(move || watch.watch_loop(command.into(), rx));
+        thread::sleep(Duration::from_millis(300));
+        std::fs::write(&env_file, "GREETING=bye\n").unwrap();
+        tx.send(vec![modified(&env_file)].into()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        // a malformed file keeps the last valid values
+        std::fs::write(&env_file, "GREETING\n").unwrap();
+        tx.send(vec![modified(&env_file)].into()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        std::fs::write(&env_file, "OTHER=1\n").unwrap();
+        tx.send(vec![modified(&env_file)].into()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        drop(tx);
+        handle.join().unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "hello\nbye\nbye\n\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        let log = |name: &str| {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("echo {name} >> {}", output.display()));
+            command
+        };
+        let runs = || {
+            let runs = std::fs::read_to_string(&output).unwrap_or_default();
+            std::fs::remove_file(&output).unwrap();
+            runs
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default()
+            .bind_extension("rs", log("check"))
+            .bind_extension(".toml", log("build"));
+        let handle = thread::spawn({
+            let main = log("main");
+            move || watch.watch_loop(main.into(), rx)
+        });
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(runs(), "main\n");
+
+        for (batch, expected) in [
+            (vec!["Cargo.toml"], "build\n"),
+            (
+                vec!["src/lib.rs", "Cargo.toml", "src/main.rs"],
+                "check\nbuild\n",
+            ),
+            (vec!["README.md", "src/lib.rs"], "main\ncheck\n"),
+        ] {
+            tx.send(batch.into_iter().map(modified).collect::<Vec<_>>().into())
+                .unwrap();
+            thread::sleep(Duration::from_millis(200));
+            assert_eq!(runs(), expected);
+        }
+        drop(tx);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn manifest_change() {
+        let check = CommandList::from_argv(["cargo", "check"]);
+        let build = CommandList::from_argv(["cargo", "build"]);
+        let fmt = CommandList::from_argv(["taplo", "fmt"]);
+        let watch = Watch::default()
+            .bind_extension("toml", fmt.clone())
+            .on_manifest_change(build.clone());
+        let selected = |paths: &[&str]| -> Vec<CommandList> {
+            let changes: Vec<Change> = paths.iter().map(|x| modified(*x)).collect();
+            watch.commands_for(&check, &changes)
+        };
+        let same = |lists: Vec<CommandList>, expected: &[&CommandList]| {
+            lists.len() == expected.len()
+                && lists
+                    .iter()
+                    .zip(expected)
+                    .all(|(x, y)| Arc::ptr_eq(&x.commands, &y.commands))
+        };
+
+        assert!(same(selected(&["my-project/Cargo.toml"]), &[&build]));
+        assert!(same(selected(&["rustfmt.toml"]), &[&fmt]));
+        assert!(same(
+            selected(&["my-project/src/lib.rs", "xtask/Cargo.toml"]),
+            &[&check, &build]
+        ));
+        assert!(same(selected(&[]), &[&check]));
+    }
+
+    #[test]
+    fn rerun_at() {
+        assert_eq!(parse_time_of_day("00:00"), Ok(Duration::ZERO));
+        assert_eq!(parse_time_of_day("23:59"), Ok(Duration::from_secs(86340)));
+        for invalid in ["24:00", "12:60", "12", "noon"] {
+            assert!(parse_time_of_day(invalid).is_err());
+        }
+        assert_eq!(
+            Watch::default().rerun_at(1, 30).rerun_at,
+            [Duration::from_secs(5400)]
+        );
+
+        let hours = |x: &[u64]| {
+            x.iter()
+                .map(|x| Duration::from_secs(x * 3600))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            next_scheduled_delay(hours(&[10])[0], &hours(&[0, 12])),
+            Some(hours(&[2])[0])
+        );
+        assert_eq!(
+            next_scheduled_delay(hours(&[12])[0], &hours(&[0, 12])),
+            Some(hours(&[12])[0])
+        );
+        assert_eq!(next_scheduled_delay(Duration::ZERO, &[]), None);
+
+        let (tx, rx) = mpsc::channel();
+        let soon =
+            (time_of_day(SystemTime::now()) + Duration::from_secs(1)).as_secs() % DAY.as_secs();
+        let stopped = Arc::new(AtomicBool::new(false));
+        spawn_scheduler(vec![Duration::from_secs(soon)], tx, stopped.clone());
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(3)).unwrap(),
+            Trigger::new(TriggerReason::Scheduled, Vec::new())
+        );
+
+        // the scheduler ends with the watch
+        stopped.store(true, Ordering::SeqCst);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(3)),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn settle() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("runs");
+        let runs = || std::fs::read_to_string(&output).map_or(0, |x| x.lines().count());
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo run >> {}", output.display()));
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default().settle(parse_millis("300").unwrap());
+        let handle = thread::spawn(move || watch.watch_loop(command.into(), rx));
+        thread::sleep(Duration::from_millis(200));
+        for _ in 0..10 {
+            tx.send(vec![modified("a")].into()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(runs(), 1);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(runs(), 2);
+        drop(tx);
+        handle.join().unwrap();
+
+        assert!(parse_millis("1s").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn before_terminate() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let watch = Watch::default();
+        let state = watch.clone();
+        let watch = watch.before_terminate({
+            let calls = calls.clone();
+            move || calls.lock().unwrap().push(state.is_running())
+        });
+
+        let mut command = Command::new("sleep");
+        command.arg("5");
+
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || watch.watch_loop(command.into(), rx));
+        thread::sleep(Duration::from_millis(300));
+        tx.send(vec![modified("a")].into()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(*calls.lock().unwrap(), [true]);
+
+        drop(tx);
+        handle.join().unwrap();
+        assert_eq!(*calls.lock().unwrap(), [true]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let log = root.join(".log");
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo run >> {}", log.display()));
+        let runs = || std::fs::read_to_string(&log).unwrap().lines().count();
+
+        let session = Watch::default().watch_path(&root).spawn_session(command);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(session.status(), SessionStatus::Idle);
+        assert_eq!(runs(), 1);
+
+        session.trigger();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 2);
+
+        session.pause();
+        assert_eq!(session.status(), SessionStatus::Paused);
+        session.trigger();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 2);
+
+        session.resume();
+        session.trigger();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 3);
+
+        session.stop().unwrap();
+    }
+
+    #[test]
+    fn session_stop_while_not_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+
+        let session = Watch::default()
+            .watch_path(&root)
+            .ready_when(|| false)
+            .spawn_session(Command::new("true"));
+        thread::sleep(Duration::from_millis(300));
+        session.trigger();
+        thread::sleep(Duration::from_millis(300));
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || tx.send(session.stop().is_ok()));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+    }
+
+    #[test]
+    fn reload_on_signal() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let config = root.join("watch.conf");
+        std::fs::create_dir_all(root.join("gen")).unwrap();
+        std::fs::write(root.join("gen/a.rs"), "").unwrap();
+        std::fs::write(&config, "").unwrap();
+
+        let mut watch = Watch::default()
+            .watch_path(&root)
+            .config(&config)
+            .reload_on_signal();
+        watch.resolve_exclude_paths(&[root.join("target")]).unwrap();
+        watch.resolve_watch_paths().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let handler = Arc::new(Mutex::new(WatchEventHandler::new(watch.clone(), tx)));
+        watch.config_reloader = Some(config_reloader(Arc::downgrade(&handler), config.clone()));
+        watch
+            .reloads
+            .store(WatchSignal::Reload.received(), Ordering::SeqCst);
+        let send = |path: &Path| {
+            handler
+                .lock()
+                .unwrap()
+                .handle_event(Ok(Event::new(create()).add_path(path.to_path_buf())));
+            rx.try_iter().count() > 0
+        };
+        assert!(send(&root.join("gen/a.rs")));
+
+        std::fs::write(&config, "exclude = ./gen\ndebounce = 250\n").unwrap();
+        // not reloaded until the signal
+        watch.reload_config_on_signal();
+        assert!(send(&root.join("gen/a.rs")));
+        SIGNALS_RECEIVED[WatchSignal::Reload as usize].fetch_add(1, Ordering::SeqCst);
+        watch.reload_config_on_signal();
+        assert!(!send(&root.join("gen/a.rs")));
+        assert_eq!(
+            handler.lock().unwrap().watch.debounce,
+            Duration::from_millis(250)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session_watch_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let plugin = root.join("plugin");
+        std::fs::create_dir_all(root.join("app")).unwrap();
+        std::fs::create_dir_all(plugin.join("src")).unwrap();
+        let log = root.join("app/.log");
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo run >> {}", log.display()));
+        let runs = || std::fs::read_to_string(&log).unwrap().lines().count();
+
+        let session = Watch::default()
+            .watch_path(root.join("app"))
+            .debounce(Duration::from_millis(100))
+            .spawn_session(command);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 1);
+
+        std::fs::write(plugin.join("src/lib.rs"), "").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 1);
+
+        session.add_watch(&plugin).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        std::fs::write(plugin.join("src/lib.rs"), "changed").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 2);
+
+        session.remove_watch(&plugin).unwrap();
+        std::fs::write(plugin.join("src/lib.rs"), "").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 2);
+
+        session.stop().unwrap();
+
+        // a path added right after spawning the session is watched too
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo run >> {}", log.display()));
+        let session = Watch::default()
+            .watch_path(root.join("app"))
+            .debounce(Duration::from_millis(100))
+            .spawn_session(command);
+        session.add_watch(&plugin).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 3);
+        std::fs::write(plugin.join("src/lib.rs"), "changed").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 4);
+        session.stop().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+
+        let pid_file = root.join(".pid");
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo $$ > {}; exec sleep 5", pid_file.display()));
+
+        let session = Watch::default().watch_path(&root).spawn_session(command);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(session.status(), SessionStatus::Running);
+
+        let start = Instant::now();
+        drop(session);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let alive = Command::new("kill")
+            .args(["-0", pid.trim()])
+            .stderr(Stdio::null())
+            .status()
+            .unwrap()
+            .success();
+        assert!(!alive);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sessions_from_clones() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+
+        let watch = Watch::default().watch_path(&root);
+        let first = watch.clone().spawn_session(Command::new("true"));
+        let second = watch.clone().spawn_session(Command::new("true"));
+        thread::sleep(Duration::from_millis(300));
+
+        first.pause();
+        assert_eq!(first.status(), SessionStatus::Paused);
+        assert_eq!(second.status(), SessionStatus::Idle);
+        first.stop().unwrap();
+        assert_eq!(second.status(), SessionStatus::Idle);
+        second.stop().unwrap();
+
+        // a watch stopped before can run again
+        let third = watch.spawn_session(Command::new("true"));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(third.status(), SessionStatus::Idle);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pause_with_signal() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo run >> {}", log.display()));
+
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default().pause_on_signal(true);
+        let handle = thread::spawn(move || watch.watch_loop(command.into(), rx));
+        let runs = || std::fs::read_to_string(&log).unwrap().lines().count();
+
+        let toggle =
+            || SIGNALS_RECEIVED[WatchSignal::Pause as usize].fetch_add(1, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(300));
+        toggle();
+        thread::sleep(Duration::from_millis(300));
+        tx.send(vec![modified("a")].into()).unwrap();
+        tx.send(vec![modified("a")].into()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 1);
+
+        toggle();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs(), 2);
+
+        drop(tx);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn formatted_command() {
+        let mut command = Command::new("cargo");
+        command.args(["test", "--features=foo,bar", "some test", "it's", ""]);
+        assert_eq!(
+            format_command(&command),
+            r"cargo test --features=foo,bar 'some test' 'it'\''s' ''"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn prompt_terminate() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut child = SharedChild::new();
+        child.replace(Command::new("sleep").arg("10").spawn().unwrap());
+
+        // well before the end of the sleep, even on a loaded machine
+        let start = Instant::now();
+        child.terminate();
+        assert!(child.wait(None).signal().is_some());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kill_escalation() {
+        use std::os::unix::process::ExitStatusExt;
+
+        assert_eq!(
+            parse_kill_step("SIGINT:0.5"),
+            Ok(KillStep {
+                signal: libc::SIGINT,
+                delay: Duration::from_millis(500)
+            })
+        );
+        assert_eq!(parse_kill_step("9:1").map(|x| x.signal), Ok(libc::SIGKILL));
+        for invalid in ["TERM", "FOO:1", "TERM:-1", "TERM:soon"] {
+            assert!(parse_kill_step(invalid).is_err());
+        }
+
+        let steps: Vec<KillStep> = ["INT:0", "TERM:0.3", "KILL:0.6"]
+            .into_iter()
+            .map(|x| parse_kill_step(x).unwrap())
+            .collect();
+        let terminate = |ignored: &str| {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("trap '' {ignored}; exec sleep 10"));
+            let mut child = SharedChild::new().kill_escalation(steps.clone());
+            child.replace(command.spawn().unwrap());
+            thread::sleep(Duration::from_millis(200));
+
+            let start = Instant::now();
+            child.terminate();
+            (start.elapsed(), child.wait(None).signal())
+        };
+
+        let (elapsed, signal) = terminate("HUP");
+        assert!(elapsed < Duration::from_millis(300));
+        assert_eq!(signal, Some(libc::SIGINT));
+        let (elapsed, signal) = terminate("INT");
+        assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_millis(600));
+        assert_eq!(signal, Some(libc::SIGTERM));
+        let (elapsed, signal) = terminate("INT TERM");
+        assert!(elapsed >= Duration::from_millis(600) && elapsed < Duration::from_secs(2));
+        assert_eq!(signal, Some(libc::SIGKILL));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn measure_shutdown() {
+        capture_logs();
+        let shutdown = |script: &str| {
+            let mut child = SharedChild::new().measure_shutdown(true);
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(script);
+            child.replace(command.spawn().unwrap());
+            thread::sleep(Duration::from_millis(200));
+            child.terminate();
+
+            let logs = CAPTURED_LOGS.lock().unwrap();
+            let line = logs
+                .iter()
+                .rev()
+                .find(|x| x.starts_with("Shutdown: "))
+                .cloned()
+                .unwrap();
+            let millis: f64 = line
+                .rsplit(' ')
+                .next()
+                .and_then(|x| x.strip_suffix("ms"))
+                .map_or(f64::NAN, |x| x.parse().unwrap());
+            (line, millis)
+        };
+
+        let (line, millis) = shutdown("trap 'sleep 0.5; exit 0' TERM; sleep 10 & wait");
+        assert!(line.starts_with("Shutdown: command exited in "), "{line}");
+        assert!((450.0..1000.0).contains(&millis), "{line}");
+
+        let (line, _) = shutdown("trap '' TERM; sleep 10 & wait; sleep 10");
+        assert!(
+            line.starts_with("Shutdown: command killed after 2."),
+            "{line}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fifo_trigger() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("trigger");
+
+        let (tx, rx) = mpsc::channel();
+        spawn_fifo_reader(&fifo, tx).unwrap();
+        std::fs::write(&fifo, "rerun\n").unwrap();
+
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            Trigger::manual()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pty_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!(
+            "if [ -t 1 ] && [ -t 2 ]; then echo tty > {0}; else echo none > {0}; fi",
+            output.display()
+        ));
+        let mut commands = CommandList::from(command);
+
+        let options = SpawnOptions {
+            pty: true,
+            ..Default::default()
+        };
+        commands.spawn_in(&options, |res, _| res.unwrap().wait().unwrap().success());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "tty\n");
+
+        // the streams of the command are kept from one run to the next
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("if [ -t 1 ]; then echo tty; else echo none; fi; [ -t 2 ]")
+            .stdout(std::fs::File::create(&output).unwrap());
+        let mut commands = CommandList::from(command);
+        for _ in 0..2 {
+            commands.spawn_in(&options, |res, _| {
+                // the standard error is still in the pseudo-terminal
+                assert!(res.unwrap().wait().unwrap().success());
+                true
+            });
+        }
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "none\nnone\n");
+
+        // the output of the pseudo-terminal is copied and prefixed too
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("[ -t 1 ] && echo out; [ -t 2 ] && echo err >&2");
+        let mut commands = CommandList::from(command);
+        let copied = Arc::new(Mutex::new(Vec::new()));
+        let options = SpawnOptions {
+            pty: true,
+            tee: Some(dir.path().join("logs")),
+            prefix_output: true,
+            capture: Some(copied.clone()),
+            ..Default::default()
+        };
+        commands.spawn_in(&options, |res, _| res.unwrap().wait().unwrap().success());
+        assert_eq!(*copied.lock().unwrap(), b"[sh] out\n[sh] err\n");
+        let file = std::fs::read_dir(dir.path().join("logs"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(file.path()).unwrap(),
+            "[sh] out\n[sh] err\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pty_window_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("stty size <&2 > {}", output.display()));
+        let size = libc::winsize {
+            ws_row: 30,
+            ws_col: 100,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let (pty, slave) = Pty::open(Some(size)).unwrap();
+        command.stderr(slave);
+        let mut child = command.spawn().unwrap();
+        drop(command);
+        let sink = OutputSink {
+            stream: OutputStream::Buffer(Default::default()),
+            file: None,
+            prefix: None,
+        };
+        let (resizes, _) = pty.forward(child.id(), sink);
+        assert!(child.wait().unwrap().success());
+        drop(resizes);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "30 100\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_log_file() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_042);
+        assert_eq!(
+            run_log_line(time, ExitStatus::from_raw(0), &[]),
+            "1700000000.042 exit=0 initial\n",
+        );
+        assert_eq!(
+            run_log_line(time, ExitStatus::from_raw(15), &[PathBuf::from("a b")]),
+            "1700000000.042 signal=15 \"a b\"\n",
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("runs.log");
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("exit 3");
+
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default().log_file(&log);
+        let handle = thread::spawn(move || watch.watch_loop(command.into(), rx));
+        thread::sleep(Duration::from_millis(300));
+        tx.send(vec![modified("src/lib.rs")].into()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        drop(tx);
+        handle.join().unwrap();
+
+        let log = std::fs::read_to_string(&log).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2, "{log}");
+        assert!(lines[0].ends_with(" exit=3 initial"), "{log}");
+        assert!(lines[1].ends_with(" exit=3 \"src/lib.rs\""), "{log}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chmod_changes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let script = root.join("script.sh");
+        std::fs::write(&script, "").unwrap();
+
+        let chmod = |on_chmod: bool, mode: u32| {
+            let mut watch = Watch::default().watch_path(&root);
+            if on_chmod {
+                watch = watch.on_chmod();
+            }
+            let (tx, rx) = mpsc::channel();
+            let mut watcher =
+                notify::recommended_watcher(WatchEventHandler::new(watch.clone(), tx)).unwrap();
+            watch.watch_directories(&mut watcher, &root, &mut None);
+
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(mode)).unwrap();
+            thread::sleep(Duration::from_millis(500));
+            rx.try_iter().flatten().any(|x| x.path == script)
+        };
+
+        assert!(!chmod(false, 0o755));
+        assert!(chmod(true, 0o644));
+
+        let mut probe = EventProbe::new(Watch::default());
+        let permissions = notify::EventKind::Modify(notify::event::ModifyKind::Metadata(
+            notify::event::MetadataKind::Permissions,
+        ));
+        let write_time = notify::EventKind::Modify(notify::event::ModifyKind::Metadata(
+            notify::event::MetadataKind::WriteTime,
+        ));
+        assert!(!probe.send(permissions, &script));
+        assert!(probe.send(write_time, &script));
+    }
+
+    #[test]
+    fn trigger_events() {
+        let watch = Watch::try_parse_from(["watch", "--events", "create,remove"]).unwrap();
+        assert_eq!(watch.events, [TriggerEvent::Create, TriggerEvent::Remove]);
+
+        let err = Watch::try_parse_from(["watch", "--events", "create,rename"]).unwrap_err();
+        assert!(err.to_string().contains("invalid value 'rename'"), "{err}");
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let file = root.join("lib.rs");
+        std::fs::write(&file, "").unwrap();
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Data(
+            notify::event::DataChange::Any,
+        ));
+        let remove = notify::EventKind::Remove(notify::event::RemoveKind::File);
+
+        let mut probe = EventProbe::new(watch.debounce(Duration::ZERO).watch_path(&root));
+        assert!(probe.send(create(), &file));
+        assert!(!probe.send(modify, &file));
+        assert!(probe.send(remove, root.join("removed.rs")));
+
+        let mut probe = EventProbe::new(Watch::default().watch_path(&root));
+        assert!(probe.send(create(), &file));
+        assert!(probe.send(modify, &file));
+        assert!(!probe.send(remove, root.join("removed.rs")));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*", "foo"));
+        assert!(wildcard_match("f?o", "foo"));
+        assert!(wildcard_match("*-cli", "xtask-cli"));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(!wildcard_match("a*b*c", "aXbYbZ"));
+        assert!(!wildcard_match("*", ".git"));
+        assert!(wildcard_match(".*", ".git"));
+
+        let glob = Path::new("/ws/crates/*/src");
+        assert_eq!(glob_parent(glob), Path::new("/ws/crates"));
+        assert_eq!(
+            glob_match(glob, Path::new("/ws/crates")),
+            Some(GlobMatch::Partial)
+        );
+        assert_eq!(
+            glob_match(glob, Path::new("/ws/crates/a")),
+            Some(GlobMatch::Partial)
+        );
+        assert_eq!(
+            glob_match(glob, Path::new("/ws/crates/a/src")),
+            Some(GlobMatch::Full)
+        );
+        assert_eq!(glob_match(glob, Path::new("/ws/crates/a/docs")), None);
+        assert_eq!(glob_match(glob, Path::new("/ws/crates/a/src/lib.rs")), None);
+    }
+
+    #[test]
+    fn watch_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        for path in ["crates/a/src", "crates/a/docs", "crates/b/src"] {
+            std::fs::create_dir_all(root.join(path)).unwrap();
+        }
+
+        let mut watch = Watch::default().watch_path(root.join("crates/*/src"));
+        watch.resolve_watch_globs().unwrap();
+        assert_eq!(watch.watch_paths, Vec::<PathBuf>::new());
+        assert_eq!(watch.watch_globs, [root.join("crates/*/src")]);
+
+        let (tx, rx) = mpsc::channel();
+        let (dir_tx, dir_rx) = mpsc::channel();
+        let mut handler = WatchEventHandler::new(watch.clone(), tx);
+        handler.new_dirs = Some(dir_tx);
+        let watcher = Arc::new(Mutex::new(notify::recommended_watcher(handler).unwrap()));
+        watch.watch_glob_directories(
+            &mut *watcher.lock().unwrap(),
+            &root.join("crates"),
+            &mut None,
+        );
+        watch.spawn_directory_registrar(Arc::downgrade(&watcher), dir_rx, None, Vec::new());
+
+        for path in [
+            "crates/a/src/lib.rs",
+            "crates/a/docs/a.md",
+            "crates/b/src/lib.rs",
+        ] {
+            std::fs::write(root.join(path), "").unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        let mut paths: Vec<PathBuf> = rx.try_iter().flatten().map(|x| x.path).collect();
+        paths.dedup();
+        assert_eq!(
+            paths,
+            [
+                root.join("crates/a/src/lib.rs"),
+                root.join("crates/b/src/lib.rs")
+            ]
+        );
+
+        // a new crate is added
+        std::fs::create_dir_all(root.join("crates/c/src")).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        rx.try_iter().for_each(drop);
+        std::fs::write(root.join("crates/c/src/lib.rs"), "").unwrap();
+        thread::sleep(Duration::from_millis(500));
+        let paths: Vec<PathBuf> = rx.try_iter().flatten().map(|x| x.path).collect();
+        assert!(
+            paths.contains(&root.join("crates/c/src/lib.rs")),
+            "{paths:?}"
+        );
+    }
+
+    #[test]
+    fn since_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let since = root.join(".since");
+        std::fs::create_dir(root.join("src")).unwrap();
+        std::fs::write(root.join("src/old.rs"), "").unwrap();
+
+        let watch = Watch::default().watch_path(&root).since_file(&since);
+        assert_eq!(watch.changes_since_last_run(), []);
+
+        thread::sleep(Duration::from_millis(50));
+        let (tx, rx) = mpsc::channel();
+        let handle = {
+            let watch = watch.clone();
+            thread::spawn(move || watch.watch_loop(Command::new("true").into(), rx))
+        };
+        thread::sleep(Duration::from_millis(300));
+        drop(tx);
+        handle.join().unwrap();
+        assert!(parse_timestamp(&std::fs::read_to_string(&since).unwrap()).is_some());
+
+        // while the watch is down
+        thread::sleep(Duration::from_millis(50));
+        std::fs::write(root.join("src/new.rs"), "").unwrap();
+        std::fs::write(root.join("src/new.rs~"), "").unwrap();
+
+        assert_eq!(
+            watch.changes_since_last_run(),
+            [modified(root.join("src/new.rs"))]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn running_state() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("sleep 0.5");
+
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default();
+        let state = watch.clone();
+        assert!(!state.is_running());
+        let handle = thread::spawn(move || watch.watch_loop(command.into(), rx));
+
+        thread::sleep(Duration::from_millis(200));
+        assert!(state.is_running());
+        thread::sleep(Duration::from_millis(600));
+        assert!(!state.is_running());
+
+        drop(tx);
+        handle.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn last_success_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let flag = dir.path().join("fail");
+        let log = dir.path().join("runs");
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!(
+            "echo run >> {}; test ! -e {}",
+            log.display(),
+            flag.display()
+        ));
+
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default();
+        let state = watch.clone();
+        assert_eq!(state.last_success(), None);
+        let handle = thread::spawn(move || watch.watch_loop(command.into(), rx));
+        let wait_for_run = |count: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                let runs = std::fs::read_to_string(&log).map_or(0, |x| x.lines().count());
+                if runs == count && !state.is_running() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("run {count} did not finish");
+        };
+
+        wait_for_run(1);
+        let last_success = state.last_success();
+        assert!(last_success.is_some());
+
+        std::fs::write(&flag, "").unwrap();
+        tx.send(vec![modified(&flag)].into()).unwrap();
+        wait_for_run(2);
+        assert_eq!(state.last_success(), last_success);
+
+        drop(tx);
+        handle.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_numbers() {
+        capture_logs();
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default();
+        let handle = thread::spawn(move || watch.watch_loop(Command::new("true").into(), rx));
+        thread::sleep(Duration::from_millis(200));
+        tx.send(vec![modified("src/lib.rs")].into()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        drop(tx);
+        handle.join().unwrap();
+
+        let logs = CAPTURED_LOGS.lock().unwrap();
+        for run in 1..=2 {
+            let position = |message: &str| {
+                logs.iter()
+                    .position(|x| x.starts_with(&format!("[run #{run}] {message}")))
+                    .unwrap_or_else(|| panic!("no {message:?} for run {run} in {logs:?}"))
+            };
+            assert!(position("Re-running command") < position("Command succeeded in"));
+        }
+    }
+
+    #[test]
+    fn profile() {
+        capture_logs();
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default().profile();
+        let handle = thread::spawn(move || watch.watch_loop(Command::new("true").into(), rx));
+        thread::sleep(Duration::from_millis(200));
+        tx.send(vec![modified("profile.rs")].into()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        drop(tx);
+        handle.join().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let mut probe = EventProbe::new(Watch::default().watch_path(&root).profile());
+        std::fs::write(root.join("a.rs"), "").unwrap();
+        assert!(probe.send(create(), root.join("a.rs")));
+
+        let logs = CAPTURED_LOGS.lock().unwrap();
+        for message in [
+            "Profile: event accepted in",
+            "[run #1] Profile: previous run stopped in",
+            "[run #2] Profile: command spawned in",
+            "[run #2] Profile: command ran in",
+        ] {
+            assert!(
+                logs.iter().any(|x| x.starts_with(message)),
+                "no {message:?} in {logs:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn show_reason() {
+        let watch = Watch::default().watch_path("/workspace").show_reason();
+        let files = ["src/lib.rs", "src/main.rs", "src/lib.rs"]
+            .map(|x| modified(Path::new("/workspace").join(x)))
+            .to_vec();
+        assert_eq!(
+            watch.trigger_tag(&Trigger::from(files[..1].to_vec())),
+            "[file: src/lib.rs]"
+        );
+        assert_eq!(
+            watch.trigger_tag(&Trigger::from(files)),
+            "[file: src/lib.rs and 1 more]"
+        );
+
+        capture_logs();
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || watch.watch_loop(Command::new("true").into(), rx));
+        thread::sleep(Duration::from_millis(200));
+        tx.send(Trigger::manual()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        drop(tx);
+        handle.join().unwrap();
+
+        let logs = CAPTURED_LOGS.lock().unwrap();
+        for line in [
+            "[run #1] Re-running command [initial]",
+            "[run #2] Re-running command [manual]",
+        ] {
+            assert!(logs.contains(&line.to_string()), "no {line:?} in {logs:?}");
+        }
+    }
+
+    #[test]
+    fn custom_summary() {
+        capture_logs();
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default().summary_formatter(|status, _, changes| {
+            format!("custom summary: {} {changes:?}", status.success())
+        });
+        let handle = thread::spawn(move || watch.watch_loop(Command::new("true").into(), rx));
+        thread::sleep(Duration::from_millis(200));
+        tx.send(vec![modified("summary.rs")].into()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        drop(tx);
+        handle.join().unwrap();
+
+        let logs = CAPTURED_LOGS.lock().unwrap();
+        assert!(logs.contains(&"[run #1] custom summary: true []".to_string()));
+        assert!(logs.contains(&"[run #2] custom summary: true [\"summary.rs\"]".to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clean_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("env");
+        assert!(env::var_os("PATH").is_some());
+
+        let mut command = Command::new("env");
+        command
+            .stdout(std::fs::File::create(&output).unwrap())
+            .env("EXPLICIT", "explicit");
+        let mut commands = CommandList::from(command);
+        commands.clear_env();
+        commands.spawn(|res| res.unwrap().wait().unwrap().success());
+
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "EXPLICIT=explicit\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("done");
+
+        let mut sleep = Command::new("sleep");
+        sleep.arg("5");
+        let mut done = Command::new("sh");
+        done.arg("-c")
+            .arg(format!("sleep 0.2; touch {}", output.display()));
+        let mut commands = CommandList::from(Vec::new())
+            .with_timeout(sleep, Duration::from_millis(100))
+            .with_timeout(done, Duration::from_secs(5));
+
+        let start = Instant::now();
+        let mut statuses = Vec::new();
+        commands.spawn_in(&SpawnOptions::default(), |res, spawned| {
+            let mut child = SharedChild::new();
+            child.replace(res.unwrap());
+            statuses.push(child.wait(spawned.timeout));
+            true
+        });
+
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(statuses.len(), 2);
+        assert!(!statuses[0].success());
+        assert!(statuses[1].success());
+        assert!(output.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tee_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = dir.path().join("logs");
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo out; echo err >&2");
+        let mut commands = CommandList::from([command, Command::new("true")]);
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let options = SpawnOptions {
+            tee: Some(logs.clone()),
+            capture: Some(output.clone()),
+            ..Default::default()
+        };
+        commands.spawn_in(&options, |res, _| res.unwrap().wait().unwrap().success());
+        let mut copied: Vec<String> = String::from_utf8(output.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        copied.sort();
+        assert_eq!(copied, ["err", "out"]);
+
+        let files: Vec<PathBuf> = std::fs::read_dir(&logs)
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with("run-") && name.ends_with(".log"), "{name}");
+        let mut lines: Vec<String> = std::fs::read_to_string(&files[0])
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        assert_eq!(lines, ["err", "out"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tee_keeps_command_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = dir.path().join("logs");
+        let output = dir.path().join("output");
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("echo out; echo err >&2")
+            .stdout(std::fs::File::create(&output).unwrap());
+        let mut commands = CommandList::from(command);
+        let copied = Arc::new(Mutex::new(Vec::new()));
+        let options = SpawnOptions {
+            tee: Some(logs.clone()),
+            prefix_output: true,
+            capture: Some(copied.clone()),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            commands.spawn_in(&options, |res, _| res.unwrap().wait().unwrap().success());
+        }
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "out\nout\n");
+        assert_eq!(*copied.lock().unwrap(), b"[sh] err\n[sh] err\n");
+        for file in std::fs::read_dir(&logs).unwrap() {
+            let content = std::fs::read_to_string(file.unwrap().path()).unwrap();
+            assert!(!content.contains("out"), "{content}");
+        }
+
+        // a process left running by the command doesn't hold the run
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("sleep 5 & echo started");
+        let mut commands = CommandList::from(command);
+        let start = Instant::now();
+        commands.spawn_in(&options, |res, _| res.unwrap().wait().unwrap().success());
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn prefix_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = dir.path().join("logs");
+
+        let sh = |script: &str| {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(script);
+            command
+        };
+        let mut commands = CommandList::from([
+            sh("echo one; echo two >&2"),
+            Command::new("true"),
+            sh("printf three"),
+        ]);
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let options = SpawnOptions {
+            tee: Some(logs.clone()),
+            prefix_output: true,
+            capture: Some(output.clone()),
+            ..Default::default()
+        };
+        commands.spawn_in(&options, |res, _| res.unwrap().wait().unwrap().success());
+        let mut copied: Vec<String> = String::from_utf8(output.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        copied.sort();
+        assert_eq!(copied, ["[sh#1] one", "[sh#1] two", "[sh#3] three"]);
+
+        let file = std::fs::read_dir(&logs).unwrap().next().unwrap().unwrap();
+        let mut lines: Vec<String> = std::fs::read_to_string(file.path())
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        assert_eq!(lines, ["[sh#1] one", "[sh#1] two", "[sh#3] three"]);
+
+        assert_eq!(
+            output_prefixes(&[
+                CommandSpec::from(Command::new("/usr/bin/cargo")),
+                CommandSpec::from(Command::new("npm")),
+            ]),
+            ["[cargo] ", "[npm] "]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cargo_build_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("target");
+
+        let echo_target = |explicit: Option<&str>| {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(format!(
+                "echo \"$CARGO_BUILD_TARGET\" >> {}",
+                output.display()
+            ));
+            if let Some(target) = explicit {
+                command.env("CARGO_BUILD_TARGET", target);
+            }
+            command
+        };
+        let mut commands = CommandList::from([
+            echo_target(None),
+            echo_target(Some("x86_64-unknown-linux-gnu")),
+        ]);
+        commands.default_env("CARGO_BUILD_TARGET", "wasm32-unknown-unknown");
+        commands.clear_env();
+        // every run gets the variable
+        for _ in 0..2 {
+            commands.spawn(|res| res.unwrap().wait().unwrap().success());
+        }
+
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "wasm32-unknown-unknown\nx86_64-unknown-linux-gnu\n".repeat(2)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skip_initial() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo run >> {}", output.display()));
+        let commands = CommandList::from(command);
+        let runs = || std::fs::read_to_string(&output).unwrap_or_default();
+
+        let watch = Watch::try_parse_from(["watch", "--skip-initial"]).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn({
+            let commands = commands.clone();
+            move || watch.watch_loop(commands, rx)
+        });
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(runs(), "");
+        tx.send(vec![modified("src/lib.rs")].into()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(runs(), "run\n");
+        drop(tx);
+        handle.join().unwrap();
+
+        std::fs::remove_file(&output).unwrap();
+        let watch = Watch::try_parse_from(["watch", "--skip-initial", "--run-now"]).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || watch.watch_loop(commands, rx));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(runs(), "run\n");
+        drop(tx);
+        handle.join().unwrap();
+        assert_eq!(runs(), "run\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn missing_program() {
+        let err = Watch::default()
+            .on_missing_program(MissingProgram::Abort)
+            .run(Command::new("xtask-watch-missing-program"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "program `xtask-watch-missing-program` not found"
+        );
+
+        // a command that fails keeps the watch going
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default();
+        let handle = thread::spawn({
+            let watch = watch.clone();
+            move || watch.watch_loop(Command::new("false").into(), rx)
+        });
+        thread::sleep(Duration::from_millis(300));
+        assert!(!watch.is_stopping());
+        drop(tx);
+        handle.join().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default();
+        let handle = thread::spawn({
+            let watch = watch.clone();
+            move || watch.watch_loop(Command::new("xtask-watch-missing-program").into(), rx)
+        });
+        thread::sleep(Duration::from_millis(300));
+        assert!(!watch.is_stopping());
+        drop(tx);
+        handle.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn teardown_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("teardown");
+        let mut teardown = Command::new("sh");
+        teardown
+            .arg("-c")
+            .arg(format!("echo teardown >> {}", log.display()));
+
+        let watch = Watch::default()
+            .exclude_path(dir.path().join("missing"))
+            .teardown_command(teardown);
+        assert!(watch.run(Command::new("true")).is_err());
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "teardown\n");
     }
 
-    fn replace(&mut self, child: impl Into<Option<Child>>) {
-        *self.child.lock().expect("not poisoned") = child.into();
+    #[test]
+    fn interrupt_stops_teardown_watch() {
+        let interrupt =
+            || SIGNALS_RECEIVED[WatchSignal::Interrupt as usize].fetch_add(1, Ordering::SeqCst);
+        // received by a previous watch
+        interrupt();
+
+        let (_tx, rx) = mpsc::channel();
+        let watch = Watch::default().teardown_command(Command::new("true"));
+        let handle = thread::spawn({
+            let watch = watch.clone();
+            move || watch.watch_loop(Command::new("true").into(), rx)
+        });
+        thread::sleep(Duration::from_millis(300));
+        assert!(!handle.is_finished());
+        assert!(!watch.is_stopping());
+
+        interrupt();
+        handle.join().unwrap();
     }
 
-    fn wait(&mut self) -> ExitStatus {
-        loop {
-            let mut child = self.child.lock().expect("not poisoned");
-            match child.as_mut().map(|child| child.try_wait()) {
-                Some(Ok(Some(status))) => {
-                    break status;
-                }
-                Some(Ok(None)) => {
-                    drop(child);
-                    thread::sleep(Duration::from_millis(10));
-                }
-                Some(Err(err)) => {
-                    log::error!("could not wait for child process: {err}");
-                    break Default::default();
-                }
-                None => {
-                    break Default::default();
-                }
+    #[cfg(unix)]
+    #[test]
+    fn detach_on_exit() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let alive_after_exit = |watch: Watch| {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("echo $$ > {}; exec sleep 5", pid_file.display()));
+            let (tx, rx) = mpsc::channel();
+            let handle = thread::spawn(move || watch.watch_loop(command.into(), rx));
+            thread::sleep(Duration::from_millis(300));
+            drop(tx);
+            handle.join().unwrap();
+
+            let pid: libc::pid_t = std::fs::read_to_string(&pid_file)
+                .unwrap()
+                .trim()
+                .parse()
+                .unwrap();
+            let alive = unsafe { libc::kill(pid, 0) } == 0;
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
             }
-        }
+            alive
+        };
+
+        assert!(alive_after_exit(Watch::default().detach_on_exit()));
+        assert!(!alive_after_exit(Watch::default()));
     }
 
-    fn terminate(&mut self) {
-        if let Some(child) = self.child.lock().expect("not poisoned").as_mut() {
-            #[cfg(unix)]
-            {
-                let killing_start = Instant::now();
+    #[cfg(unix)]
+    #[test]
+    fn control_socket() {
+        use std::os::unix::net::UnixStream;
 
-                unsafe {
-                    log::trace!("sending SIGTERM to {}", child.id());
-                    libc::kill(child.id() as _, libc::SIGTERM);
-                }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let watch = Watch::default();
+        let (tx, rx) = mpsc::channel();
+        watch.spawn_control_socket(&path, tx).unwrap();
 
-                while killing_start.elapsed().as_secs() < 2 {
-                    std::thread::sleep(Duration::from_millis(200));
-                    if let Ok(Some(_)) = child.try_wait() {
-                        break;
-                    }
-                }
-            }
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let mut replies = io::BufRead::lines(io::BufReader::new(stream.try_clone().unwrap()));
+        let mut send = |command: &str| {
+            writeln!(stream, "{command}").unwrap();
+            replies.next().unwrap().unwrap()
+        };
 
-            match child.try_wait() {
-                Ok(Some(_)) => {}
-                _ => {
-                    log::trace!("killing {}", child.id());
-                    let _ = child.kill();
-                    let _ = child.wait();
-                }
-            }
-        } else {
-            log::trace!("nothing to terminate");
-        }
+        assert_eq!(send("ping"), "pong");
+        assert_eq!(send("status"), "idle");
+        assert_eq!(send("rerun"), "ok");
+        assert_eq!(rx.try_recv().unwrap(), Trigger::manual());
+        assert_eq!(send("foo"), "unknown command");
+        assert!(!watch.is_stopping());
+        assert_eq!(send("stop"), "ok");
+        assert!(watch.is_stopping());
     }
-}
 
-/// A list of commands to run.
-#[derive(Debug, Clone)]
-pub struct CommandList {
-    commands: Arc<Mutex<Vec<Command>>>,
-}
+    #[cfg(unix)]
+    #[test]
+    fn control_socket_changes() {
+        use std::os::unix::net::UnixStream;
 
-impl From<Command> for CommandList {
-    fn from(command: Command) -> Self {
-        Self {
-            commands: Arc::new(Mutex::new(vec![command])),
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let (tx, rx) = mpsc::channel();
+        Watch::default().spawn_control_socket(&path, tx).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let mut replies = io::BufRead::lines(io::BufReader::new(stream.try_clone().unwrap()));
+        let mut send = |command: &str| {
+            writeln!(stream, "{command}").unwrap();
+            replies.next().unwrap().unwrap()
+        };
+
+        assert_eq!(
+            send(r#"{"changed": ["/project/src/lib.rs", "/project/build.rs"]}"#),
+            "ok"
+        );
+        let trigger = rx.try_recv().unwrap();
+        assert_eq!(trigger.reason, TriggerReason::File);
+        assert_eq!(
+            trigger.changes,
+            [
+                modified("/project/src/lib.rs"),
+                modified("/project/build.rs")
+            ]
+        );
+
+        assert_eq!(send(r#"{"changed": ["src/lib.rs"]}"#), "ok");
+        assert_eq!(
+            rx.try_recv().unwrap().changes,
+            [modified(metadata().workspace_root.join("src/lib.rs"))]
+        );
+
+        for (message, reply) in [
+            (r#"{"changed": []}"#, "invalid message: no changed path"),
+            (
+                r#"{"changed": [1]}"#,
+                "invalid message: paths must be strings",
+            ),
+            (r#"{"rerun": true}"#, "invalid message: no `changed` array"),
+        ] {
+            assert_eq!(send(message), reply);
         }
+        assert!(send(r#"{"changed""#).starts_with("invalid message: "));
+        assert!(rx.try_recv().is_err());
     }
-}
 
-impl From<Vec<Command>> for CommandList {
-    fn from(commands: Vec<Command>) -> Self {
-        Self {
-            commands: Arc::new(Mutex::new(commands)),
-        }
+    #[cfg(unix)]
+    #[test]
+    fn restart_crashed_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let command = |code: u8| {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("echo run >> {}; exit {code}", log.display()));
+            command
+        };
+        let runs = || std::fs::read_to_string(&log).unwrap().lines().count();
+
+        let (_tx, rx) = mpsc::channel();
+        let watch = Watch::default().restart_on_crash();
+        let stop = watch.clone();
+        let commands = CommandList::from(command(0));
+        let handle = thread::spawn(move || watch.watch_loop(commands, rx));
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(runs(), 1);
+        stop.stopped.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+
+        std::fs::remove_file(&log).unwrap();
+        let (_tx, rx) = mpsc::channel();
+        let watch = Watch::default().restart_on_crash();
+        let stop = watch.clone();
+        let commands = CommandList::from(command(1));
+        let handle = thread::spawn(move || watch.watch_loop(commands, rx));
+        thread::sleep(Duration::from_millis(400));
+        assert!(runs() > 1);
+        stop.stopped.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
     }
-}
 
-impl<const SIZE: usize> From<[Command; SIZE]> for CommandList {
-    fn from(commands: [Command; SIZE]) -> Self {
-        Self {
-            commands: Arc::new(Mutex::new(Vec::from(commands))),
+    #[cfg(unix)]
+    #[test]
+    fn changes_summary() {
+        let watch = Watch::default().watch_path("/workspace");
+        assert_eq!(
+            watch.changes_summary(&[modified("/workspace/src/lib.rs")]),
+            "Detected changes in 1 file: src/lib.rs"
+        );
+
+        capture_logs();
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || watch.watch_loop(Command::new("true").into(), rx));
+        let batch = ["a.rs", "b.rs", "c.rs", "a.rs", "d.rs", "e.rs"]
+            .map(|x| modified(Path::new("/workspace/src").join(x)))
+            .to_vec();
+        tx.send(batch.into()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        drop(tx);
+        handle.join().unwrap();
+
+        let logs = CAPTURED_LOGS.lock().unwrap();
+        assert!(logs.contains(
+            &"Detected changes in 5 files: src/a.rs, src/b.rs, src/c.rs and 2 more".to_string()
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn null_stdin() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("stdin");
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("readlink /proc/self/fd/0 > {}", output.display()));
+        let mut commands = CommandList::from(command);
+        let options = SpawnOptions {
+            stdin: ChildStdin::Null,
+            ..Default::default()
+        };
+        commands.spawn_in(&options, |res, _| res.unwrap().wait().unwrap().success());
+
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "/dev/null\n");
+    }
+
+    #[test]
+    fn changes_by_kind() {
+        let watch = Watch::default().watch_path("/workspace");
+        let change = |path: &str, kind| Change::new(Path::new("/workspace").join(path), kind);
+        let changes = [
+            change("src/b.rs", ChangeKind::Modified),
+            change("src/old.rs", ChangeKind::Removed),
+            change("src/a.rs", ChangeKind::Modified),
+            change("src/new.rs", ChangeKind::Created),
+            change("src/b.rs", ChangeKind::Modified),
+        ];
+
+        assert_eq!(
+            watch.changes_by_kind(&changes),
+            [
+                "Created: src/new.rs",
+                "Modified: src/a.rs, src/b.rs",
+                "Removed: src/old.rs",
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn niceness() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("nice");
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("nice > {}", output.display()));
+        let mut commands = CommandList::from(command);
+        commands.set_niceness(42);
+        commands.spawn(|res| res.unwrap().wait().unwrap().success());
+
+        assert_eq!(std::fs::read_to_string(&output).unwrap().trim(), "19");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("umask");
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!("touch {}", output.display()));
+        let mut commands = CommandList::from(command);
+        commands.set_umask(parse_umask("027").unwrap());
+        commands.spawn(|res| res.unwrap().wait().unwrap().success());
+
+        let mode = std::fs::metadata(&output).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        assert_eq!(parse_umask("0o22"), Ok(0o22));
+        assert!(parse_umask("1000").is_err());
+        assert!(parse_umask("8").is_err());
+    }
+
+    #[test]
+    fn include_submodules() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let submodule = root.join("vendor/my lib");
+        for path in [root.join("src"), submodule.join(".git"), root.join("docs")] {
+            std::fs::create_dir_all(path).unwrap();
+        }
+        std::fs::write(
+            root.join(".gitmodules"),
+            "[submodule \"my lib\"]\n\
+             \tpath = vendor/my lib\n\
+             \turl = https://example.com/lib.git\n\
+             [submodule \"missing\"]\n\
+             \tpath = vendor/missing\n\
+             \turl = https://example.com/missing.git\n",
+        )
+        .unwrap();
+        for file in [submodule.join("lib.rs"), submodule.join(".git/index")] {
+            std::fs::write(file, "").unwrap();
         }
+
+        assert!(submodule_paths(&root.join("src")).unwrap().is_empty());
+        assert_eq!(
+            submodule_paths(&root).unwrap(),
+            std::slice::from_ref(&submodule)
+        );
+
+        let mut watch = Watch::default()
+            .watch_path(root.join("src"))
+            .include_submodules()
+            .include_hidden();
+        let submodules = watch.add_submodules(&root).unwrap();
+        watch.watch_paths.extend(submodules);
+        watch.resolve_exclude_paths(&[root.join("target")]).unwrap();
+        watch.resolve_watch_paths().unwrap();
+
+        let mut probe = EventProbe::new(watch);
+        assert!(probe.send(create(), submodule.join("lib.rs")));
+        assert!(!probe.send(create(), submodule.join(".git/index")));
+        assert!(!probe.send(create(), root.join("docs/book.md")));
     }
-}
 
-impl CommandList {
-    /// Returns `true` if the list is empty.
-    pub fn is_empty(&self) -> bool {
-        self.commands.lock().expect("not poisoned").is_empty()
+    #[test]
+    fn git_head_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let git_dir = root.join(".git");
+        std::fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+        std::fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        std::fs::write(git_dir.join("refs/heads/main"), "").unwrap();
+        std::fs::write(root.join("lib.rs"), "").unwrap();
+
+        let mut probe = EventProbe::new(Watch::default().watch_path(&root));
+        probe.handler.git_dir = Some(git_dir.clone());
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Any);
+
+        assert!(!probe.send(modify, git_dir.join("refs/heads/main.lock")));
+        assert!(probe.send(modify, git_dir.join("refs/heads/main")));
+        assert!(!probe.send(modify, git_dir.join("HEAD")));
+        assert!(!probe.send(modify, root.join("lib.rs")));
     }
 
-    /// Spawn each command of the list one after the other.
-    ///
-    /// The caller is responsible to wait the commands.
-    pub fn spawn(&mut self, mut callback: impl FnMut(io::Result<Child>) -> bool) {
-        for process in self.commands.lock().expect("not poisoned").iter_mut() {
-            if !callback(process.spawn()) {
-                break;
-            }
+    #[test]
+    fn include_hidden() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let config = root.join(".config/app.toml");
+        std::fs::create_dir_all(config.parent().unwrap()).unwrap();
+        std::fs::write(&config, "").unwrap();
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Data(
+            notify::event::DataChange::Any,
+        ));
+
+        let watch = Watch::default().watch_path(&root);
+        assert!(!EventProbe::new(watch.clone()).send(modify, &config));
+        assert!(!watch
+            .watched_directories(&root, None)
+            .contains(&root.join(".config")));
+
+        let watch = watch.include_hidden();
+        assert!(EventProbe::new(watch.clone()).send(modify, &config));
+        assert!(watch
+            .watched_directories(&root, None)
+            .contains(&root.join(".config")));
+    }
+
+    #[test]
+    fn inbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let inbox = root.join("inbox");
+        std::fs::create_dir_all(&inbox).unwrap();
+        for file in [
+            "inbox/old.csv",
+            "inbox/new.csv",
+            "inbox/moved.csv",
+            "lib.rs",
+        ] {
+            std::fs::write(root.join(file), "").unwrap();
         }
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Data(
+            notify::event::DataChange::Any,
+        ));
+        let rename = |mode| notify::EventKind::Modify(notify::event::ModifyKind::Name(mode));
+
+        let watch = Watch::default().watch_path(&root).inbox(&inbox);
+        let mut probe = EventProbe::new(watch);
+        assert!(probe.send(create(), inbox.join("new.csv")));
+        assert!(probe.send(
+            rename(notify::event::RenameMode::To),
+            inbox.join("moved.csv")
+        ));
+        assert!(!probe.send(modify, inbox.join("old.csv")));
+        assert!(!probe.send(
+            rename(notify::event::RenameMode::From),
+            inbox.join("old.csv")
+        ));
+        assert!(probe.send(modify, root.join("lib.rs")));
     }
 
-    /// Run all the commands sequentially using [`std::process::Command::status`] and stop at the
-    /// first failure.
-    pub fn status(&mut self) -> io::Result<ExitStatus> {
-        for process in self.commands.lock().expect("not poisoned").iter_mut() {
-            let exit_status = process.status()?;
-            if !exit_status.success() {
-                return Ok(exit_status);
-            }
+    #[test]
+    fn watch_hidden() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        for path in [".git", ".sqlx"] {
+            std::fs::create_dir_all(root.join(path)).unwrap();
         }
-        Ok(Default::default())
+        for file in [".env", ".env.local", ".git/index", ".sqlx/query.json"] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Data(
+            notify::event::DataChange::Any,
+        ));
+
+        let watch = Watch::try_parse_from(["watch", "--watch-hidden", ".env"])
+            .unwrap()
+            .debounce(Duration::ZERO)
+            .watch_path(&root)
+            .watch_hidden(".sqlx");
+        let mut probe = EventProbe::new(watch.clone());
+        assert!(probe.send(modify, root.join(".env")));
+        assert!(probe.send(modify, root.join(".sqlx/query.json")));
+        assert!(!probe.send(modify, root.join(".env.local")));
+        assert!(!probe.send(modify, root.join(".git/index")));
+
+        let dirs = watch.watched_directories(&root, None);
+        assert!(dirs.contains(&root.join(".sqlx")));
+        assert!(!dirs.contains(&root.join(".git")));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn directory_debounce() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("src/foo")).unwrap();
+        for path in ["src/foo/a.rs", "src/foo/b.rs", "src/foo/c.rs", "src/lib.rs"] {
+            std::fs::write(root.join(path), "").unwrap();
+        }
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Data(
+            notify::event::DataChange::Any,
+        ));
+
+        let mut probe = EventProbe::new(
+            Watch::default()
+                .watch_path(&root)
+                .directory_debounce(Duration::from_secs(1)),
+        );
+        assert!(!probe.send(modify, root.join("src/foo/a.rs")));
+        thread::sleep(Duration::from_millis(100));
+        assert!(!probe.send(modify, root.join("src/lib.rs")));
+        thread::sleep(Duration::from_millis(300));
+        assert!(!probe.send(modify, root.join("src/foo/b.rs")));
+        assert!(!probe.send(modify, root.join("src/foo/c.rs")));
+        assert!(!probe.send(modify, root.join("src/foo/a.rs")));
+
+        // sent once each directory is quiet, with all its changes
+        let paths = |trigger: Trigger| trigger.into_iter().map(|x| x.path).collect::<Vec<_>>();
+        let first = probe.rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(paths(first), [root.join("src/lib.rs")]);
+        let second = probe.rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            paths(second),
+            ["src/foo/a.rs", "src/foo/b.rs", "src/foo/c.rs"].map(|x| root.join(x))
+        );
+
+        let mut probe = EventProbe::new(Watch::default().watch_path(&root));
+        assert!(probe.send(modify, root.join("src/foo/a.rs")));
+        assert!(probe.send(modify, root.join("src/foo/b.rs")));
+    }
 
     #[test]
-    fn exclude_relative_path() {
-        let watch = Watch {
-            debounce: Default::default(),
-            watch_paths: Vec::new(),
-            exclude_paths: Vec::new(),
-            workspace_exclude_paths: vec![PathBuf::from("src/watch.rs")],
-        };
+    fn file_debounce() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        for path in ["a.rs", "b.rs"] {
+            std::fs::write(root.join(path), "").unwrap();
+        }
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Data(
+            notify::event::DataChange::Any,
+        ));
 
-        assert!(watch.is_excluded_path(
-            metadata()
-                .workspace_root
-                .join("src")
-                .join("watch.rs")
-                .as_std_path()
+        let watch = Watch::try_parse_from(["xtask", "--file-debounce", "200"])
+            .unwrap()
+            .watch_path(&root)
+            .debounce(Duration::from_millis(20));
+        assert_eq!(watch.file_debounce, Some(Duration::from_millis(200)));
+        let mut probe = EventProbe::new(watch);
+        thread::sleep(Duration::from_millis(50));
+        assert!(probe.send(modify, root.join("a.rs")));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!probe.send(modify, root.join("a.rs")));
+        assert!(probe.send(modify, root.join("b.rs")));
+
+        let mut probe = EventProbe::new(
+            Watch::default()
+                .watch_path(&root)
+                .debounce(Duration::from_millis(20)),
+        );
+        thread::sleep(Duration::from_millis(50));
+        assert!(probe.send(modify, root.join("a.rs")));
+        thread::sleep(Duration::from_millis(50));
+        assert!(probe.send(modify, root.join("a.rs")));
+    }
+
+    #[test]
+    fn startup_quiet() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::write(root.join("lib.rs"), "").unwrap();
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Data(
+            notify::event::DataChange::Any,
         ));
-        assert!(!watch.is_excluded_path(metadata().workspace_root.join("src").as_std_path()));
+
+        let watch = Watch::try_parse_from(["xtask", "--startup-quiet", "0.2"])
+            .unwrap()
+            .watch_path(&root)
+            .debounce(Duration::ZERO);
+        assert_eq!(watch.startup_quiet, Some(Duration::from_millis(200)));
+        assert!(Watch::try_parse_from(["xtask", "--startup-quiet", "-1"]).is_err());
+        let mut probe = EventProbe::new(watch);
+        assert!(!probe.send(modify, root.join("lib.rs")));
+        thread::sleep(Duration::from_millis(250));
+        assert!(probe.send(modify, root.join("lib.rs")));
+
+        let mut probe = EventProbe::new(Watch::default().watch_path(&root));
+        assert!(probe.send(modify, root.join("lib.rs")));
+    }
+
+    #[test]
+    fn events_overflow() {
+        let mut probe = EventProbe::new(Watch::default());
+        let overflow = Event::new(notify::EventKind::Other).set_flag(notify::event::Flag::Rescan);
+        probe.handler.handle_event(Ok(overflow));
+        assert_eq!(
+            probe.rx.try_iter().collect::<Vec<_>>(),
+            [Trigger::new(TriggerReason::Rescan, Vec::new())]
+        );
+
+        probe
+            .handler
+            .handle_event(Ok(Event::new(notify::EventKind::Other)));
+        assert_eq!(probe.rx.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn receiver_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let mut probe = EventProbe::new(Watch::default().watch_path(&root));
+        drop(probe.rx);
+
+        let (_, rx) = mpsc::channel();
+        probe.rx = rx;
+        assert!(!probe.send(create(), &root));
+        assert!(probe.handler.disconnected);
+        assert!(!probe.send(create(), &root));
+    }
+
+    #[test]
+    fn main_command_line() {
+        let MainOpt::Watch { command, watch } =
+            MainOpt::try_parse_from(["xtask", "watch", "-w", "src", "cargo", "test", "--release"])
+                .unwrap();
+        assert_eq!(command, ["cargo", "test", "--release"]);
+        assert_eq!(watch.watch_paths, [PathBuf::from("src")]);
+
+        let MainOpt::Watch { command, .. } = MainOpt::try_parse_from(["xtask", "watch"]).unwrap();
+        assert!(command.is_empty());
     }
 
     #[test]
@@ -603,4 +10022,290 @@ mod test {
         let _: CommandList = vec![Command::new("foo")].into();
         let _: CommandList = [Command::new("foo")].into();
     }
+
+    fn command_list_argvs(commands: &CommandList) -> Vec<Vec<String>> {
+        commands
+            .describe()
+            .into_iter()
+            .map(|x| {
+                std::iter::once(x.program)
+                    .chain(x.args)
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn command_list_describe() {
+        let mut build = Command::new("cargo");
+        build
+            .args(["build", "--release"])
+            .env("RUSTFLAGS", "-Dwarnings")
+            .env_remove("CARGO_TARGET_DIR")
+            .current_dir("frontend");
+        let commands = CommandList::from([build, Command::new("true")]);
+
+        assert_eq!(
+            commands.describe(),
+            [
+                CommandDescription {
+                    program: "cargo".into(),
+                    args: vec!["build".into(), "--release".into()],
+                    envs: vec![
+                        ("CARGO_TARGET_DIR".into(), None),
+                        ("RUSTFLAGS".into(), Some("-Dwarnings".into())),
+                    ],
+                    current_dir: Some("frontend".into()),
+                },
+                CommandDescription {
+                    program: "true".into(),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn command_list_from_argv() {
+        let commands = CommandList::from_argv(["cargo", "check", "--workspace"]);
+
+        assert_eq!(
+            command_list_argvs(&commands),
+            [["cargo", "check", "--workspace"]]
+        );
+    }
+
+    #[test]
+    fn command_list_from_argvs() {
+        let commands = CommandList::from_argvs([
+            vec!["cargo", "check"],
+            vec!["cargo", "test", "--workspace"],
+            vec!["true"],
+        ]);
+
+        assert_eq!(
+            command_list_argvs(&commands),
+            [
+                vec!["cargo", "check"],
+                vec!["cargo", "test", "--workspace"],
+                vec!["true"],
+            ]
+        );
+        assert!(CommandList::from_argvs(Vec::<Vec<&str>>::new()).is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn command_list_from_empty_argv() {
+        CommandList::from_argv(Vec::<&str>::new());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serialized_reruns() {
+        let dir = tempfile::tempdir().unwrap();
+        // like binding a port, creating the directory fails if another
+        // command holds it
+        let script = format!(
+            "cd {}; trap 'rmdir lock 2>/dev/null; exit 1' TERM; \
+             if mkdir lock; then echo run >> runs; else echo conflict >> conflicts; fi; \
+             sleep 0.05 & wait; rmdir lock",
+            dir.path().display()
+        );
+        let command = || {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&script);
+            command
+        };
+        let commands = CommandList::from(Vec::new())
+            .with_condition(command(), RunCondition::Always)
+            .with_condition(command(), RunCondition::Always)
+            .with_condition(command(), RunCondition::Always);
+
+        let (tx, rx) = mpsc::channel();
+        let watch = Watch::default();
+        let handle = thread::spawn(move || watch.watch_loop(commands, rx));
+        for i in 0..40 {
+            thread::sleep(Duration::from_millis(10 + i % 4 * 10));
+            tx.send(Trigger::manual()).unwrap();
+        }
+        thread::sleep(Duration::from_millis(300));
+        drop(tx);
+        handle.join().unwrap();
+
+        assert!(dir.path().join("runs").exists());
+        assert!(
+            !dir.path().join("conflicts").exists(),
+            "{:?}",
+            std::fs::read_to_string(dir.path().join("conflicts"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_list_conditions() {
+        let dir = tempfile::tempdir().unwrap();
+        let touch = |name: &str| {
+            let mut command = Command::new("touch");
+            command.arg(dir.path().join(name));
+            command
+        };
+        let run = |build: &str| {
+            for name in ["test", "notify", "always"] {
+                let _ = std::fs::remove_file(dir.path().join(name));
+            }
+            let mut commands = CommandList::from(Vec::new())
+                .with_condition(Command::new(build), RunCondition::Always)
+                .with_condition(touch("test"), RunCondition::OnSuccess)
+                .with_condition(touch("notify"), RunCondition::OnFailure)
+                .with_condition(touch("always"), RunCondition::Always);
+            commands.spawn(|res| res.unwrap().wait().unwrap().success());
+            ["test", "notify", "always"].map(|name| dir.path().join(name).exists())
+        };
+
+        assert_eq!(run("true"), [true, false, true]);
+        assert_eq!(run("false"), [false, true, true]);
+
+        let mut commands = CommandList::from(Command::new("false"))
+            .with_condition(touch("status"), RunCondition::OnFailure);
+        assert!(!commands.status().unwrap().success());
+        assert!(dir.path().join("status").exists());
+    }
+
+    #[cfg(feature = "live-reload")]
+    #[test]
+    fn live_reload() {
+        use std::{
+            io::{BufRead, Read},
+            net::TcpStream,
+        };
+
+        assert_eq!(
+            live_reload::accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let server = live_reload::LiveReloadServer::bind(0, stopped.clone()).unwrap();
+        assert!(live_reload::client_script(server.addr()).contains(&format!(
+            "new WebSocket(\"ws://127.0.0.1:{}/\")",
+            server.addr().port()
+        )));
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .unwrap();
+        let mut reader = io::BufReader::new(stream);
+        let mut response = String::new();
+        while !response.ends_with("\r\n\r\n") {
+            assert_ne!(reader.read_line(&mut response).unwrap(), 0);
+        }
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let (tx, rx) = mpsc::channel();
+        let server_addr = server.addr();
+        let watch = Watch {
+            live_reload: Some(server),
+            ..Watch::default()
+        };
+        let handle = thread::spawn(move || watch.watch_loop(Command::new("true").into(), rx));
+        let mut frame = [0; 8];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame, b"\x81\x06reload");
+
+        drop(tx);
+        handle.join().unwrap();
+
+        // the server stops listening with the watch
+        stopped.store(true, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(300));
+        assert!(TcpStream::connect(server_addr).is_err());
+    }
+
+    #[test]
+    fn preview_watched_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        for path in ["src/generated", ".git", "assets"] {
+            std::fs::create_dir_all(root.join(path)).unwrap();
+        }
+        for file in [
+            "Cargo.toml",
+            "src/lib.rs",
+            "src/lib.rs~",
+            ".env",
+            "src/generated/bindings.rs",
+            ".git/HEAD",
+            "assets/logo.svg",
+        ] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+
+        let watch = Watch::default()
+            .watch_path(&root)
+            .exclude_path(root.join("src/generated"));
+        assert_eq!(
+            watch.preview_watched_files(),
+            [
+                root.join("Cargo.toml"),
+                root.join("assets/logo.svg"),
+                root.join("src/lib.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn exclude_index() {
+        let excluded = |count: usize| -> Vec<PathBuf> {
+            (0..count)
+                .map(|i| PathBuf::from(format!("/project/generated/{i}")))
+                .chain(["node_modules".into(), "/project/dist".into()])
+                .collect()
+        };
+        let set = PathSet::new(&excluded(5000), true);
+        assert!(set.contains_ancestor(Path::new("/project/generated/4999/mod.rs")));
+        assert!(set.contains_ancestor(Path::new("/project/generated/0")));
+        assert!(set.contains_ancestor(Path::new("/project/dist/index.html")));
+        assert!(set.contains_ancestor(Path::new("/project/web/node_modules/x.js")));
+        assert!(!set.contains_ancestor(Path::new("/project/generated/5000/mod.rs")));
+        assert!(!set.contains_ancestor(Path::new("/project/generated")));
+        assert!(!set.contains_ancestor(Path::new("/project/distant")));
+
+        let relative = PathSet::new(&[PathBuf::from("docs")], false);
+        assert!(relative.contains_ancestor(Path::new("docs/book.md")));
+        assert!(!relative.contains_ancestor(Path::new("src/docs/mod.rs")));
+
+        // the paths share their prefix in the tree, so a lookup walks one
+        // node per component whatever the number of excluded paths
+        fn node<'a>(set: &'a PathSet, path: &str) -> &'a PathNode {
+            Path::new(path)
+                .components()
+                .fold(&set.root, |node, x| &node.children[x.as_os_str()])
+        }
+        assert_eq!(set.root.children.len(), 1);
+        assert_eq!(node(&set, "/").children.len(), 1);
+        assert_eq!(node(&set, "/project").children.len(), 2);
+        assert_eq!(node(&set, "/project/generated").children.len(), 5000);
+        assert!(!node(&set, "/project/generated").end);
+        assert!(node(&set, "/project/generated/4999").children.is_empty());
+        assert_eq!(
+            set.names.iter().collect::<Vec<_>>(),
+            [OsStr::new("node_modules")]
+        );
+
+        let watch = Watch::default().exclude_paths(excluded(5000));
+        assert!(watch.is_excluded_path(Path::new("/project/generated/42/lib.rs")));
+        assert!(!watch.is_excluded_path(Path::new("/project/src/lib.rs")));
+    }
 }
@@ -0,0 +1,185 @@
+//! Minimal WebSocket server telling the browsers to reload the page after a
+//! successful build.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// GUID appended to the client key to compute the handshake's accept key.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Server accepting the WebSocket connections of the pages to reload.
+#[derive(Clone, Debug)]
+pub(crate) struct LiveReloadServer {
+    addr: SocketAddr,
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl LiveReloadServer {
+    /// Listen on `port` of the loopback interface, `0` picking any free port,
+    /// until `stopped` is set.
+    pub(crate) fn bind(port: u16, stopped: Arc<AtomicBool>) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        // polled to close the listener once the watch stops
+        listener.set_nonblocking(true)?;
+        let server = Self {
+            addr: listener.local_addr()?,
+            clients: Default::default(),
+        };
+
+        let clients = server.clients.clone();
+        let addr = server.addr;
+        thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        if err.kind() != io::ErrorKind::WouldBlock {
+                            log::debug!("live reload connection failed: {err}");
+                        }
+                        thread::sleep(Duration::from_millis(100));
+                        continue;
+                    }
+                };
+                let clients = clients.clone();
+                thread::spawn(move || {
+                    if let Err(err) = stream
+                        .set_nonblocking(false)
+                        .and_then(|_| accept(stream, addr, &clients))
+                    {
+                        log::debug!("live reload connection failed: {err}");
+                    }
+                });
+            }
+        });
+
+        Ok(server)
+    }
+
+    /// Address the server is listening on.
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Send a reload message to every connected page, forgetting the pages
+    /// that went away.
+    pub(crate) fn reload(&self) {
+        let mut clients = self.clients.lock().expect("not poisoned");
+        clients.retain_mut(|stream| {
+            // unmasked text frame of 6 bytes
+            stream
+                .write_all(&[0x81, 6])
+                .and_then(|_| stream.write_all(b"reload"))
+                .is_ok()
+        });
+        log::trace!("reload sent to {} page(s)", clients.len());
+    }
+}
+
+/// Answer a new connection: upgrade the WebSocket requests and serve the
+/// script connecting to the server otherwise.
+fn accept(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    clients: &Mutex<Vec<TcpStream>>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+    let mut key = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    match key {
+        Some(key) => {
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(&key),
+            )?;
+            clients.lock().expect("not poisoned").push(stream);
+        }
+        None if path == "/live-reload.js" => {
+            let script = client_script(addr);
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/javascript\r\n\
+                 Content-Length: {}\r\n\
+                 Access-Control-Allow-Origin: *\r\n\
+                 Connection: close\r\n\r\n{script}",
+                script.len(),
+            )?;
+        }
+        None => {
+            stream.write_all(
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Script reloading the page when the server sends a message.
+///
+/// The page connects to the loopback address the server listens on, whatever
+/// the host serving the page.
+pub(crate) fn client_script(addr: SocketAddr) -> String {
+    format!(
+        "(function () {{\n  \
+         var socket = new WebSocket(\"ws://{addr}/\");\n  \
+         socket.onmessage = function () {{ location.reload(); }};\n\
+         }})();\n",
+    )
+}
+
+/// Compute the `Sec-WebSocket-Accept` header answering the client `key`.
+pub(crate) fn accept_key(key: &str) -> String {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    base64(&hasher.digest().bytes())
+}
+
+/// Encode `bytes` in padded base64.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}